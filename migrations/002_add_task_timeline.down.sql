@@ -0,0 +1 @@
+ALTER TABLE file_to_embedding_task DROP COLUMN IF EXISTS timeline;
\ No newline at end of file
@@ -0,0 +1,3 @@
+-- Per-stage processing timings recorded by file-processor
+ALTER TABLE file_to_embedding_task
+ADD COLUMN timeline JSONB NOT NULL DEFAULT '[]'::jsonb;
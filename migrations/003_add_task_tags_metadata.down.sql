@@ -0,0 +1,3 @@
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS metadata,
+DROP COLUMN IF EXISTS tags;
\ No newline at end of file
@@ -0,0 +1,4 @@
+-- User-editable annotations, mirrored into the Qdrant payload
+ALTER TABLE file_to_embedding_task
+ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}',
+ADD COLUMN metadata JSONB NOT NULL DEFAULT '{}'::jsonb;
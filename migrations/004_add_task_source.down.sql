@@ -0,0 +1,4 @@
+DROP INDEX IF EXISTS idx_file_to_embedding_task_source;
+
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS source;
@@ -0,0 +1,5 @@
+-- Ingestion source (upload, crawler, sync, ...), mirrored into the Qdrant payload
+ALTER TABLE file_to_embedding_task
+ADD COLUMN source VARCHAR(100);
+
+CREATE INDEX idx_file_to_embedding_task_source ON file_to_embedding_task(source);
@@ -0,0 +1,2 @@
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS error_details;
@@ -0,0 +1,3 @@
+-- Structured failure info (stage, provider error code, chunk index) alongside error_message
+ALTER TABLE file_to_embedding_task
+ADD COLUMN error_details JSONB;
@@ -0,0 +1,4 @@
+-- Base64 file content, so the file-processor can load it by task id when the Kafka
+-- message does not carry it inline
+ALTER TABLE file_to_embedding_task
+ADD COLUMN file_content TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS attachments,
+DROP COLUMN IF EXISTS content_type;
\ No newline at end of file
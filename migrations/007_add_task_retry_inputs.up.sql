@@ -0,0 +1,4 @@
+-- Task inputs kept so a failed task's task_created event can be published again on retry
+ALTER TABLE file_to_embedding_task
+ADD COLUMN content_type VARCHAR(255),
+ADD COLUMN attachments TEXT[] NOT NULL DEFAULT '{}';
\ No newline at end of file
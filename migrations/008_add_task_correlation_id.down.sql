@@ -0,0 +1,2 @@
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS correlation_id;
\ No newline at end of file
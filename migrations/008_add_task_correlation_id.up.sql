@@ -0,0 +1,3 @@
+-- Id shared by every log line and message about one task, generated when it is created
+ALTER TABLE file_to_embedding_task
+ADD COLUMN correlation_id UUID;
\ No newline at end of file
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS idx_file_to_embedding_task_full_content_tsv;
+
+ALTER TABLE file_to_embedding_task
+DROP COLUMN IF EXISTS full_content_tsv,
+DROP COLUMN IF EXISTS full_content;
\ No newline at end of file
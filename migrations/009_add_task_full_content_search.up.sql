@@ -0,0 +1,7 @@
+-- Extracted document text, written by the file-processor on completion, and its
+-- full-text index for keyword and hybrid search
+ALTER TABLE file_to_embedding_task
+ADD COLUMN full_content TEXT,
+ADD COLUMN full_content_tsv TSVECTOR GENERATED ALWAYS AS (to_tsvector('english', COALESCE(full_content, ''))) STORED;
+
+CREATE INDEX idx_file_to_embedding_task_full_content_tsv ON file_to_embedding_task USING GIN (full_content_tsv);
\ No newline at end of file
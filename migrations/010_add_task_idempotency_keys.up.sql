@@ -0,0 +1,10 @@
+-- Idempotency-Key headers of task creation requests, so a retried request returns the
+-- task it created instead of a duplicate. Rows older than IDEMPOTENCY_KEY_TTL_SECS are
+-- deleted by later creations.
+CREATE TABLE task_idempotency_key (
+    key VARCHAR(255) PRIMARY KEY,
+    task_id INTEGER NOT NULL REFERENCES file_to_embedding_task(id) ON DELETE CASCADE,
+    created_at TIMESTAMP NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_task_idempotency_key_created_at ON task_idempotency_key(created_at);
@@ -0,0 +1,213 @@
+use anyhow::Result;
+use std::{
+    collections::{HashSet, VecDeque},
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+use xlib::client::{OpenAIClient, QdrantStore};
+
+use crate::{chunk_document, embed_chunks, store_chunks};
+
+const DEFAULT_MAX_DEPTH: usize = 2;
+const DEFAULT_MAX_TOTAL_BYTES: u64 = 10 * 1024 * 1024;
+
+/// Bounds on how far and how much attachment ingestion may fetch per task
+pub struct AttachmentLimits {
+    pub documents_root: PathBuf,
+    pub max_depth: usize,
+    pub max_total_bytes: u64,
+}
+
+impl AttachmentLimits {
+    pub fn from_env() -> Self {
+        Self {
+            documents_root: PathBuf::from(
+                std::env::var("DOCUMENTS_PATH").unwrap_or_else(|_| "/documents".to_string()),
+            ),
+            max_depth: std::env::var("ATTACHMENT_MAX_DEPTH")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_DEPTH),
+            max_total_bytes: std::env::var("ATTACHMENT_MAX_TOTAL_BYTES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_TOTAL_BYTES),
+        }
+    }
+}
+
+struct PendingAttachment {
+    path: String,
+    parent_file: String,
+    depth: usize,
+}
+
+/// Embed attachments breadth-first, following references found in text attachments
+/// up to `max_depth`. Failures are logged and skipped so the parent task still completes.
+/// Returns the number of attachments stored and the number of chunks they were split into.
+pub async fn ingest_attachments(
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    task_id: u64,
+    parent_file: &str,
+    source: Option<&str>,
+    manifest: Vec<String>,
+    limits: &AttachmentLimits,
+) -> (usize, usize) {
+    let mut queue: VecDeque<PendingAttachment> = manifest
+        .into_iter()
+        .map(|path| PendingAttachment {
+            path,
+            parent_file: parent_file.to_string(),
+            depth: 1,
+        })
+        .collect();
+    let mut visited = HashSet::from([parent_file.to_string()]);
+    let mut total_bytes = 0;
+    let mut stored = 0;
+    let mut chunks = 0;
+
+    while let Some(attachment) = queue.pop_front() {
+        if attachment.depth > limits.max_depth || !visited.insert(attachment.path.clone()) {
+            continue;
+        }
+
+        let content = match read_attachment(limits, &attachment.path, &mut total_bytes) {
+            Ok(Some(content)) => content,
+            Ok(None) => break,
+            Err(e) => {
+                warn!("⚠️ Skipping attachment '{}': {}", attachment.path, e);
+                continue;
+            }
+        };
+
+        for path in document_references(&attachment.path, &content) {
+            queue.push_back(PendingAttachment {
+                path,
+                parent_file: attachment.path.clone(),
+                depth: attachment.depth + 1,
+            });
+        }
+
+        match embed_attachment(store, openai, task_id, &attachment, source, &content).await {
+            Ok(chunk_count) => {
+                stored += 1;
+                chunks += chunk_count;
+            }
+            Err(e) => warn!("⚠️ Failed to embed attachment '{}': {}", attachment.path, e),
+        }
+    }
+
+    info!(
+        "📎 Embedded {} attachments in {} chunks for '{}' ({} bytes fetched)",
+        stored, chunks, parent_file, total_bytes
+    );
+    (stored, chunks)
+}
+
+/// Read an attachment from the documents directory, returning `None` once the byte
+/// budget would be exceeded
+fn read_attachment(
+    limits: &AttachmentLimits,
+    relative_path: &str,
+    total_bytes: &mut u64,
+) -> Result<Option<String>> {
+    let root = limits.documents_root.canonicalize()?;
+    let path = root.join(relative_path).canonicalize()?;
+    if !path.starts_with(&root) {
+        return Err(anyhow::anyhow!("path escapes the documents directory"));
+    }
+
+    let size = std::fs::metadata(&path)?.len();
+    if *total_bytes + size > limits.max_total_bytes {
+        warn!(
+            "⚠️ Attachment budget of {} bytes reached, not fetching '{}'",
+            limits.max_total_bytes, relative_path
+        );
+        return Ok(None);
+    }
+    *total_bytes += size;
+
+    let bytes = std::fs::read(&path)?;
+    let content = String::from_utf8(bytes)
+        .map_err(|_| anyhow::anyhow!("not a UTF-8 text file, only text attachments are embedded"))?;
+
+    Ok(Some(content))
+}
+
+async fn embed_attachment(
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    task_id: u64,
+    attachment: &PendingAttachment,
+    source: Option<&str>,
+    content: &str,
+) -> Result<usize> {
+    let (chunks, _) = chunk_document(&attachment.path, content)?;
+
+    // The task's timeline only times attachments as a whole, not their chunks
+    let mut chunk_timings = Vec::new();
+    let embedded = embed_chunks(store, openai, &attachment.path, chunks, &mut chunk_timings)
+        .await
+        .map_err(|failure| failure.error)?;
+    store_chunks(
+        store,
+        task_id,
+        &attachment.path,
+        Some(&attachment.parent_file),
+        source,
+        embedded,
+        &mut chunk_timings,
+    )
+    .await
+    .map_err(|failure| failure.error)
+}
+
+/// Local files linked from `text`, resolved against the directory of `file_name`
+pub fn document_references(file_name: &str, text: &str) -> Vec<String> {
+    find_references(text)
+        .into_iter()
+        .map(|reference| resolve_reference(file_name, &reference))
+        .collect()
+}
+
+/// Markdown-style link targets (`[label](target)`) that point at local files
+fn find_references(text: &str) -> Vec<String> {
+    text.match_indices("](")
+        .filter_map(|(index, _)| {
+            let rest = &text[index + 2..];
+            let target = rest[..rest.find(')')?].trim();
+            let is_local = !target.is_empty() && !target.contains("://") && !target.starts_with('#');
+            is_local.then(|| target.to_string())
+        })
+        .collect()
+}
+
+/// Resolve a reference relative to the directory of the file that contains it
+fn resolve_reference(referencing_file: &str, reference: &str) -> String {
+    Path::new(referencing_file)
+        .parent()
+        .map_or_else(|| PathBuf::from(reference), |dir| dir.join(reference))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_references_resolve_local_links_against_the_document() {
+        let text = "See [setup](setup.md), [the API](../api/index.md) and \
+                    [docs](https://example.com/docs) or [top](#top).";
+        assert_eq!(
+            document_references("guides/intro.md", text),
+            ["guides/setup.md", "guides/../api/index.md"]
+        );
+    }
+
+    #[test]
+    fn document_references_of_a_top_level_file() {
+        assert_eq!(document_references("intro.md", "[notes]( notes.txt )"), ["notes.txt"]);
+    }
+}
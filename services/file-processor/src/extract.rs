@@ -0,0 +1,95 @@
+use anyhow::Result;
+use tracing::info;
+
+/// Used when a task names no content type and its file extension is not recognized
+const DEFAULT_CONTENT_TYPE: &str = "text/plain";
+
+/// Turns the raw bytes of one kind of document into the text that gets embedded
+trait TextExtractor: Sync {
+    fn name(&self) -> &'static str;
+    fn handles(&self, content_type: &str) -> bool;
+    fn extract(&self, bytes: Vec<u8>) -> Result<String>;
+}
+
+struct PlainText;
+
+impl TextExtractor for PlainText {
+    fn name(&self) -> &'static str {
+        "text"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        content_type.starts_with("text/")
+            || matches!(content_type, "application/json" | "application/xml")
+    }
+
+    fn extract(&self, bytes: Vec<u8>) -> Result<String> {
+        String::from_utf8(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to convert decoded bytes to UTF-8: {}", e))
+    }
+}
+
+struct Pdf;
+
+impl TextExtractor for Pdf {
+    fn name(&self) -> &'static str {
+        "pdf"
+    }
+
+    fn handles(&self, content_type: &str) -> bool {
+        content_type == "application/pdf"
+    }
+
+    fn extract(&self, bytes: Vec<u8>) -> Result<String> {
+        pdf_extract::extract_text_from_mem(&bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to extract text from PDF: {}", e))
+    }
+}
+
+/// Extractors tried in order; add new document types here
+const EXTRACTORS: &[&dyn TextExtractor] = &[&PlainText, &Pdf];
+
+/// The task's content type (without parameters such as `charset`), or one guessed from
+/// the file extension
+pub fn resolve_content_type(content_type: Option<&str>, file_name: &str) -> String {
+    if let Some(content_type) = content_type.filter(|value| !value.trim().is_empty()) {
+        return content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+    }
+
+    let extension = file_name
+        .rsplit_once('.')
+        .map(|(_, extension)| extension.to_lowercase());
+    match extension.as_deref() {
+        Some("pdf") => "application/pdf".to_string(),
+        Some("md" | "markdown") => "text/markdown".to_string(),
+        Some("csv") => "text/csv".to_string(),
+        Some("html" | "htm") => "text/html".to_string(),
+        Some("json") => "application/json".to_string(),
+        _ => DEFAULT_CONTENT_TYPE.to_string(),
+    }
+}
+
+/// Extract the text of a `content_type` document. Parsers run on the blocking pool, which
+/// also contains a parser panic on a malformed file as an error.
+pub async fn extract_text(content_type: &str, bytes: Vec<u8>) -> Result<String> {
+    let extractor = EXTRACTORS
+        .iter()
+        .copied()
+        .find(|extractor| extractor.handles(content_type))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Unsupported content type '{}': only text and PDF documents can be embedded",
+                content_type
+            )
+        })?;
+
+    info!("📑 Extracting '{}' content with the {} extractor", content_type, extractor.name());
+    tokio::task::spawn_blocking(move || extractor.extract(bytes))
+        .await
+        .map_err(|e| anyhow::anyhow!("The {} extractor crashed: {}", extractor.name(), e))?
+}
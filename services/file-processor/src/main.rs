@@ -1,61 +1,149 @@
 #![allow(clippy::redundant_pub_crate)]
 
-use anyhow::Result;
+mod attachments;
+mod extract;
+mod retry;
+mod tokens;
+
+use anyhow::{Context, Result};
 use axum::{http::StatusCode, response::Json, routing::get, Router};
 use base64::{engine::general_purpose, Engine as _};
+use chrono::{DateTime, Utc};
 use qdrant_client::qdrant::{
-    CreateCollectionBuilder, Distance, PointStruct, UpsertPointsBuilder, VectorParamsBuilder,
+    vector_output, vectors_config, Condition, CreateFieldIndexCollectionBuilder, Distance,
+    FieldType, Filter, PointStruct, VectorParams, Vectors, ScrollPointsBuilder,
 };
-use qdrant_client::Qdrant;
+use qdrant_client::Payload;
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::atomic::{AtomicBool, Ordering}, time::Duration};
-use tokio::time;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::{sync::watch, task::JoinHandle, time};
 use tower::ServiceBuilder;
-use tracing::{error, info, warn};
+use tracing::{error, info, info_span, warn, Instrument};
 use uuid::Uuid;
+use attachments::AttachmentLimits;
 use xlib::{
-    app::{graceful_shutdown::shutdown_signal, tracing::init_tracing},
-    client::{KafkaClient, KafkaClientConfig},
+    app::{
+        graceful_shutdown::shutdown_signal,
+        metrics::{init_metrics, PrometheusHandle},
+        tracing::{init_tracing, set_parent_from_headers},
+    },
+    client::{
+        openai::{check_configured_dimensions, effective_dimensions, DEFAULT_EMBEDDING_MODEL},
+        qdrant, EmbeddingFallbackConfig, KafkaClient, KafkaClientConfig, KafkaMessage,
+        OpenAIClient, OpenAIClientConfig, OpenAIError, QdrantClientConfig, QdrantStore,
+        VectorSpec, VectorStore,
+    },
+    collection::{
+        check_configured_vector_size, collection_name, fields, PRIMARY_VECTOR, RERANK_VECTOR,
+    },
+    vector::l2_normalize,
 };
 
 #[derive(Serialize)]
-struct EmbeddingRequest {
-    input: String,
-    model: String,
+struct UpdateTaskRequest {
+    status: Option<String>,
+    error_message: Option<String>,
+    embedding_count: Option<i32>,
+    timeline_events: Option<Vec<TimelineEvent>>,
+    error_details: Option<ErrorDetails>,
+    /// Extracted text, stored by rag-api for keyword search
+    full_content: Option<String>,
 }
 
-#[derive(Deserialize)]
-struct EmbeddingResponse {
-    data: Vec<EmbeddingData>,
-    model: String,
-    usage: Usage,
+/// Structured failure info stored on the task next to its `error_message`
+#[derive(Serialize)]
+struct ErrorDetails {
+    stage: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_index: Option<u32>,
+    message: String,
 }
 
-#[derive(Deserialize)]
-struct EmbeddingData {
-    embedding: Vec<f32>,
-    index: i32,
+/// A processing failure tagged with the pipeline stage it happened in
+struct StageError {
+    stage: &'static str,
+    error: anyhow::Error,
+    /// Chunk being processed when the failure happened
+    chunk_index: Option<u32>,
 }
 
-#[derive(Deserialize)]
-struct Usage {
-    prompt_tokens: i32,
-    total_tokens: i32,
+impl StageError {
+    fn new(stage: &'static str, error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            stage,
+            error: error.into(),
+            chunk_index: None,
+        }
+    }
+
+    fn at_chunk(mut self, chunk_index: usize) -> Self {
+        self.chunk_index = u32::try_from(chunk_index).ok();
+        self
+    }
+
+    fn details(&self) -> ErrorDetails {
+        ErrorDetails {
+            stage: self.stage.to_string(),
+            code: self
+                .error
+                .downcast_ref::<OpenAIError>()
+                .and_then(OpenAIError::code)
+                .map(ToString::to_string),
+            chunk_index: self.chunk_index,
+            message: format!("{:#}", self.error),
+        }
+    }
 }
 
+/// Timing of a single processing stage, or of one chunk within it, appended to the
+/// task's timeline
 #[derive(Serialize)]
-struct UpdateTaskRequest {
-    status: Option<String>,
-    error_message: Option<String>,
-    embedding_count: Option<i32>,
+struct TimelineEvent {
+    stage: String,
+    started_at: DateTime<Utc>,
+    duration_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_index: Option<u32>,
+    detail: Option<String>,
 }
 
-const COLLECTION_NAME: &str = "rag-collection";
-const VECTOR_SIZE: u64 = 1536; // OpenAI text-embedding-3-small dimensions
+impl TimelineEvent {
+    fn finished(stage: &str, started_at: DateTime<Utc>, detail: Option<String>) -> Self {
+        let duration_ms = u64::try_from((Utc::now() - started_at).num_milliseconds()).unwrap_or(0);
+        Self {
+            stage: stage.to_string(),
+            started_at,
+            duration_ms,
+            chunk_index: None,
+            detail,
+        }
+    }
+
+    fn at_chunk(mut self, chunk_index: usize) -> Self {
+        self.chunk_index = u32::try_from(chunk_index).ok();
+        self
+    }
+
+    fn ended_at(&self) -> DateTime<Utc> {
+        self.started_at
+            + chrono::Duration::milliseconds(i64::try_from(self.duration_ms).unwrap_or(0))
+    }
+}
 
 // Global flag to track if kafka consumer is ready
 static KAFKA_CONSUMER_READY: AtomicBool = AtomicBool::new(false);
 
+/// How long shutdown waits for the message being processed (default)
+const DEFAULT_SHUTDOWN_GRACE_SECS: u64 = 30;
+
 #[derive(Serialize)]
 struct HealthResponse {
     status: String,
@@ -76,10 +164,12 @@ async fn health_check() -> Result<Json<HealthResponse>, StatusCode> {
     }
 }
 
-async fn start_health_server() -> Result<()> {
-    let app = Router::new()
-        .route("/health", get(health_check))
-        .layer(ServiceBuilder::new());
+async fn start_health_server(metrics: Option<PrometheusHandle>) -> Result<()> {
+    let mut app = Router::new().route("/health", get(health_check));
+    if let Some(handle) = metrics {
+        app = app.route("/metrics", get(move || std::future::ready(handle.render())));
+    }
+    let app = app.layer(ServiceBuilder::new());
 
     let addr = SocketAddr::from(([0, 0, 0, 0], 8080));
     info!("🏥 Health check server starting on {}", addr);
@@ -95,6 +185,9 @@ async fn update_task_status(
     status: &str,
     error_message: Option<String>,
     embedding_count: Option<i32>,
+    timeline_events: Option<Vec<TimelineEvent>>,
+    error_details: Option<ErrorDetails>,
+    full_content: Option<String>,
 ) -> Result<()> {
     let rag_api_url = std::env::var("RAG_API_URL")
         .unwrap_or_else(|_| "http://localhost:3000".to_string());
@@ -104,6 +197,9 @@ async fn update_task_status(
         status: Some(status.to_string()),
         error_message,
         embedding_count,
+        timeline_events,
+        error_details,
+        full_content,
     };
     
     let url = format!("{}/api/v1/embedding-tasks/{}", rag_api_url, task_id);
@@ -134,74 +230,550 @@ async fn update_task_status(
     }
 }
 
-async fn ensure_collection_exists(qdrant_client: &Qdrant) -> Result<()> {
-    info!("🗄️ Checking if collection '{}' exists...", COLLECTION_NAME);
+#[derive(Deserialize)]
+struct TaskContentResponse {
+    file_content: String,
+}
+
+/// Load the task's stored file content from rag-api, for messages sent without it inline
+async fn fetch_task_content(task_id: u64) -> Result<String> {
+    let rag_api_url = std::env::var("RAG_API_URL")
+        .unwrap_or_else(|_| "http://localhost:3000".to_string());
+    let url = format!("{}/api/v1/embedding-tasks/{}/content", rag_api_url, task_id);
+    info!("📥 Fetching stored content for task {}", task_id);
+
+    let response = reqwest::Client::new()
+        .get(&url)
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Request for task {} content failed: {}", task_id, e))?;
+    if !response.status().is_success() {
+        let status_code = response.status();
+        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
+        return Err(anyhow::anyhow!(
+            "Failed to fetch task {} content: HTTP {} - {}",
+            task_id,
+            status_code,
+            error_text
+        ));
+    }
+
+    Ok(response.json::<TaskContentResponse>().await?.file_content)
+}
+
+/// Embedding model from `EMBEDDING_MODEL`
+fn embedding_model() -> String {
+    std::env::var("EMBEDDING_MODEL").unwrap_or_else(|_| DEFAULT_EMBEDDING_MODEL.to_string())
+}
+
+/// Second embedding model from `RERANK_EMBEDDING_MODEL`, stored as a named vector next to
+/// the primary one so rag-api can rerank with it
+fn rerank_embedding_model() -> Option<String> {
+    std::env::var("RERANK_EMBEDDING_MODEL")
+        .ok()
+        .filter(|model| !model.is_empty())
+}
+
+/// Vector length configured for `model`: `EMBEDDING_DIMENSIONS` for the embedding model,
+/// `RERANK_EMBEDDING_DIMENSIONS` for the rerank model
+fn configured_dimensions(model: &str) -> Option<u64> {
+    let dimensions_var = if model == embedding_model() {
+        "EMBEDDING_DIMENSIONS"
+    } else if rerank_embedding_model().as_deref() == Some(model) {
+        "RERANK_EMBEDDING_DIMENSIONS"
+    } else {
+        return None;
+    };
+    std::env::var(dimensions_var)
+        .ok()
+        .and_then(|v| v.parse().ok())
+}
+
+/// Client for the embedding and rerank models, falling over to `EMBEDDING_FALLBACK_URL`.
+/// `RetryPolicy` retries transient failures of a whole chunk, so the client doesn't.
+fn openai_client() -> Result<OpenAIClient> {
+    let api_key = std::env::var("OPENAI_API_KEY")
+        .map_err(|_| anyhow::anyhow!("OPENAI_API_KEY environment variable not set"))?;
+    let model = embedding_model();
+    let embedding_dimensions = std::iter::once(model.clone())
+        .chain(rerank_embedding_model())
+        .filter_map(|model| {
+            let dimensions = configured_dimensions(&model)?;
+            Some((model, dimensions))
+        })
+        .collect();
+
+    OpenAIClient::new(OpenAIClientConfig {
+        api_key,
+        embedding_model: Some(model),
+        embedding_dimensions,
+        max_retries: Some(0),
+        embedding_fallback: EmbeddingFallbackConfig::from_env(),
+        ..Default::default()
+    })
+}
+
+/// Name of the primary model's vector; `None` for a collection with a single unnamed vector
+fn primary_vector_name() -> Option<&'static str> {
+    rerank_embedding_model().map(|_| PRIMARY_VECTOR)
+}
+
+/// Dimension of `model`: its configured dimension when the model accepts it, then the
+/// known table, then a probe embedding
+async fn resolve_vector_size(openai: &OpenAIClient, model: &str) -> Result<u64> {
+    let configured = configured_dimensions(model);
+    if let Some(configured) = configured {
+        check_configured_dimensions(model, configured)?;
+    }
+    if let Some(dimensions) = effective_dimensions(model, configured) {
+        return Ok(dimensions);
+    }
+
+    info!("📏 Probing the dimension of embedding model '{}'", model);
+    let probe = openai
+        .create_embedding_with_model("dimension check", model)
+        .await?;
+    Ok(u64::try_from(probe.len())?)
+}
+
+/// Parameters of the collection's vector named `vector_name`, or of its single unnamed
+/// vector when `None`
+async fn collection_vector_params(
+    store: &QdrantStore,
+    vector_name: Option<&str>,
+) -> Result<Option<VectorParams>> {
+    let collection_info = store
+        .client()
+        .collection_info(store.collection())
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read collection info: {}", e))?;
+
+    Ok(collection_info
+        .result
+        .and_then(|info| info.config)
+        .and_then(|config| config.params)
+        .and_then(|params| params.vectors_config)
+        .and_then(|vectors_config| vectors_config.config)
+        .and_then(|config| match (config, vector_name) {
+            (vectors_config::Config::Params(params), None) => Some(params),
+            (vectors_config::Config::ParamsMap(mut params_map), Some(name)) => {
+                params_map.map.remove(name)
+            }
+            _ => None,
+        }))
+}
+
+/// A vector the collection must hold for a configured embedding model
+struct ExpectedVector<'a> {
+    /// `None` for the single unnamed vector
+    name: Option<&'a str>,
+    model: &'a str,
+    size: u64,
+}
+
+/// Ways the existing collection differs from the configured models, one description each.
+/// Empty when `SKIP_DIMENSION_CHECK=true`.
+async fn collection_mismatches(
+    store: &QdrantStore,
+    expected: &[&ExpectedVector<'_>],
+) -> Result<Vec<String>> {
+    if std::env::var("SKIP_DIMENSION_CHECK").is_ok_and(|v| v == "true") {
+        warn!("⚠️ Skipping the embedding dimension check");
+        return Ok(Vec::new());
+    }
+
+    let mut mismatches = Vec::new();
+    for vector in expected {
+        let vector_label = vector.name.unwrap_or("unnamed");
+        let Some(params) = collection_vector_params(store, vector.name).await? else {
+            mismatches.push(format!(
+                "it has no {} vector; RERANK_EMBEDDING_MODEL must match how it was created",
+                vector_label
+            ));
+            continue;
+        };
+
+        if params.size != vector.size {
+            mismatches.push(format!(
+                "its {} vector has {} dimensions but embedding model '{}' produces {}",
+                vector_label, params.size, vector.model, vector.size
+            ));
+        }
+        // With NORMALIZE_EMBEDDINGS, validate_normalization decides which metrics are usable
+        if params.distance() != Distance::Cosine && !normalize_embeddings() {
+            mismatches.push(format!(
+                "its {} vector uses {} distance instead of Cosine",
+                vector_label,
+                params.distance().as_str_name()
+            ));
+        }
+    }
+    Ok(mismatches)
+}
+
+/// Whether a mismatched collection is deleted and created again
+/// (`RECREATE_COLLECTION_ON_MISMATCH`). This drops every stored point.
+fn recreate_collection_on_mismatch() -> bool {
+    std::env::var("RECREATE_COLLECTION_ON_MISMATCH").is_ok_and(|v| v == "true")
+}
+
+/// Create the collection when missing: a single unnamed vector, or named primary and
+/// rerank vectors when `rerank` is given. An existing collection must match the vectors'
+/// sizes and Cosine distance; otherwise startup fails, or the collection is re-created
+/// with `RECREATE_COLLECTION_ON_MISMATCH=true`.
+async fn ensure_collection_exists(
+    store: &QdrantStore,
+    primary: &ExpectedVector<'_>,
+    rerank: Option<&ExpectedVector<'_>>,
+) -> Result<()> {
+    let collection = store.collection();
+    info!("🗄️ Checking if collection '{}' exists...", collection);
 
     // Check if collection exists
-    match qdrant_client.collection_exists(COLLECTION_NAME).await {
-        Ok(exists) => {
-            if exists {
-                info!("✅ Collection '{}' already exists", COLLECTION_NAME);
+    match store.client().collection_exists(collection).await {
+        Ok(true) => {
+            let expected: Vec<&ExpectedVector<'_>> =
+                std::iter::once(primary).chain(rerank).collect();
+            let mismatches = collection_mismatches(store, &expected).await?;
+            if mismatches.is_empty() {
+                info!("✅ Collection '{}' already exists and matches the models", collection);
                 return Ok(());
             }
+            if !recreate_collection_on_mismatch() {
+                return Err(anyhow::anyhow!(
+                    "Collection '{}' does not match the configured embedding models: {}. Use \
+                     matching models, re-create the collection, or set \
+                     RECREATE_COLLECTION_ON_MISMATCH=true to have it re-created \
+                     (SKIP_DIMENSION_CHECK=true bypasses this check)",
+                    collection,
+                    mismatches.join("; ")
+                ));
+            }
+
+            warn!(
+                "⚠️ Re-creating collection '{}', dropping all its points: {}",
+                collection,
+                mismatches.join("; ")
+            );
+            store
+                .client()
+                .delete_collection(collection)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to delete collection: {}", e))?;
         }
+        Ok(false) => {}
         Err(e) => {
             warn!("Failed to check collection existence: {}", e);
         }
     }
 
-    // Create collection if it doesn't exist
-    info!(
-        "🏗️ Creating collection '{}' with {} dimensions...",
-        COLLECTION_NAME, VECTOR_SIZE
-    );
+    let vectors: Vec<VectorSpec<'_>> = std::iter::once(primary)
+        .chain(rerank)
+        .map(|vector| VectorSpec {
+            name: vector.name,
+            size: vector.size,
+        })
+        .collect();
+    if store.ensure_collection(&vectors).await? {
+        info!(
+            "✅ Created collection '{}' with {} dimensions",
+            collection, primary.size
+        );
+        if let Some(rerank) = rerank {
+            info!(
+                "🏗️ Added a {}-dimension '{}' vector for reranking",
+                rerank.size, RERANK_VECTOR
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Whether vectors are scaled to unit length before upsert (`NORMALIZE_EMBEDDINGS`)
+fn normalize_embeddings() -> bool {
+    std::env::var("NORMALIZE_EMBEDDINGS").is_ok_and(|v| v == "true")
+}
+
+/// Check `NORMALIZE_EMBEDDINGS` against the collection's distance metric. Unit vectors make
+/// Dot and Euclid rank like Cosine, and Cosine already ignores length, so only Manhattan is refused.
+async fn validate_normalization(store: &QdrantStore) -> Result<()> {
+    if !normalize_embeddings() {
+        return Ok(());
+    }
+
+    let distance = collection_vector_params(store, primary_vector_name())
+        .await?
+        .map(|params| params.distance());
+
+    match distance {
+        Some(Distance::Cosine) => {
+            info!("📐 L2 normalization enabled; Cosine rankings are unaffected");
+        }
+        Some(Distance::Dot | Distance::Euclid) => {
+            info!("📐 L2 normalization enabled; vectors will rank by cosine similarity");
+        }
+        Some(other) => {
+            return Err(anyhow::anyhow!(
+                "NORMALIZE_EMBEDDINGS is not supported with {} distance",
+                other.as_str_name()
+            ));
+        }
+        None => warn!("Could not determine the collection's distance metric to validate normalization"),
+    }
+
+    Ok(())
+}
+
+/// Index payload fields that searches filter on; creating an existing index is a no-op
+async fn ensure_payload_indexes(store: &QdrantStore) -> Result<()> {
+    let indexes = [
+        (fields::SOURCE, FieldType::Keyword),
+        (fields::FILE_NAME, FieldType::Keyword),
+        (fields::TASK_ID, FieldType::Integer),
+        (fields::CHUNK_INDEX, FieldType::Integer),
+        (fields::EMBEDDING_MODEL, FieldType::Keyword),
+    ];
+
+    for (field, field_type) in indexes {
+        store
+            .client()
+            .create_field_index(
+                CreateFieldIndexCollectionBuilder::new(store.collection(), field, field_type)
+                    .wait(true),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to create payload index on '{}': {}", field, e))?;
+
+        info!("✅ Payload index on '{}' is ready", field);
+    }
+    Ok(())
+}
+
+/// Vectors stored for one document
+struct DocumentVectors {
+    primary: Vec<f32>,
+    /// Vector from `RERANK_EMBEDDING_MODEL`, when set
+    rerank: Option<Vec<f32>>,
+}
+
+/// An embedding already stored in Qdrant for identical content
+struct ExistingEmbedding {
+    vectors: DocumentVectors,
+    file_name: String,
+    embedding_provider: Option<String>,
+}
+
+fn content_hash(content: &str) -> String {
+    format!("{:x}", Sha256::digest(content.as_bytes()))
+}
 
-    qdrant_client
-        .create_collection(
-            CreateCollectionBuilder::new(COLLECTION_NAME)
-                .vectors_config(VectorParamsBuilder::new(VECTOR_SIZE, Distance::Cosine)),
+/// A point with the same content embedded by the current `EMBEDDING_MODEL`; vectors of
+/// another model (or of an unrecorded one) are never reused
+async fn find_embedding_by_content_hash(
+    store: &QdrantStore,
+    content_hash: &str,
+) -> Result<Option<ExistingEmbedding>> {
+    let scroll_result = store
+        .client()
+        .scroll(
+            ScrollPointsBuilder::new(store.collection())
+                .filter(Filter::must([
+                    Condition::matches(fields::CONTENT_HASH, content_hash.to_string()),
+                    Condition::matches(fields::EMBEDDING_MODEL, embedding_model()),
+                ]))
+                .limit(1)
+                .with_payload(true)
+                .with_vectors(true),
         )
         .await
-        .map_err(|e| anyhow::anyhow!("Failed to create collection: {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Failed to look up content hash in Qdrant: {}", e))?;
 
-    info!("✅ Successfully created collection '{}'", COLLECTION_NAME);
-    Ok(())
+    let Some(point) = scroll_result.result.into_iter().next() else {
+        return Ok(None);
+    };
+
+    let Some(stored_vectors) = point.vectors else {
+        return Ok(None);
+    };
+    let dense_vector = |name: Option<&str>| {
+        match stored_vectors.get_vector_by_name(name.unwrap_or_default()) {
+            Some(vector_output::Vector::Dense(dense)) => Some(dense.data),
+            _ => None,
+        }
+    };
+    let Some(primary) = dense_vector(primary_vector_name()) else {
+        return Ok(None);
+    };
+    // Points stored before reranking was enabled lack the rerank vector; embed those again
+    let rerank = match rerank_embedding_model() {
+        Some(_) => match dense_vector(Some(RERANK_VECTOR)) {
+            Some(rerank) => Some(rerank),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+
+    // Point at the original file rather than at another duplicate
+    let file_name = point
+        .payload
+        .get(fields::DUPLICATE_OF)
+        .or_else(|| point.payload.get(fields::FILE_NAME))
+        .and_then(|v| v.as_str())
+        .map_or_else(|| "unknown".to_string(), Clone::clone);
+
+    let embedding_provider = point
+        .payload
+        .get(fields::EMBEDDING_PROVIDER)
+        .and_then(|v| v.as_str())
+        .cloned();
+
+    Ok(Some(ExistingEmbedding {
+        vectors: DocumentVectors { primary, rerank },
+        file_name,
+        embedding_provider,
+    }))
 }
 
-async fn store_embedding_in_qdrant(
-    qdrant_client: &Qdrant,
-    task_id: u64,
-    embedding: Vec<f32>,
+/// Characters kept in the `content_snippet` payload field when `SNIPPET_LENGTH` is unset
+const DEFAULT_SNIPPET_LENGTH: usize = 200;
+
+/// Characters kept in the `content_snippet` payload field (`SNIPPET_LENGTH`)
+fn snippet_length() -> Result<usize> {
+    match std::env::var("SNIPPET_LENGTH") {
+        Ok(value) => value
+            .parse::<usize>()
+            .ok()
+            .filter(|length| *length > 0)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Invalid SNIPPET_LENGTH '{}': expected a positive number of characters",
+                    value
+                )
+            }),
+        Err(_) => Ok(DEFAULT_SNIPPET_LENGTH),
+    }
+}
+
+/// Whether the whole document is stored in the `full_content` payload field
+/// (`STORE_FULL_CONTENT`, default true). Without it rag-api uses the task's extracted text.
+fn store_full_content() -> bool {
+    !std::env::var("STORE_FULL_CONTENT").is_ok_and(|v| v == "false")
+}
+
+/// Whether re-processing a file deletes its points beyond the new chunk count
+/// (`PRUNE_STALE_CHUNKS`, default false)
+fn prune_stale_chunks() -> bool {
+    std::env::var("PRUNE_STALE_CHUNKS").is_ok_and(|v| v == "true")
+}
+
+/// A chunk of a document and the payload metadata stored alongside its vector
+struct StoredDocument {
     file_name: String,
+    chunk_index: usize,
+    chunk_count: usize,
+    /// Text of this chunk
     content: String,
+    content_hash: String,
+    duplicate_of: Option<String>,
+    parent_file: Option<String>,
+    /// Name of the provider that produced the vector
+    embedding_provider: Option<String>,
+    /// System the document was ingested from
+    source: Option<String>,
+}
+
+async fn store_embedding_in_qdrant(
+    store: &dyn VectorStore,
+    task_id: u64,
+    vectors: DocumentVectors,
+    document: StoredDocument,
 ) -> Result<()> {
-    info!("💾 Storing embedding for task {} in Qdrant...", task_id);
+    let StoredDocument {
+        file_name,
+        chunk_index,
+        chunk_count,
+        content,
+        content_hash,
+        duplicate_of,
+        parent_file,
+        embedding_provider,
+        source,
+    } = document;
+
+    info!(
+        "💾 Storing embedding of chunk {}/{} for task {} in Qdrant...",
+        chunk_index + 1,
+        chunk_count,
+        task_id
+    );
+
+    let DocumentVectors {
+        primary: mut embedding,
+        mut rerank,
+    } = vectors;
+    if normalize_embeddings() {
+        l2_normalize(&mut embedding);
+        if let Some(rerank) = rerank.as_mut() {
+            l2_normalize(rerank);
+        }
+    }
 
     // Create a truncated content snippet for metadata
-    let content_snippet = if content.len() > 200 {
-        format!("{}...", &content[..200])
-    } else {
-        content.clone()
+    let content_snippet = match content.char_indices().nth(snippet_length()?) {
+        Some((end, _)) => format!("{}...", &content[..end]),
+        None => content.clone(),
     };
 
-    // Generate a deterministic UUID from file_name only - same file will update existing embedding
-    let point_id = Uuid::new_v5(&Uuid::NAMESPACE_OID, file_name.as_bytes());
+    let point_id = chunk_point_id(&file_name, parent_file.as_deref(), chunk_index);
+    let vector_dim = i64::try_from(embedding.len())?;
     
-    let point = PointStruct::new(
-        point_id.to_string(),
-        embedding,
-        [
-            ("file_name", file_name.into()),
-            ("task_id", (task_id as i64).into()),
-            ("content_snippet", content_snippet.into()),
-            ("full_content", content.into()),
-        ],
-    );
+    let task_id_value = i64::try_from(task_id)
+        .map_err(|e| anyhow::anyhow!("Task id {} out of range: {}", task_id, e))?;
 
-    qdrant_client
-        .upsert_points(UpsertPointsBuilder::new(COLLECTION_NAME, vec![point]))
-        .await
-        .map_err(|e| anyhow::anyhow!("Failed to store embedding in Qdrant: {}", e))?;
+    let mut payload = Payload::new();
+    payload.insert(fields::FILE_NAME, file_name);
+    payload.insert(fields::TASK_ID, task_id_value);
+    payload.insert(fields::CHUNK_INDEX, i64::try_from(chunk_index)?);
+    payload.insert(fields::CHUNK_COUNT, i64::try_from(chunk_count)?);
+    payload.insert(fields::CONTENT_SNIPPET, content_snippet);
+    if store_full_content() {
+        payload.insert(fields::FULL_CONTENT, content);
+    }
+    payload.insert(fields::CONTENT_HASH, content_hash);
+    if let Some(duplicate_of) = duplicate_of {
+        payload.insert(fields::DUPLICATE_OF, duplicate_of);
+    }
+    if let Some(parent_file) = parent_file {
+        payload.insert(fields::PARENT_FILE, parent_file);
+    }
+    if let Some(embedding_provider) = embedding_provider {
+        payload.insert(fields::EMBEDDING_PROVIDER, embedding_provider);
+    }
+    // The fallback provider serves the same model, and reused vectors are looked up by it
+    payload.insert(fields::EMBEDDING_MODEL, embedding_model());
+    payload.insert(fields::VECTOR_DIM, vector_dim);
+    if let Some(source) = source {
+        payload.insert(fields::SOURCE, source);
+    }
+
+    let vectors: Vectors = match rerank {
+        Some(rerank) => HashMap::from([
+            (PRIMARY_VECTOR.to_string(), embedding),
+            (RERANK_VECTOR.to_string(), rerank),
+        ])
+        .into(),
+        None => embedding.into(),
+    };
+    let point = PointStruct::new(point_id.to_string(), vectors, payload);
+
+    retry::RetryPolicy::from_env()
+        .run("Qdrant upsert", || async {
+            store
+                .upsert(vec![point.clone()])
+                .await
+                .context("Failed to store embedding in Qdrant")
+        })
+        .await?;
 
     info!(
         "✅ Successfully stored embedding for task {} in Qdrant",
@@ -210,22 +782,169 @@ async fn store_embedding_in_qdrant(
     Ok(())
 }
 
+/// Deterministic point id of a chunk, so re-processing a file updates its points. The
+/// first chunk of a document keeps the id derived from the file name alone, which rag-api
+/// uses to look documents up by name and which points stored before chunking already have.
+/// Attachment ids include the file they are attached to, so they never replace the points
+/// of a document with the same name or of the same attachment under another parent.
+fn chunk_point_id(file_name: &str, parent_file: Option<&str>, chunk_index: usize) -> Uuid {
+    let key = match (parent_file, chunk_index) {
+        (Some(parent_file), _) => {
+            format!("{}#attachment-{}#chunk-{}", parent_file, file_name, chunk_index)
+        }
+        (None, 0) => file_name.to_string(),
+        (None, _) => format!("{}#chunk-{}", file_name, chunk_index),
+    };
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, key.as_bytes())
+}
+
+/// A chunk of a document with the vectors to store for it
+struct EmbeddedChunk {
+    text: String,
+    content_hash: String,
+    vectors: DocumentVectors,
+    /// File whose identical chunk's vectors were reused
+    duplicate_of: Option<String>,
+    embedding_provider: Option<String>,
+}
+
+/// Embed each chunk of `file_name`, reusing the vectors of identical chunks already stored.
+/// Pushes an `embed` event per chunk to `timeline`.
+async fn embed_chunks(
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    file_name: &str,
+    chunks: Vec<String>,
+    timeline: &mut Vec<TimelineEvent>,
+) -> Result<Vec<EmbeddedChunk>, StageError> {
+    let mut embedded = Vec::with_capacity(chunks.len());
+
+    for (chunk_index, text) in chunks.into_iter().enumerate() {
+        let chunk_started_at = Utc::now();
+        let content_hash = content_hash(&text);
+        let existing = match find_embedding_by_content_hash(store, &content_hash).await {
+            Ok(existing) => existing,
+            Err(e) => {
+                warn!("Content dedup lookup failed, embedding anyway: {}", e);
+                None
+            }
+        };
+
+        let chunk = if let Some(existing) = existing {
+            info!(
+                "♻️ Chunk {} of '{}' already embedded as part of '{}', reusing its vector",
+                chunk_index, file_name, existing.file_name
+            );
+            EmbeddedChunk {
+                duplicate_of: (existing.file_name != file_name).then_some(existing.file_name),
+                embedding_provider: existing.embedding_provider,
+                vectors: existing.vectors,
+                content_hash,
+                text,
+            }
+        } else {
+            let (vectors, provider) = retry::RetryPolicy::from_env()
+                .run("Embedding", || embed_document(openai, &text))
+                .await
+                .map_err(|e| StageError::new("embed", e).at_chunk(chunk_index))?;
+            EmbeddedChunk {
+                duplicate_of: None,
+                embedding_provider: Some(provider),
+                vectors,
+                content_hash,
+                text,
+            }
+        };
+        let detail = chunk.duplicate_of.is_some().then(|| "reused".to_string());
+        timeline.push(
+            TimelineEvent::finished("embed", chunk_started_at, detail).at_chunk(chunk_index),
+        );
+        embedded.push(chunk);
+    }
+
+    Ok(embedded)
+}
+
+/// Upsert one point per chunk, pushing an `upsert` event per chunk to `timeline`, then,
+/// with `PRUNE_STALE_CHUNKS`, delete chunks left over from a longer earlier version of the
+/// file. Returns the number of points stored.
+async fn store_chunks(
+    store: &QdrantStore,
+    task_id: u64,
+    file_name: &str,
+    parent_file: Option<&str>,
+    source: Option<&str>,
+    chunks: Vec<EmbeddedChunk>,
+    timeline: &mut Vec<TimelineEvent>,
+) -> Result<usize, StageError> {
+    let chunk_count = chunks.len();
+
+    for (chunk_index, chunk) in chunks.into_iter().enumerate() {
+        let chunk_started_at = Utc::now();
+        store_embedding_in_qdrant(
+            store,
+            task_id,
+            chunk.vectors,
+            StoredDocument {
+                file_name: file_name.to_string(),
+                chunk_index,
+                chunk_count,
+                content: chunk.text,
+                content_hash: chunk.content_hash,
+                duplicate_of: chunk.duplicate_of,
+                parent_file: parent_file.map(ToString::to_string),
+                embedding_provider: chunk.embedding_provider,
+                source: source.map(ToString::to_string),
+            },
+        )
+        .await
+        .map_err(|e| StageError::new("upsert", e).at_chunk(chunk_index))?;
+        timeline.push(
+            TimelineEvent::finished("upsert", chunk_started_at, None).at_chunk(chunk_index),
+        );
+    }
+
+    remove_stale_chunks(store, file_name, parent_file, chunk_count, prune_stale_chunks())
+        .await
+        .map_err(|e| StageError::new("upsert", e))?;
+    Ok(chunk_count)
+}
+
+/// Delete points of `file_name` whose chunk index is `chunk_count` or higher, leaving
+/// alone the points of the same name stored under another parent. Does nothing unless
+/// `enabled`.
+async fn remove_stale_chunks(
+    store: &dyn VectorStore,
+    file_name: &str,
+    parent_file: Option<&str>,
+    chunk_count: usize,
+    enabled: bool,
+) -> Result<()> {
+    if !enabled {
+        return Ok(());
+    }
+    store
+        .delete_by_file(file_name, parent_file, Some(chunk_count))
+        .await
+        .with_context(|| format!("Failed to delete stale chunks of '{}'", file_name))
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
-    init_tracing();
+    let _tracing = init_tracing("file-processor");
 
     info!("Starting file processor worker...");
 
-    // Initialize Kafka client
-    let kafka_config = KafkaClientConfig {
-        bootstrap_servers: std::env::var("KAFKA_BOOTSTRAP_SERVERS")
-            .unwrap_or_else(|_| "localhost:9092".to_string()),
-        group_id: Some("file-processor-group".to_string()),
-    };
+    // Fail fast on a bad payload configuration rather than on the first task
+    snippet_length()?;
+    if !store_full_content() {
+        info!("📦 full_content will not be stored; rag-api uses the task's extracted text");
+    }
 
+    // Initialize Kafka client
     let kafka_client = KafkaClient::new_with_retry(
-        kafka_config,
+        kafka_config(),
         5,                                 // max retries
         std::time::Duration::from_secs(2), // retry delay
     )
@@ -233,18 +952,41 @@ async fn main() -> Result<()> {
     let kafka_client = std::sync::Arc::new(kafka_client);
 
     // Initialize Qdrant client
-    let qdrant_url =
-        std::env::var("QDRANT_URL").unwrap_or_else(|_| "http://localhost:6333".to_string());
-
-    info!("Connecting to Qdrant at: {}", qdrant_url);
-    let qdrant_client = std::sync::Arc::new(
-        Qdrant::from_url(&qdrant_url)
-            .build()
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Qdrant: {}", e))?
-    );
+    let qdrant_config = QdrantClientConfig::from_env();
+    info!("Connecting to Qdrant at: {}", qdrant_config.url);
+    let qdrant_client = std::sync::Arc::new(qdrant::connect_with_retry(&qdrant_config).await?);
+    let store = std::sync::Arc::new(QdrantStore::new(qdrant_client, collection_name()));
+
+    // Ensure collection exists and matches the embedding model
+    let openai = std::sync::Arc::new(openai_client()?);
+    let model = embedding_model();
+    let vector_size = resolve_vector_size(&openai, &model).await?;
+    check_configured_vector_size(&model, vector_size)?;
+    let rerank_model = rerank_embedding_model();
+    let rerank_vector_size = match &rerank_model {
+        Some(rerank_model) => {
+            Some(resolve_vector_size(&openai, rerank_model).await?)
+        }
+        None => None,
+    };
+    let primary = ExpectedVector {
+        name: primary_vector_name(),
+        model: &model,
+        size: vector_size,
+    };
+    let rerank = rerank_model.as_deref().zip(rerank_vector_size).map(|(model, size)| {
+        ExpectedVector {
+            name: Some(RERANK_VECTOR),
+            model,
+            size,
+        }
+    });
+    ensure_collection_exists(&store, &primary, rerank.as_ref()).await?;
+    ensure_payload_indexes(&store).await?;
+    validate_normalization(&store).await?;
 
-    // Ensure collection exists
-    ensure_collection_exists(&qdrant_client).await?;
+    // A fallback embedding provider must match the collection's vector size
+    openai.verify_embedding_fallback().await?;
 
     // Subscribe to the topic
     kafka_client
@@ -254,15 +996,26 @@ async fn main() -> Result<()> {
     info!("File processor subscribed to Kafka topics and ready to process messages");
 
     // Start health server
-    let health_server_handle = tokio::spawn(start_health_server());
+    let health_server_handle = tokio::spawn(start_health_server(init_metrics()?));
 
     // Mark kafka consumer as ready
     KAFKA_CONSUMER_READY.store(true, Ordering::Relaxed);
     info!("🚀 Kafka consumer ready - health endpoint will now return healthy");
 
-    // Run indefinitely until shutdown signal
+    // Run until a shutdown signal, then let the message in progress finish
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut consumer_handle = tokio::spawn({
+        let kafka_client = kafka_client.clone();
+        let store = store.clone();
+        let openai = openai.clone();
+        async move { kafka_consumer_loop(&kafka_client, &store, &openai, shutdown_rx).await }
+    });
+
     tokio::select! {
-        () = kafka_consumer_loop(&kafka_client, &qdrant_client) => {
+        result = &mut consumer_handle => {
+            if let Err(e) = result {
+                error!("Kafka consumer task error: {}", e);
+            }
             info!("Kafka consumer loop completed");
         }
         result = health_server_handle => {
@@ -271,9 +1024,14 @@ async fn main() -> Result<()> {
                 Ok(Err(e)) => error!("Health server error: {}", e),
                 Err(e) => error!("Health server task error: {}", e),
             }
+            shutdown_tx.send_replace(true);
+            drain_consumer(&kafka_client, consumer_handle).await;
         }
         () = shutdown_signal() => {
             info!("Shutdown signal received");
+            KAFKA_CONSUMER_READY.store(false, Ordering::Relaxed);
+            shutdown_tx.send_replace(true);
+            drain_consumer(&kafka_client, consumer_handle).await;
         }
     }
 
@@ -281,110 +1039,297 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn generate_embedding(text: &str) -> Result<Vec<f32>> {
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|_| anyhow::anyhow!("OPENAI_API_KEY environment variable not set"))?;
+/// Consumer settings from the `KAFKA_*` environment variables
+fn kafka_config() -> KafkaClientConfig {
+    KafkaClientConfig {
+        bootstrap_servers: std::env::var("KAFKA_BOOTSTRAP_SERVERS")
+            .unwrap_or_else(|_| "localhost:9092".to_string()),
+        group_id: Some("file-processor-group".to_string()),
+        partition_assignment_strategy: std::env::var("KAFKA_PARTITION_ASSIGNMENT_STRATEGY").ok(),
+        group_instance_id: std::env::var("KAFKA_GROUP_INSTANCE_ID").ok(),
+        manual_commit: std::env::var("KAFKA_MANUAL_COMMIT").is_ok_and(|v| v == "true"),
+        commit_batch_size: std::env::var("KAFKA_COMMIT_BATCH_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        commit_interval: std::env::var("KAFKA_COMMIT_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis),
+        auto_offset_reset: std::env::var("KAFKA_AUTO_OFFSET_RESET").ok(),
+        session_timeout_ms: std::env::var("KAFKA_SESSION_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        heartbeat_interval_ms: std::env::var("KAFKA_HEARTBEAT_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        max_poll_interval_ms: std::env::var("KAFKA_MAX_POLL_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        ..Default::default()
+    }
+}
 
-    let client = reqwest::Client::new();
+/// Wait up to `SHUTDOWN_GRACE_SECS` for the consumer loop to finish the message it is
+/// processing, then deliver pending dead letters and commit what was processed
+async fn drain_consumer(kafka_client: &KafkaClient, consumer_handle: JoinHandle<()>) {
+    let grace = Duration::from_secs(
+        std::env::var("SHUTDOWN_GRACE_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_SHUTDOWN_GRACE_SECS),
+    );
 
-    let request_body = EmbeddingRequest {
-        input: text.to_string(),
-        model: "text-embedding-3-small".to_string(),
+    info!("⏳ Waiting up to {:?} for the in-flight message to finish", grace);
+    let drained = match time::timeout(grace, consumer_handle).await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error!("Kafka consumer task error: {}", e);
+            false
+        }
+        Err(_) => {
+            // Dropping the handle doesn't stop the task, but the runtime ends with main
+            warn!(
+                "⚠️ In-flight message did not finish within {:?}; only manual commit redelivers it",
+                grace
+            );
+            false
+        }
+    };
+
+    if let Err(e) = kafka_client.flush(grace).await {
+        warn!("Failed to flush Kafka producer: {}", e);
+    }
+    // Committing the position now would skip a message abandoned mid-processing, but the
+    // messages already processed can still be committed
+    let committed = if drained {
+        kafka_client.commit_position()
+    } else {
+        kafka_client.commit_pending()
     };
+    if let Err(e) = committed {
+        warn!("Failed to commit Kafka consumer position: {}", e);
+    }
+}
+
+/// Embed `text`, returning the vector and the name of the provider that produced it
+async fn generate_embedding(openai: &OpenAIClient, text: &str) -> Result<(Vec<f32>, String)> {
+    // Reject absurdly large inputs before paying for tokenization or a request
+    let model = embedding_model();
+    OpenAIError::check_input_size(text, tokens::max_embed_input_bytes(&model))?;
+
+    // Trim inputs near the model's token limit instead of letting the request fail
+    let input = tokens::trim_to_token_limit(text, tokens::max_embed_tokens(&model));
 
     info!("🤖 Generating embedding for text: '{}'", text);
 
-    let response = client
-        .post("https://api.openai.com/v1/embeddings")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
-        .await?;
+    let (embedding, provider) = openai.create_embedding_with_provider(&input).await?;
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(anyhow::anyhow!(
-            "OpenAI API request failed with status {}: {}",
-            status,
-            error_text
-        ));
-    }
+    info!("✅ Successfully generated embedding!");
+    info!("🔢 Embedding dimensions: {}", embedding.len());
 
-    let embedding_response: EmbeddingResponse = response.json().await?;
+    // Print embedding summary instead of full vector
+    let sample_values = if embedding.len() >= 3 {
+        format!("{:.4}, {:.4}, {:.4}...", embedding[0], embedding[1], embedding[2])
+    } else {
+        format!("{:?}", embedding)
+    };
+    info!("🎯 Embedding vector summary: [{}] (length: {})", sample_values, embedding.len());
 
-    if let Some(embedding_data) = embedding_response.data.first() {
-        let embedding = embedding_data.embedding.clone();
+    Ok((embedding, provider.to_string()))
+}
 
-        info!("✅ Successfully generated embedding!");
-        info!("📊 Model: {}", embedding_response.model);
-        info!("🔢 Embedding dimensions: {}", embedding.len());
-        info!(
-            "💰 Token usage: {} prompt tokens, {} total tokens",
-            embedding_response.usage.prompt_tokens, embedding_response.usage.total_tokens
-        );
+/// Embed `text` with `RERANK_EMBEDDING_MODEL`, when set. The fallback provider serves
+/// only the primary model, so this has none.
+async fn generate_rerank_embedding(
+    openai: &OpenAIClient,
+    text: &str,
+) -> Result<Option<Vec<f32>>> {
+    let Some(model) = rerank_embedding_model() else {
+        return Ok(None);
+    };
+    OpenAIError::check_input_size(text, tokens::max_embed_input_bytes(&model))?;
+    let input = tokens::trim_to_token_limit(text, tokens::max_embed_tokens(&model));
 
-        // Print embedding summary instead of full vector
-        let sample_values = if embedding.len() >= 3 {
-            format!("{:.4}, {:.4}, {:.4}...", embedding[0], embedding[1], embedding[2])
-        } else {
-            format!("{:?}", embedding)
-        };
-        info!("🎯 Embedding vector summary: [{}] (length: {})", sample_values, embedding.len());
+    let embedding = openai.create_embedding_with_model(&input, &model).await?;
+    info!("🔢 Rerank embedding dimensions: {}", embedding.len());
 
-        Ok(embedding)
-    } else {
-        Err(anyhow::anyhow!(
-            "No embedding data received from OpenAI API"
-        ))
-    }
+    Ok(Some(embedding))
+}
+
+/// Every vector stored for `text`, with the name of the provider of the primary one
+async fn embed_document(openai: &OpenAIClient, text: &str) -> Result<(DocumentVectors, String)> {
+    let (primary, provider) = generate_embedding(openai, text).await?;
+    let rerank = generate_rerank_embedding(openai, text).await?;
+
+    Ok((DocumentVectors { primary, rerank }, provider))
 }
 
+/// Decode base64 file content into raw bytes
+fn decode_content(file_content: &str) -> Result<Vec<u8>> {
+    general_purpose::STANDARD
+        .decode(file_content)
+        .map_err(|e| anyhow::anyhow!("Failed to decode base64 content: {}", e))
+}
+
+/// Split `text` into overlapping windows that each fit the embedding model. Only the first
+/// `MAX_CHUNKS_PER_DOCUMENT` are kept, so a pathologically large document can't run up
+/// embedding costs; the note returned alongside says when chunks were dropped.
+fn chunk_document(file_name: &str, text: &str) -> Result<(Vec<String>, Option<String>)> {
+    let (max_tokens, overlap) = tokens::chunk_settings(&embedding_model())?;
+    let mut chunks = tokens::chunk_text(text, max_tokens, overlap);
+
+    let max_chunks = tokens::max_chunks_per_document();
+    let truncation = tokens::cap_chunks(&mut chunks, max_chunks).map(|chunk_count| {
+        warn!(
+            "⚠️ '{}' split into {} chunks, embedding only the first {}",
+            file_name, chunk_count, max_chunks
+        );
+        format!(
+            "Truncated to the first {} of {} chunks (MAX_CHUNKS_PER_DOCUMENT)",
+            max_chunks, chunk_count
+        )
+    });
+    Ok((chunks, truncation))
+}
+
+/// The document a `task_created` message asks to embed, apart from its content
+struct TaskDocument {
+    file_name: String,
+    attachments: Vec<String>,
+    source: Option<String>,
+    content_type: Option<String>,
+}
+
+/// Run every processing stage, appending each finished one to `timeline` so the stages
+/// that completed before a failure are still recorded
 async fn process_file_content(
     file_content: &str,
     task_id: u64,
-    file_name: String,
-    qdrant_client: &Qdrant,
-) -> Result<()> {
-    // Update status to processing\n    if let Err(e) = update_task_status(task_id, \"processing\", None, None).await {\n        warn!(\"Failed to update task {} to processing status: {}\", task_id, e);\n        // Continue processing even if status update fails\n    }\n\n    // Decode base64 content
-    let decoded_bytes = general_purpose::STANDARD
-        .decode(file_content)
-        .map_err(|e| anyhow::anyhow!("Failed to decode base64 content: {}", e))?;
+    document: TaskDocument,
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    timeline: &mut Vec<TimelineEvent>,
+) -> Result<(), StageError> {
+    let TaskDocument {
+        file_name,
+        mut attachments,
+        source,
+        content_type,
+    } = document;
+
+    // Update status to processing
+    if let Err(e) = update_task_status(task_id, "processing", None, None, None, None, None).await {
+        warn!("Failed to update task {} to processing status: {}", task_id, e);
+        // Continue processing even if status update fails
+    }
+
+    let stage_started_at = Utc::now();
+    let decoded_bytes = decode_content(file_content).map_err(|e| StageError::new("decode", e))?;
+    timeline.push(TimelineEvent::finished("decode", stage_started_at, None));
 
-    // Convert to UTF-8 string
-    let decoded_text = String::from_utf8(decoded_bytes)
-        .map_err(|e| anyhow::anyhow!("Failed to convert decoded bytes to UTF-8: {}", e))?;
+    let stage_started_at = Utc::now();
+    let content_type = extract::resolve_content_type(content_type.as_deref(), &file_name);
+    let decoded_text = extract::extract_text(&content_type, decoded_bytes)
+        .await
+        .map_err(|e| StageError::new("extract", e))?;
+    timeline.push(TimelineEvent::finished("extract", stage_started_at, Some(content_type)));
 
     info!("📄 Successfully decoded file content: '{}'", decoded_text);
     info!("📝 Content length: {} characters", decoded_text.len());
 
-    // Generate embedding
-    let embedding = generate_embedding(&decoded_text).await?;
-    info!("🎉 Embedding generation completed successfully!");
-    info!("📊 Generated {} dimensional embedding", embedding.len());
+    let stage_started_at = Utc::now();
+    let (chunks, truncation) =
+        chunk_document(&file_name, &decoded_text).map_err(|e| StageError::new("chunk", e))?;
+    timeline.push(TimelineEvent::finished(
+        "chunk",
+        stage_started_at,
+        Some(truncation.clone().unwrap_or_else(|| format!("{} chunks", chunks.len()))),
+    ));
+
+    // Reuses the vectors of identical chunks stored under other file names
+    let stage_started_at = Utc::now();
+    let embedded = embed_chunks(store, openai, &file_name, chunks, timeline).await?;
+    let reused = embedded.iter().filter(|chunk| chunk.duplicate_of.is_some()).count();
+    timeline.push(TimelineEvent::finished(
+        "embed",
+        stage_started_at,
+        Some(format!("{} chunks embedded, {} reused", embedded.len() - reused, reused)),
+    ));
+    info!("🎉 Embedded {} chunks of '{}'", embedded.len(), file_name);
 
     // Store in Qdrant
-    store_embedding_in_qdrant(qdrant_client, task_id, embedding, file_name, decoded_text).await?;
+    let stage_started_at = Utc::now();
+    let chunk_count = store_chunks(
+        store,
+        task_id,
+        &file_name,
+        None,
+        source.as_deref(),
+        embedded,
+        timeline,
+    )
+    .await?;
+    timeline.push(TimelineEvent::finished("upsert", stage_started_at, None));
     info!(
-        "🎯 Successfully stored embedding in Qdrant for task {}",
-        task_id
+        "🎯 Successfully stored {} chunks in Qdrant for task {}",
+        chunk_count, task_id
     );
 
+    // Embed attachments listed in the manifest or referenced by the document
+    let mut embedding_count = i32::try_from(chunk_count).unwrap_or(i32::MAX);
+    attachments.extend(attachments::document_references(&file_name, &decoded_text));
+    if !attachments.is_empty() {
+        let stage_started_at = Utc::now();
+        let (attachment_count, attachment_chunks) = attachments::ingest_attachments(
+            store,
+            openai,
+            task_id,
+            &file_name,
+            source.as_deref(),
+            attachments,
+            &AttachmentLimits::from_env(),
+        )
+        .await;
+        timeline.push(TimelineEvent::finished(
+            "attachments",
+            stage_started_at,
+            Some(format!("{} attachments embedded", attachment_count)),
+        ));
+        embedding_count = embedding_count
+            .saturating_add(i32::try_from(attachment_chunks).unwrap_or(i32::MAX));
+    }
+
     // Update task status to completed
-    if let Err(e) = update_task_status(task_id, "completed", None, Some(1)).await {
+    if let Err(e) =
+        update_task_status(
+            task_id,
+            "completed",
+            truncation,
+            Some(embedding_count),
+            Some(std::mem::take(timeline)),
+            None,
+            Some(decoded_text),
+        ).await
+    {
         warn!("Failed to update task {} to completed status: {}", task_id, e);
     }
-
     Ok(())
 }
 
+/// Count a finished task and how long it took, by outcome and failing stage
+fn record_task_metrics(elapsed: Duration, failure: Option<&StageError>) {
+    let outcome = if failure.is_some() { "failed" } else { "completed" };
+    metrics::counter!("tasks_processed_total", "outcome" => outcome).increment(1);
+    metrics::histogram!("task_processing_duration_seconds", "outcome" => outcome).record(elapsed);
+    if let Some(failure) = failure {
+        metrics::counter!("tasks_failed_total", "stage" => failure.stage).increment(1);
+    }
+}
+
 async fn process_task_created_message(
     payload: &serde_json::Map<String, serde_json::Value>,
-    qdrant_client: &Qdrant,
+    store: &QdrantStore,
+    openai: &OpenAIClient,
 ) -> Result<()> {
     let task_id = payload
         .get("task_id")
@@ -397,23 +1342,156 @@ async fn process_task_created_message(
         .unwrap_or("unknown_file")
         .to_string();
 
-    let file_content = payload
-        .get("file_content")
+    let attachments = payload
+        .get("attachments")
+        .and_then(|v| v.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|v| v.as_str().map(ToString::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let source = payload
+        .get("source")
+        .and_then(|v| v.as_str())
+        .map(ToString::to_string);
+
+    let content_type = payload
+        .get("content_type")
         .and_then(|v| v.as_str())
-        .ok_or_else(|| anyhow::anyhow!("No file_content found in message"))?;
+        .map(ToString::to_string);
 
     info!("🚀 Processing file embedding task {}", task_id);
 
-    process_file_content(file_content, task_id, file_name, qdrant_client).await
+    // Messages published with INLINE_TASK_CONTENT=false leave the content on the task row
+    let fetch_started_at = Utc::now();
+    let file_content = match payload.get("file_content").and_then(|v| v.as_str()) {
+        Some(file_content) => Ok(file_content.to_string()),
+        None => fetch_task_content(task_id).await.map_err(|e| StageError::new("fetch", e)),
+    };
+    let started = std::time::Instant::now();
+    let mut timeline = Vec::new();
+    let result = match file_content {
+        Ok(file_content) => {
+            let document = TaskDocument {
+                file_name,
+                attachments,
+                source,
+                content_type,
+            };
+            process_file_content(&file_content, task_id, document, store, openai, &mut timeline)
+                .await
+        }
+        Err(failure) => Err(failure),
+    };
+    record_task_metrics(started.elapsed(), result.as_ref().err());
+    let Err(failure) = result else {
+        return Ok(());
+    };
+
+    // Record where processing stopped so the task doesn't stay in `processing`, along
+    // with the stages that finished and the one that failed, which ran from the end of the
+    // last recorded event (the previous chunk's, when a chunk failed)
+    let failed_stage_started_at = timeline
+        .last()
+        .map_or(fetch_started_at, TimelineEvent::ended_at);
+    let mut failed_stage =
+        TimelineEvent::finished(failure.stage, failed_stage_started_at, Some("failed".to_string()));
+    failed_stage.chunk_index = failure.chunk_index;
+    timeline.push(failed_stage);
+    let details = failure.details();
+    if let Err(e) = update_task_status(
+        task_id,
+        "failed",
+        Some(format!("{} failed: {:#}", failure.stage, failure.error)),
+        None,
+        Some(timeline),
+        Some(details),
+        None,
+    )
+    .await
+    {
+        warn!("Failed to update task {} to failed status: {}", task_id, e);
+    }
+
+    Err(failure.error)
+}
+
+/// Process a `task_created` message, dead-lettering it when processing fails
+async fn handle_task_created(
+    kafka_client: &KafkaClient,
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    message: &KafkaMessage,
+) {
+    // Convert serde_json::Value to Map if it's an object
+    let failure = if let Some(payload_map) = message.payload.as_object() {
+        process_task_created_message(payload_map, store, openai)
+            .await
+            .err()
+            .map(|e| {
+                error!("Failed to process task_created message: {}", e);
+                e.to_string()
+            })
+    } else {
+        error!("Message payload is not a JSON object");
+        Some("Message payload is not a JSON object".to_string())
+    };
+
+    // Keep the failed payload around for reprocessing instead of dropping it
+    if let Some(failure) = failure {
+        if let Err(e) = kafka_client.produce_to_dlq(message, &failure).await {
+            error!("Failed to dead-letter message: {}", e);
+        }
+    }
 }
 
-async fn kafka_consumer_loop(kafka_client: &KafkaClient, qdrant_client: &Qdrant) {
-    loop {
-        match kafka_client.consume_message().await {
+/// Consume and process messages until `shutdown` flips to true. Shutdown is only checked
+/// between messages, so a message being processed always runs to completion.
+async fn kafka_consumer_loop(
+    kafka_client: &KafkaClient,
+    store: &QdrantStore,
+    openai: &OpenAIClient,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let manual_commit = std::env::var("KAFKA_MANUAL_COMMIT").is_ok_and(|v| v == "true");
+    // A partial batch of offsets is committed once the topic stays quiet this long
+    let idle_commit = kafka_client.commit_interval().filter(|_| manual_commit);
+
+    while !*shutdown.borrow() {
+        let consumed = tokio::select! {
+            // Waiting for the next message is safe to abandon; nothing was read yet
+            _ = shutdown.changed() => break,
+            consumed = kafka_client.consume_message() => consumed,
+            () = time::sleep(idle_commit.unwrap_or_default()), if idle_commit.is_some() => {
+                if let Err(e) = kafka_client.commit_pending() {
+                    warn!("Failed to commit Kafka offsets: {}", e);
+                }
+                continue;
+            }
+        };
+
+        match consumed {
             Ok(Some(message)) => {
+                // How long the message waited in the topic; a growing age means the
+                // consumer is falling behind
+                let age = (Utc::now() - message.timestamp).to_std().unwrap_or_default();
+                metrics::histogram!("kafka_message_age_seconds").record(age);
+
                 info!("📨 Received Kafka message:");
                 info!("  Event Type: {}", message.event_type);
                 info!("  Timestamp: {}", message.timestamp);
+                if let Some(position) = &message.position {
+                    info!(
+                        "  Key: {:?}, partition {} offset {}",
+                        message.key, position.partition, position.offset
+                    );
+                }
+                if !message.headers.is_empty() {
+                    info!("  Headers: {:?}", message.headers);
+                }
                 info!(
                     "  Payload: {}",
                     serde_json::to_string_pretty(&message.payload)
@@ -421,15 +1499,29 @@ async fn kafka_consumer_loop(kafka_client: &KafkaClient, qdrant_client: &Qdrant)
                 );
 
                 if message.event_type == "task_created" {
-                    // Convert serde_json::Value to Map if it's an object
-                    if let Some(payload_map) = message.payload.as_object() {
-                        if let Err(e) =
-                            process_task_created_message(payload_map, qdrant_client).await
-                        {
-                            error!("Failed to process task_created message: {}", e);
-                        }
-                    } else {
-                        error!("Message payload is not a JSON object");
+                    // Every log line for the task carries its ids; the trace continues the
+                    // API request that published it
+                    let span = info_span!(
+                        "process_task_created",
+                        otel.kind = "consumer",
+                        task_id = message.payload.get("task_id").and_then(|v| v.as_u64()),
+                        correlation_id = message
+                            .payload
+                            .get("correlation_id")
+                            .and_then(|v| v.as_str())
+                            .unwrap_or("none"),
+                    );
+                    set_parent_from_headers(&span, &message.headers);
+                    handle_task_created(kafka_client, store, openai, &message)
+                        .instrument(span)
+                        .await;
+                }
+
+                // Failures are recorded on the task, so only a crash before this point
+                // leads to redelivery
+                if manual_commit {
+                    if let Err(e) = kafka_client.commit_message(&message) {
+                        warn!("Failed to commit Kafka offset: {}", e);
                     }
                 }
             }
@@ -443,3 +1535,113 @@ async fn kafka_consumer_loop(kafka_client: &KafkaClient, qdrant_client: &Qdrant)
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qdrant_client::qdrant::{ScoredPoint, SearchPoints};
+
+    #[test]
+    fn first_chunk_of_a_document_keeps_the_file_name_id() {
+        let id = Uuid::new_v5(&Uuid::NAMESPACE_OID, b"guide.md");
+        assert_eq!(chunk_point_id("guide.md", None, 0), id);
+        assert_ne!(chunk_point_id("guide.md", None, 1), id);
+    }
+
+    #[test]
+    fn attachment_ids_include_the_parent_file() {
+        let attachment = chunk_point_id("images/diagram.md", Some("guide.md"), 0);
+        assert_ne!(attachment, chunk_point_id("images/diagram.md", None, 0));
+        assert_ne!(attachment, chunk_point_id("images/diagram.md", Some("faq.md"), 0));
+        assert_ne!(attachment, chunk_point_id("images/diagram.md", Some("guide.md"), 1));
+        assert_eq!(attachment, chunk_point_id("images/diagram.md", Some("guide.md"), 0));
+    }
+
+    #[test]
+    fn only_chunk_events_carry_a_chunk_index() {
+        let started_at = Utc::now();
+        let stage = serde_json::to_value(TimelineEvent::finished("embed", started_at, None));
+        let chunk = TimelineEvent::finished("embed", started_at, None).at_chunk(3);
+
+        assert!(stage.unwrap().get("chunk_index").is_none());
+        assert_eq!(serde_json::to_value(chunk).unwrap()["chunk_index"], 3);
+    }
+
+    /// Chunk indexes stored per (file name, parent file)
+    #[derive(Default)]
+    struct ChunkStore(std::sync::Mutex<Vec<(String, Option<String>, usize)>>);
+
+    impl ChunkStore {
+        fn holding(file_name: &str, parent_file: Option<&str>, chunk_count: usize) -> Self {
+            let store = Self::default();
+            store.0.lock().unwrap().extend(
+                (0..chunk_count)
+                    .map(|i| (file_name.to_string(), parent_file.map(ToString::to_string), i)),
+            );
+            store
+        }
+
+        fn chunks(&self, file_name: &str, parent_file: Option<&str>) -> Vec<usize> {
+            let points = self.0.lock().unwrap();
+            points
+                .iter()
+                .filter(|(name, parent, _)| name == file_name && parent.as_deref() == parent_file)
+                .map(|(_, _, index)| *index)
+                .collect()
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl VectorStore for ChunkStore {
+        fn collection(&self) -> &'static str {
+            "documents"
+        }
+
+        async fn ensure_collection(&self, _vectors: &[VectorSpec<'_>]) -> Result<bool> {
+            Ok(false)
+        }
+
+        async fn upsert(&self, _points: Vec<PointStruct>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn search(&self, _request: SearchPoints) -> Result<Vec<ScoredPoint>> {
+            Ok(Vec::new())
+        }
+
+        async fn delete_by_file(
+            &self,
+            file_name: &str,
+            parent_file: Option<&str>,
+            from_chunk: Option<usize>,
+        ) -> Result<()> {
+            self.0.lock().unwrap().retain(|(name, parent, index)| {
+                name != file_name
+                    || parent.as_deref() != parent_file
+                    || from_chunk.is_some_and(|from| *index < from)
+            });
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_beyond_the_new_count_are_removed_only_when_pruning() {
+        let store = ChunkStore::holding("guide.md", None, 5);
+        remove_stale_chunks(&store, "guide.md", None, 2, false).await.unwrap();
+        assert_eq!(store.chunks("guide.md", None), vec![0, 1, 2, 3, 4]);
+
+        remove_stale_chunks(&store, "guide.md", None, 2, true).await.unwrap();
+        assert_eq!(store.chunks("guide.md", None), vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn pruning_leaves_the_same_name_under_another_parent() {
+        let store = ChunkStore::holding("diagram.md", Some("guide.md"), 3);
+        store.0.lock().unwrap().push(("diagram.md".to_string(), None, 2));
+
+        remove_stale_chunks(&store, "diagram.md", Some("guide.md"), 1, true).await.unwrap();
+
+        assert_eq!(store.chunks("diagram.md", Some("guide.md")), vec![0]);
+        assert_eq!(store.chunks("diagram.md", None), vec![2]);
+    }
+}
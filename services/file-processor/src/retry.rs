@@ -0,0 +1,191 @@
+use anyhow::{Context, Result};
+use qdrant_client::QdrantError;
+use std::{future::Future, io::ErrorKind, time::Duration};
+use tracing::warn;
+use xlib::client::OpenAIError;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY_MS: u64 = 500;
+
+/// Upper bound on a single backoff
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Bounded exponential backoff for the embed and upsert steps
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn from_env() -> Self {
+        Self {
+            max_retries: std::env::var("PROCESSING_MAX_RETRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_MAX_RETRIES),
+            base_delay: Duration::from_millis(
+                std::env::var("PROCESSING_RETRY_BASE_DELAY_MS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(DEFAULT_BASE_DELAY_MS),
+            ),
+        }
+    }
+
+    /// Run `operation`, retrying transient failures with a delay that doubles each attempt.
+    /// Permanent failures are returned immediately.
+    pub async fn run<T, F, Fut>(&self, description: &str, mut operation: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            let error = match operation().await {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !is_transient(&error) {
+                return Err(error);
+            }
+            if attempt >= self.max_retries {
+                let attempts = attempt + 1;
+                return Err(error)
+                    .with_context(|| format!("{} failed after {} attempts", description, attempts));
+            }
+
+            let delay = self.delay(attempt);
+            attempt += 1;
+            warn!(
+                "⏳ {} failed with a transient error, retrying in {:?} ({}/{}): {:#}",
+                description, delay, attempt, self.max_retries, error
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Wait before retry number `attempt` (from 0), capped at `MAX_RETRY_DELAY`
+    fn delay(&self, attempt: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2_u32.saturating_pow(attempt))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Timeouts, refused connections, rate limits and 5xx responses anywhere in the error
+/// chain. Everything else (bad input, 4xx, invalid UTF-8) fails the task straight away.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<OpenAIError>() {
+            return e.status().is_some_and(|status| status == 429 || status >= 500);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout()
+                || e.is_connect()
+                || e.status().is_some_and(|status| status.is_server_error());
+        }
+        if let Some(e) = cause.downcast_ref::<QdrantError>() {
+            return match e {
+                QdrantError::ResponseError { status } => matches!(
+                    status.code(),
+                    tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::Aborted
+                ),
+                QdrantError::ResourceExhaustedError { .. } => true,
+                _ => false,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                ErrorKind::ConnectionRefused
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::TimedOut
+            );
+        }
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries,
+            base_delay: Duration::from_millis(1),
+        }
+    }
+
+    fn refused() -> anyhow::Error {
+        std::io::Error::from(ErrorKind::ConnectionRefused).into()
+    }
+
+    #[test]
+    fn delay_doubles_up_to_the_cap() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_millis(500),
+        };
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(10), MAX_RETRY_DELAY);
+        assert_eq!(policy.delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn rate_limits_server_errors_and_dropped_connections_are_transient() {
+        assert!(is_transient(&OpenAIError::from_response_text(429, "slow down")));
+        assert!(is_transient(&OpenAIError::from_response_text(503, "unavailable")));
+        assert!(is_transient(&refused().context("Failed to upsert point")));
+        assert!(!is_transient(&OpenAIError::from_response_text(400, "bad input")));
+        assert!(!is_transient(&anyhow::anyhow!("invalid UTF-8")));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried_until_success() {
+        let attempts = Cell::new(0);
+
+        let result = policy(3)
+            .run("Embedding", || {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move { if attempt < 3 { Err(refused()) } else { Ok(attempt) } }
+            })
+            .await;
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_are_not_retried() {
+        let attempts = Cell::new(0);
+
+        let result: Result<()> = policy(3)
+            .run("Embedding", || {
+                attempts.set(attempts.get() + 1);
+                async { Err(OpenAIError::from_response_text(400, "bad input")) }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_max_retries() {
+        let attempts = Cell::new(0);
+
+        let result: Result<()> = policy(2)
+            .run("Upsert", || {
+                attempts.set(attempts.get() + 1);
+                async { Err(refused()) }
+            })
+            .await;
+
+        assert_eq!(attempts.get(), 3);
+        assert_eq!(result.unwrap_err().to_string(), "Upsert failed after 3 attempts");
+    }
+}
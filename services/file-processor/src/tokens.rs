@@ -0,0 +1,85 @@
+use anyhow::Result;
+
+pub use xlib::tokens::{chunk_text, max_embed_input_bytes, max_embed_tokens, trim_to_token_limit};
+
+/// Tokens per chunk when `CHUNK_MAX_TOKENS` is unset
+const DEFAULT_CHUNK_MAX_TOKENS: usize = 512;
+
+/// Tokens shared by consecutive chunks when `CHUNK_OVERLAP_TOKENS` is unset
+const DEFAULT_CHUNK_OVERLAP_TOKENS: usize = 64;
+
+/// Chunk size and overlap from `CHUNK_MAX_TOKENS` and `CHUNK_OVERLAP_TOKENS`. Chunks are
+/// capped at `model`'s input limit, and the overlap must be smaller than the chunk size.
+pub fn chunk_settings(model: &str) -> Result<(usize, usize)> {
+    let max_tokens = std::env::var("CHUNK_MAX_TOKENS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CHUNK_MAX_TOKENS)
+        .min(max_embed_tokens(model));
+    let overlap = std::env::var("CHUNK_OVERLAP_TOKENS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_CHUNK_OVERLAP_TOKENS);
+
+    if max_tokens == 0 || overlap >= max_tokens {
+        return Err(anyhow::anyhow!(
+            "CHUNK_OVERLAP_TOKENS ({}) must be smaller than CHUNK_MAX_TOKENS ({})",
+            overlap,
+            max_tokens
+        ));
+    }
+    Ok((max_tokens, overlap))
+}
+
+/// Chunks embedded per document when `MAX_CHUNKS_PER_DOCUMENT` is unset
+const DEFAULT_MAX_CHUNKS_PER_DOCUMENT: usize = 1000;
+
+/// Most chunks embedded for one document or attachment, from `MAX_CHUNKS_PER_DOCUMENT`
+/// (0 disables the cap)
+pub fn max_chunks_per_document() -> usize {
+    std::env::var("MAX_CHUNKS_PER_DOCUMENT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_CHUNKS_PER_DOCUMENT)
+}
+
+/// Keep the first `max_chunks` of `chunks`, returning how many there were when any were
+/// dropped. A `max_chunks` of 0 keeps every chunk.
+pub fn cap_chunks(chunks: &mut Vec<String>, max_chunks: usize) -> Option<usize> {
+    let chunk_count = chunks.len();
+    if max_chunks == 0 || chunk_count <= max_chunks {
+        return None;
+    }
+    chunks.truncate(max_chunks);
+    Some(chunk_count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(count: usize) -> Vec<String> {
+        (0..count).map(|index| format!("chunk {}", index)).collect()
+    }
+
+    #[test]
+    fn cap_chunks_keeps_the_first_chunks() {
+        let mut capped = chunks(5);
+        assert_eq!(cap_chunks(&mut capped, 2), Some(5));
+        assert_eq!(capped, ["chunk 0", "chunk 1"]);
+    }
+
+    #[test]
+    fn cap_chunks_leaves_documents_within_the_cap() {
+        let mut capped = chunks(2);
+        assert_eq!(cap_chunks(&mut capped, 2), None);
+        assert_eq!(capped.len(), 2);
+    }
+
+    #[test]
+    fn cap_chunks_of_zero_disables_the_cap() {
+        let mut capped = chunks(5);
+        assert_eq!(cap_chunks(&mut capped, 0), None);
+        assert_eq!(capped.len(), 5);
+    }
+}
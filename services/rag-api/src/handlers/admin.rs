@@ -0,0 +1,455 @@
+use anyhow::Result;
+use axum::{
+    extract::State,
+    response::IntoResponse,
+    Json,
+};
+use qdrant_client::qdrant::{
+    DeletePointsBuilder, PayloadIncludeSelector, PointId, PointsIdsList, ScrollPointsBuilder,
+};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use tracing::{info, warn};
+use xlib::collection::fields;
+
+use super::{
+    file_embedding_task::{requeue_task, RequeueError},
+    search::point_id_to_string,
+};
+use crate::{
+    error::ApiError,
+    models::file_embedding_task::{
+        FileEmbeddingTask, TaskErrorDetails, TaskFilter, TaskStatus, UpdateTaskRequest,
+    },
+    AppState,
+};
+
+const SCROLL_PAGE_SIZE: u32 = 256;
+const TASK_PAGE_SIZE: i64 = 500;
+
+const MISSING_VECTORS_ERROR: &str =
+    "Embeddings missing from the vector store; retry the task to rebuild them";
+
+/// A task row as far as the check is concerned
+struct CheckedTask {
+    file_name: String,
+    status: TaskStatus,
+    embedding_count: Option<i32>,
+}
+
+#[derive(Serialize)]
+pub struct OrphanedPoint {
+    pub point_id: String,
+    pub task_id: Option<i64>,
+    pub file_name: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TaskMissingVectors {
+    pub task_id: i32,
+    pub file_name: String,
+    /// The task's `embedding_count`
+    pub expected_points: usize,
+    pub found_points: usize,
+}
+
+#[derive(Serialize)]
+pub struct ConsistencyReport {
+    pub tasks_checked: usize,
+    pub points_checked: usize,
+    pub orphaned_points: Vec<OrphanedPoint>,
+    pub tasks_missing_vectors: Vec<TaskMissingVectors>,
+    /// Tasks missing vectors that the repair queued for processing again
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub requeued_task_ids: Vec<i32>,
+    pub repaired: bool,
+}
+
+struct ScannedPoint {
+    id: PointId,
+    task_id: Option<i64>,
+    file_name: Option<String>,
+    /// Set on attachment points, whose `file_name` is the attachment's path
+    is_attachment: bool,
+}
+
+// Admin endpoint: cross-reference task rows against Qdrant points, changing nothing
+pub async fn check_consistency(
+    State(app_state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let report = reconcile(&app_state, false).await.map_err(|e| {
+        ApiError::internal("consistency_check_failed", "Failed to check consistency", &e)
+    })?;
+    Ok(Json(report))
+}
+
+// Admin endpoint: run the check, then delete orphaned points and re-queue tasks missing
+// vectors. POST only, so crawlers, prefetchers and retried GETs never modify data.
+pub async fn repair_consistency(
+    State(app_state): State<AppState>,
+) -> Result<impl IntoResponse, ApiError> {
+    let report = reconcile(&app_state, true).await.map_err(|e| {
+        ApiError::internal("consistency_repair_failed", "Failed to repair consistency", &e)
+    })?;
+    Ok(Json(report))
+}
+
+async fn reconcile(app_state: &AppState, repair: bool) -> Result<ConsistencyReport> {
+    // Tasks are loaded first so a task completing during the scan is not reported as
+    // missing vectors; tasks created during it are caught by the orphan re-check below
+    let tasks = load_tasks(app_state).await?;
+    let points = scan_points(app_state).await?;
+    info!(
+        "🔍 Checking consistency of {} tasks against {} points",
+        tasks.len(),
+        points.len()
+    );
+
+    let point_ids: HashSet<String> = points
+        .iter()
+        .map(|point| point_id_to_string(Some(point.id.clone())))
+        .collect();
+
+    // Points without a task_id or whose task row no longer exists
+    let candidates: Vec<&ScannedPoint> = points
+        .iter()
+        .filter(|point| point_task_id(point).is_none_or(|id| !tasks.contains_key(&id)))
+        .collect();
+    let orphans = recheck_orphans(app_state, candidates).await?;
+    let tasks_missing_vectors = tasks_missing_vectors(&tasks, &points);
+
+    let requeued_task_ids = if repair {
+        repair_inconsistencies(app_state, &orphans, &tasks_missing_vectors).await?
+    } else {
+        Vec::new()
+    };
+
+    Ok(ConsistencyReport {
+        tasks_checked: tasks.len(),
+        points_checked: point_ids.len(),
+        orphaned_points: orphans
+            .into_iter()
+            .map(|point| OrphanedPoint {
+                point_id: point_id_to_string(Some(point.id.clone())),
+                task_id: point.task_id,
+                file_name: point.file_name.clone(),
+            })
+            .collect(),
+        tasks_missing_vectors,
+        requeued_task_ids,
+        repaired: repair,
+    })
+}
+
+fn point_task_id(point: &ScannedPoint) -> Option<i32> {
+    point.task_id.and_then(|id| i32::try_from(id).ok())
+}
+
+/// Look the tasks of `candidates` up again and keep the points whose task still doesn't
+/// exist. A task created after the task snapshot was taken has points written during the
+/// scan, and they must not be deleted as orphans.
+async fn recheck_orphans<'a>(
+    app_state: &AppState,
+    candidates: Vec<&'a ScannedPoint>,
+) -> Result<Vec<&'a ScannedPoint>> {
+    let mut task_ids: Vec<i32> =
+        candidates.iter().filter_map(|point| point_task_id(point)).collect();
+    if task_ids.is_empty() {
+        return Ok(candidates);
+    }
+    task_ids.sort_unstable();
+    task_ids.dedup();
+
+    let existing = FileEmbeddingTask::existing_ids(&app_state.db_pool, &task_ids).await?;
+    Ok(confirmed_orphans(candidates, &existing))
+}
+
+/// The orphan candidates whose task is not in `existing`
+fn confirmed_orphans<'a>(
+    candidates: Vec<&'a ScannedPoint>,
+    existing: &HashSet<i32>,
+) -> Vec<&'a ScannedPoint> {
+    candidates
+        .into_iter()
+        .filter(|point| point_task_id(point).is_none_or(|id| !existing.contains(&id)))
+        .collect()
+}
+
+/// Completed tasks with fewer points than their `embedding_count`. A task's points are
+/// those carrying its `task_id`, plus the document chunks stored under its file name, which
+/// a task re-processing the file relabels. Only the latest completed task of a file name is
+/// checked; earlier ones had their points replaced.
+fn tasks_missing_vectors(
+    tasks: &HashMap<i32, CheckedTask>,
+    points: &[ScannedPoint],
+) -> Vec<TaskMissingVectors> {
+    let mut latest_by_file: HashMap<&str, i32> = HashMap::new();
+    for (task_id, task) in tasks {
+        if task.status == TaskStatus::Completed {
+            let latest = latest_by_file.entry(&task.file_name).or_insert(*task_id);
+            *latest = (*latest).max(*task_id);
+        }
+    }
+
+    let mut found: HashMap<i32, HashSet<String>> = HashMap::new();
+    for point in points {
+        let by_task = point.task_id.and_then(|id| i32::try_from(id).ok());
+        let by_file = point
+            .file_name
+            .as_deref()
+            .filter(|_| !point.is_attachment)
+            .and_then(|file_name| latest_by_file.get(file_name).copied());
+        for task_id in by_task.into_iter().chain(by_file) {
+            found
+                .entry(task_id)
+                .or_default()
+                .insert(point_id_to_string(Some(point.id.clone())));
+        }
+    }
+
+    let mut missing: Vec<TaskMissingVectors> = latest_by_file
+        .into_iter()
+        .filter_map(|(file_name, task_id)| {
+            // Tasks completed before counts were recorded expect at least one point
+            let expected = tasks[&task_id]
+                .embedding_count
+                .map_or(1, |count| usize::try_from(count).unwrap_or(0));
+            let found_points = found.get(&task_id).map_or(0, HashSet::len);
+            (found_points < expected).then(|| TaskMissingVectors {
+                task_id,
+                file_name: file_name.to_string(),
+                expected_points: expected,
+                found_points,
+            })
+        })
+        .collect();
+    missing.sort_by_key(|task| task.task_id);
+    missing
+}
+
+/// Every task keyed by id
+async fn load_tasks(app_state: &AppState) -> Result<HashMap<i32, CheckedTask>> {
+    let mut tasks = HashMap::new();
+    let mut after_id = 0;
+    let filter = TaskFilter::default();
+
+    loop {
+        let page =
+            FileEmbeddingTask::list_after(&app_state.db_pool, &filter, after_id, TASK_PAGE_SIZE)
+                .await?;
+        let Some(last) = page.last() else {
+            break;
+        };
+        after_id = last.id;
+        let is_last_page = page.len() < usize::try_from(TASK_PAGE_SIZE).unwrap_or(usize::MAX);
+
+        tasks.extend(page.into_iter().map(|task| {
+            let checked = CheckedTask {
+                file_name: task.file_name,
+                status: task.status,
+                embedding_count: task.embedding_count,
+            };
+            (task.id, checked)
+        }));
+        if is_last_page {
+            break;
+        }
+    }
+
+    Ok(tasks)
+}
+
+/// Every point in the collection with just the payload fields needed to match it to a task
+async fn scan_points(app_state: &AppState) -> Result<Vec<ScannedPoint>> {
+    let mut points = Vec::new();
+    let mut offset: Option<PointId> = None;
+
+    loop {
+        let mut scroll = ScrollPointsBuilder::new(&app_state.collection)
+            .limit(SCROLL_PAGE_SIZE)
+            .with_payload(PayloadIncludeSelector {
+                fields: vec![
+                    fields::TASK_ID.to_string(),
+                    fields::FILE_NAME.to_string(),
+                    fields::PARENT_FILE.to_string(),
+                ],
+            })
+            .with_vectors(false);
+        if let Some(offset) = offset.take() {
+            scroll = scroll.offset(offset);
+        }
+
+        let response = app_state
+            .qdrant_client
+            .scroll(scroll)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to scroll Qdrant points: {}", e))?;
+
+        points.extend(response.result.into_iter().filter_map(|point| {
+            Some(ScannedPoint {
+                task_id: point.payload.get(fields::TASK_ID).and_then(|v| v.as_integer()),
+                file_name: point
+                    .payload
+                    .get(fields::FILE_NAME)
+                    .and_then(|v| v.as_str())
+                    .cloned(),
+                is_attachment: point.payload.contains_key(fields::PARENT_FILE),
+                id: point.id?,
+            })
+        }));
+
+        match response.next_page_offset {
+            Some(next) => offset = Some(next),
+            None => break,
+        }
+    }
+
+    Ok(points)
+}
+
+/// Delete orphaned points, then mark tasks without vectors as failed and re-queue them
+/// through the retry path. Tasks created without stored content stay failed. Returns the
+/// ids of the re-queued tasks.
+async fn repair_inconsistencies(
+    app_state: &AppState,
+    orphans: &[&ScannedPoint],
+    tasks_missing_vectors: &[TaskMissingVectors],
+) -> Result<Vec<i32>> {
+    if !orphans.is_empty() {
+        warn!("🧹 Deleting {} orphaned points", orphans.len());
+        app_state
+            .qdrant_client
+            .delete_points(
+                DeletePointsBuilder::new(&app_state.collection)
+                    .points(PointsIdsList {
+                        ids: orphans.iter().map(|point| point.id.clone()).collect(),
+                    })
+                    .wait(true),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete orphaned points: {}", e))?;
+    }
+
+    let mut requeued = Vec::new();
+    for task in tasks_missing_vectors {
+        warn!(
+            "🧹 Marking task {} ('{}') failed: no vectors found",
+            task.task_id, task.file_name
+        );
+        FileEmbeddingTask::update(
+            &app_state.db_pool,
+            task.task_id,
+            UpdateTaskRequest {
+                status: Some(TaskStatus::Failed),
+                error_message: Some(MISSING_VECTORS_ERROR.to_string()),
+                embedding_count: None,
+                timeline_events: None,
+                error_details: Some(TaskErrorDetails {
+                    stage: "consistency_check".to_string(),
+                    code: None,
+                    chunk_index: None,
+                    message: MISSING_VECTORS_ERROR.to_string(),
+                }),
+                full_content: None,
+            },
+        )
+        .await?;
+
+        if requeue_missing_task(app_state, task.task_id).await? {
+            requeued.push(task.task_id);
+        }
+    }
+
+    Ok(requeued)
+}
+
+/// Queue failed task `task_id` again, returning whether it was. A publish failure leaves
+/// the task failed for a later retry rather than failing the whole repair.
+async fn requeue_missing_task(app_state: &AppState, task_id: i32) -> Result<bool> {
+    let Some(inputs) = FileEmbeddingTask::inputs(&app_state.db_pool, task_id).await? else {
+        return Ok(false);
+    };
+    if inputs.file_content.is_none() {
+        warn!("Task {} has no stored content to re-queue; it stays failed", task_id);
+        return Ok(false);
+    }
+
+    match requeue_task(app_state, task_id, inputs).await {
+        Ok(task) => Ok(task.is_some()),
+        Err(RequeueError::Reset(e)) => Err(e),
+        Err(RequeueError::Publish(e)) => {
+            warn!("Task {} stays failed, its event could not be published: {}", task_id, e);
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(file_name: &str, embedding_count: i32) -> CheckedTask {
+        CheckedTask {
+            file_name: file_name.to_string(),
+            status: TaskStatus::Completed,
+            embedding_count: Some(embedding_count),
+        }
+    }
+
+    fn point(id: u64, task_id: i64, file_name: &str) -> ScannedPoint {
+        ScannedPoint {
+            id: id.into(),
+            task_id: Some(task_id),
+            file_name: Some(file_name.to_string()),
+            is_attachment: false,
+        }
+    }
+
+    #[test]
+    fn counts_chunks_rather_than_looking_for_chunk_zero() {
+        let tasks = HashMap::from([(1, task("report.txt", 3)), (2, task("notes.txt", 3))]);
+        // report.txt lost chunk 0 only; notes.txt lost two of its three chunks
+        let points = [
+            point(11, 1, "report.txt"),
+            point(12, 1, "report.txt"),
+            point(13, 1, "report.txt"),
+            point(21, 2, "notes.txt"),
+        ];
+
+        let missing = tasks_missing_vectors(&tasks, &points);
+
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].task_id, 2);
+        assert_eq!((missing[0].expected_points, missing[0].found_points), (3, 1));
+    }
+
+    #[test]
+    fn points_of_a_task_created_during_the_scan_are_not_orphans() {
+        let points = [
+            point(11, 1, "deleted.txt"),
+            point(21, 2, "new.txt"),
+            ScannedPoint {
+                task_id: None,
+                ..point(31, 0, "unknown.txt")
+            },
+        ];
+        // Task 2 was missing from the snapshot but exists when re-checked
+        let candidates = points.iter().collect();
+
+        let orphans = confirmed_orphans(candidates, &HashSet::from([2]));
+
+        let ids: Vec<String> = orphans
+            .iter()
+            .map(|point| point_id_to_string(Some(point.id.clone())))
+            .collect();
+        assert_eq!(ids, ["11", "31"]);
+    }
+
+    #[test]
+    fn checks_only_the_latest_task_of_a_file() {
+        let tasks = HashMap::from([(1, task("report.txt", 5)), (2, task("report.txt", 2))]);
+        let points = [point(11, 2, "report.txt"), point(12, 2, "report.txt")];
+
+        assert!(tasks_missing_vectors(&tasks, &points).is_empty());
+    }
+}
@@ -0,0 +1,217 @@
+use axum::{extract::State, response::IntoResponse, Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{error, info};
+use xlib::collection::fields;
+
+use super::search::{retrieval_error, retrieve_points};
+use crate::{
+    error::{ApiError, ApiJson},
+    AppState,
+};
+
+/// Most labeled queries accepted in one evaluation request
+const MAX_EVAL_QUERIES: usize = 100;
+
+/// Largest cutoff accepted for `k`
+const MAX_EVAL_K: u64 = 100;
+
+#[derive(Deserialize)]
+pub struct LabeledQuery {
+    pub query: String,
+    /// File names that a good retrieval returns for `query`
+    pub relevant_files: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EvalRequest {
+    pub queries: Vec<LabeledQuery>,
+    /// Cutoff rank for the metrics (default 5)
+    pub k: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct QueryEvaluation {
+    pub query: String,
+    pub retrieved_files: Vec<String>,
+    pub recall: f64,
+    pub precision: f64,
+    /// 1 / rank of the first relevant file, 0 when none was retrieved
+    pub reciprocal_rank: f64,
+}
+
+#[derive(Serialize)]
+pub struct EvalResponse {
+    pub k: u64,
+    pub query_count: usize,
+    pub recall_at_k: f64,
+    pub precision_at_k: f64,
+    pub mrr: f64,
+    pub results: Vec<QueryEvaluation>,
+}
+
+// Evaluation endpoint: run retrieval for labeled queries and report recall@k, precision@k and MRR
+pub async fn evaluate_retrieval(
+    State(app_state): State<AppState>,
+    ApiJson(request): ApiJson<EvalRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let k = request.k.unwrap_or(5);
+    validate(&request, k)
+        .map_err(|message| ApiError::bad_request("invalid_eval_request", message))?;
+
+    info!(
+        "🧪 Evaluating retrieval for {} labeled queries at k={}",
+        request.queries.len(),
+        k
+    );
+
+    let mut results = Vec::with_capacity(request.queries.len());
+    for labeled in request.queries {
+        // Same retrieval path as /search and /query, so the metrics reflect production
+        let queries = [labeled.query.clone()];
+        let ranked_points = retrieve_points(&app_state, &queries, None, 0, k, false)
+            .await
+            .map_err(|e| {
+                error!("Retrieval failed during evaluation of '{}'", labeled.query);
+                retrieval_error(&e)
+            })?;
+
+        let retrieved_files = ranked_points
+            .into_iter()
+            .map(|ranked| {
+                ranked
+                    .point
+                    .payload
+                    .get(fields::FILE_NAME)
+                    .and_then(|v| v.as_str())
+                    .map_or_else(|| "unknown".to_string(), Clone::clone)
+            })
+            .collect();
+        results.push(evaluate_query(labeled, retrieved_files, k));
+    }
+
+    Ok(Json(summarize(k, results)))
+}
+
+fn validate(request: &EvalRequest, k: u64) -> Result<(), String> {
+    if request.queries.is_empty() {
+        return Err("At least one labeled query is required".to_string());
+    }
+    if request.queries.len() > MAX_EVAL_QUERIES {
+        return Err(format!(
+            "At most {} labeled queries are accepted per request",
+            MAX_EVAL_QUERIES
+        ));
+    }
+    if !(1..=MAX_EVAL_K).contains(&k) {
+        return Err(format!("k must be between 1 and {}", MAX_EVAL_K));
+    }
+    if let Some(labeled) = request
+        .queries
+        .iter()
+        .find(|labeled| labeled.relevant_files.is_empty())
+    {
+        return Err(format!("Query '{}' has no relevant_files", labeled.query));
+    }
+    Ok(())
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn evaluate_query(labeled: LabeledQuery, retrieved_files: Vec<String>, k: u64) -> QueryEvaluation {
+    let relevant: HashSet<&String> = labeled.relevant_files.iter().collect();
+    let found: HashSet<&String> = retrieved_files
+        .iter()
+        .filter(|file| relevant.contains(file))
+        .collect();
+    let first_hit = retrieved_files.iter().position(|file| relevant.contains(file));
+
+    QueryEvaluation {
+        recall: found.len() as f64 / relevant.len() as f64,
+        precision: found.len() as f64 / k as f64,
+        reciprocal_rank: first_hit.map_or(0.0, |rank| 1.0 / (rank + 1) as f64),
+        query: labeled.query,
+        retrieved_files,
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn summarize(k: u64, results: Vec<QueryEvaluation>) -> EvalResponse {
+    let count = results.len() as f64;
+    let mean =
+        |metric: fn(&QueryEvaluation) -> f64| results.iter().map(metric).sum::<f64>() / count;
+
+    EvalResponse {
+        k,
+        query_count: results.len(),
+        recall_at_k: mean(|result| result.recall),
+        precision_at_k: mean(|result| result.precision),
+        mrr: mean(|result| result.reciprocal_rank),
+        results,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "expected {expected}, got {actual}");
+    }
+
+    fn labeled(query: &str, relevant_files: &[&str]) -> LabeledQuery {
+        LabeledQuery {
+            query: query.to_string(),
+            relevant_files: relevant_files.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    fn files(names: &[&str]) -> Vec<String> {
+        names.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn metrics_count_each_relevant_file_once() {
+        let retrieved = files(&["intro.md", "guide.md", "guide.md"]);
+
+        let evaluation = evaluate_query(labeled("setup", &["guide.md", "faq.md"]), retrieved, 3);
+
+        assert_close(evaluation.recall, 0.5);
+        assert_close(evaluation.precision, 1.0 / 3.0);
+        assert_close(evaluation.reciprocal_rank, 0.5);
+    }
+
+    #[test]
+    fn a_query_without_relevant_hits_scores_zero() {
+        let evaluation = evaluate_query(labeled("setup", &["faq.md"]), files(&["intro.md"]), 5);
+
+        assert_close(evaluation.recall, 0.0);
+        assert_close(evaluation.precision, 0.0);
+        assert_close(evaluation.reciprocal_rank, 0.0);
+    }
+
+    #[test]
+    fn summary_averages_each_metric_over_the_queries() {
+        let results = vec![
+            evaluate_query(labeled("a", &["a.md"]), files(&["a.md", "x.md"]), 2),
+            evaluate_query(labeled("b", &["b.md"]), files(&["x.md", "y.md"]), 2),
+        ];
+
+        let summary = summarize(2, results);
+
+        assert_eq!(summary.query_count, 2);
+        assert_close(summary.recall_at_k, 0.5);
+        assert_close(summary.precision_at_k, 0.25);
+        assert_close(summary.mrr, 0.5);
+    }
+
+    #[test]
+    fn validation_rejects_bad_cutoffs_and_unlabeled_queries() {
+        let request = |queries| EvalRequest { queries, k: None };
+
+        assert!(validate(&request(vec![]), 5).is_err());
+        assert!(validate(&request(vec![labeled("a", &["a.md"])]), 0).is_err());
+        assert!(validate(&request(vec![labeled("a", &["a.md"])]), MAX_EVAL_K + 1).is_err());
+        assert!(validate(&request(vec![labeled("a", &[])]), 5).is_err());
+        assert!(validate(&request(vec![labeled("a", &["a.md"])]), MAX_EVAL_K).is_ok());
+    }
+}
@@ -1,141 +1,764 @@
 use axum::{
-    extract::{Path, Query, State},
-    http::StatusCode,
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, HeaderName, StatusCode},
     response::IntoResponse,
     Json,
 };
+use base64::{engine::general_purpose, Engine as _};
+use futures::stream;
+use qdrant_client::{
+    qdrant::{Condition, DeletePointsBuilder, Filter, SetPayloadPointsBuilder},
+    Payload,
+};
 use serde::Deserialize;
+use uuid::Uuid;
+use xlib::collection::fields;
 
-use crate::{AppState, models::file_embedding_task::{CreateTaskRequest, FileEmbeddingTask, TaskStatus, UpdateTaskRequest}};
+use crate::{
+    error::{ApiError, ApiJson, ApiPath, ApiQuery},
+    models::file_embedding_task::{
+        BulkCreateTasksRequest, BulkCreateTasksResponse, CreateTaskRequest, FileEmbeddingTask,
+        InvalidStatusTransition, SortOrder, TaskCreation, TaskFilter, TaskInputs, TaskResponse,
+        TaskSortColumn, TaskStatus, UpdateMetadataRequest, UpdateTaskRequest,
+    },
+    AppState,
+};
 
 #[derive(Debug, Deserialize)]
 pub struct ListTasksQuery {
-    pub status: Option<TaskStatus>,
+    #[serde(flatten)]
+    pub filter: TaskFilter,
+    /// `created_at` (default), `updated_at` or `status`
+    #[serde(default)]
+    pub sort_by: TaskSortColumn,
+    /// `asc` or `desc` (default)
+    #[serde(default)]
+    pub order: SortOrder,
     pub limit: Option<i64>,
     pub offset: Option<i64>,
 }
 
+/// The export takes the list's filters, without its sorting and paging
+#[derive(Debug, Deserialize)]
+pub struct ExportTasksQuery {
+    #[serde(flatten)]
+    pub filter: TaskFilter,
+}
+
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+/// Most tasks accepted in one bulk creation request
+const MAX_BULK_TASKS: usize = 100;
+
+/// Topic the file-processor consumes `task_created` events from
+const TASKS_TOPIC: &str = "file-embedding-tasks";
+
+/// Header making task creation safe to retry; a repeated key returns the task it created
+pub const IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Longest accepted `Idempotency-Key`, the width of its column
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// Longest accepted `source` in characters, the width of its column
+const MAX_SOURCE_LENGTH: usize = 100;
+
+const EXPORT_CSV_HEADER: &str = "id,file_name,status,created_at,updated_at,started_at,completed_at,error_message,embedding_count,tags,metadata,source,error_details,correlation_id\n";
+
+/// Record key and payload of the event that makes the file-processor embed `task`.
+/// Without inline content the file-processor loads it from the task row.
+fn task_created_event(
+    app_state: &AppState,
+    task: &TaskResponse,
+    inputs: TaskInputs,
+) -> (String, serde_json::Value) {
+    let file_content = inputs.file_content.filter(|_| app_state.inline_task_content);
+    let kafka_payload = serde_json::json!({
+        "task_id": task.id,
+        "file_name": task.file_name,
+        "file_content": file_content,
+        "attachments": inputs.attachments,
+        "source": task.source,
+        "content_type": inputs.content_type,
+        "status": task.status,
+        "correlation_id": task.correlation_id
+    });
+
+    // Keyed by file name so events for one file stay ordered on a single partition
+    (task.file_name.clone(), kafka_payload)
+}
+
+/// Publish the event that makes the file-processor embed `task`
+async fn publish_task_created(
+    app_state: &AppState,
+    task: &TaskResponse,
+    inputs: TaskInputs,
+) -> anyhow::Result<()> {
+    let (key, payload) = task_created_event(app_state, task, inputs);
+    app_state
+        .kafka_client
+        .produce_event_with_key(TASKS_TOPIC, &key, "task_created", payload)
+        .await
+}
+
+/// Reject `file_content` the file-processor could not decode, or whose decoded size exceeds
+/// `max_bytes`, before a task is stored and published
+fn validate_file_content(file_content: &str, max_bytes: usize) -> Result<(), ApiError> {
+    let decoded = general_purpose::STANDARD.decode(file_content).map_err(|e| {
+        ApiError::bad_request(
+            "invalid_file_content",
+            format!("file_content is not valid base64: {}", e),
+        )
+    })?;
+    if decoded.len() > max_bytes {
+        return Err(ApiError::bad_request(
+            "file_too_large",
+            format!(
+                "file_content decodes to {} bytes, more than the {} allowed",
+                decoded.len(),
+                max_bytes
+            ),
+        )
+        .with_field("max_bytes", max_bytes));
+    }
+    Ok(())
+}
+
+/// Reject a `source` longer than its column, which Postgres would refuse with an error
+fn validate_source(source: Option<&str>) -> Result<(), ApiError> {
+    match source {
+        Some(source) if source.chars().count() > MAX_SOURCE_LENGTH => {
+            Err(ApiError::bad_request(
+                "source_too_long",
+                format!("source must be at most {} characters", MAX_SOURCE_LENGTH),
+            )
+            .with_field("max_length", MAX_SOURCE_LENGTH))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reject `metadata` that isn't a JSON object. A `null` never gets here: it deserializes as
+/// `None`, which is bound as SQL `NULL` and leaves the stored metadata unchanged.
+fn validate_metadata(metadata: Option<&serde_json::Value>) -> Result<(), ApiError> {
+    match metadata {
+        Some(metadata) if !metadata.is_object() => Err(ApiError::bad_request(
+            "invalid_metadata",
+            "metadata must be a JSON object",
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// The request's `Idempotency-Key`, if it sent one
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<&str>, ApiError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LENGTH => Ok(Some(key)),
+        _ => Err(ApiError::bad_request(
+            "invalid_idempotency_key",
+            format!(
+                "Idempotency-Key must be 1 to {} visible ASCII characters",
+                MAX_IDEMPOTENCY_KEY_LENGTH
+            ),
+        )),
+    }
+}
+
 pub async fn create_task(
     State(app_state): State<AppState>,
-    Json(payload): Json<CreateTaskRequest>,
-) -> impl IntoResponse {
-    // Store file_content before payload is moved
-    let file_content = payload.file_content.clone();
-    
-    // Create task in database
-    match FileEmbeddingTask::create(&app_state.db_pool, payload).await {
-        Ok(task) => {
-            // Send Kafka message after successful task creation
-            let kafka_payload = serde_json::json!({
-                "task_id": task.id,
-                "file_name": task.file_name,
-                "file_content": file_content,
-                "status": task.status
-            });
-
-            if let Err(e) = app_state.kafka_client
-                .produce_event("file-embedding-tasks", "task_created", kafka_payload)
-                .await
-            {
-                tracing::error!("Failed to send Kafka message: {}", e);
-                // Continue anyway - don't fail the API call if Kafka is down
-            } else {
-                tracing::info!("Sent Kafka message for task creation: {}", task.id);
-            }
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<CreateTaskRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let idempotency_key = idempotency_key(&headers)?;
+    validate_file_content(&payload.file_content, app_state.max_file_bytes)?;
+    validate_source(payload.source.as_deref())?;
 
-            (StatusCode::CREATED, Json(task)).into_response()
-        }
-        Err(e) => {
-            tracing::error!("Failed to create task: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to create task"})),
+    // Copy the event inputs before payload is moved
+    let inputs = TaskInputs {
+        file_content: app_state.inline_task_content.then(|| payload.file_content.clone()),
+        content_type: payload.content_type.clone(),
+        attachments: payload.attachments.clone().unwrap_or_default(),
+    };
+
+    // Ties together the logs of every service that handles this task
+    let correlation_id = Uuid::new_v4();
+
+    // Create task in database
+    let creation = match idempotency_key {
+        Some(key) => {
+            FileEmbeddingTask::create_idempotent(
+                &app_state.db_pool,
+                payload,
+                correlation_id,
+                key,
+                app_state.idempotency_key_ttl,
             )
-                .into_response()
+            .await
         }
+        None => FileEmbeddingTask::create(&app_state.db_pool, payload, correlation_id)
+            .await
+            .map(TaskCreation::Created),
     }
+    .map_err(|e| ApiError::internal("task_create_failed", "Failed to create task", &e))?;
+    let task = match creation {
+        TaskCreation::Created(task) => task,
+        // Its event was published when the key was first used
+        TaskCreation::Replayed(task) => {
+            tracing::info!("Idempotency key replayed, returning existing task {}", task.id);
+            return Ok((StatusCode::OK, Json(task)));
+        }
+    };
+
+    // Send Kafka message after successful task creation
+    if let Err(e) = publish_task_created(&app_state, &task, inputs).await {
+        tracing::error!("Failed to send Kafka message (correlation id {}): {}", correlation_id, e);
+        // Continue anyway - don't fail the API call if Kafka is down
+    } else {
+        tracing::info!(
+            "Sent Kafka message for task creation: {} (correlation id {})",
+            task.id,
+            correlation_id
+        );
+    }
+
+    Ok((StatusCode::CREATED, Json(task)))
 }
 
-pub async fn get_task(
+/// Create up to `MAX_BULK_TASKS` tasks and publish their events in one producer flush.
+///
+/// As with a single task, one whose event could not be published is still created; its id
+/// is listed in `unqueued_task_ids`.
+pub async fn create_tasks_bulk(
     State(app_state): State<AppState>,
-    Path(id): Path<i32>,
-) -> impl IntoResponse {
-    match FileEmbeddingTask::find_by_id(&app_state.db_pool, id).await {
-        Ok(Some(task)) => (StatusCode::OK, Json(task)).into_response(),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({"error": "Task not found"})),
+    ApiJson(payload): ApiJson<BulkCreateTasksRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    if payload.tasks.is_empty() || payload.tasks.len() > MAX_BULK_TASKS {
+        return Err(ApiError::bad_request(
+            "invalid_task_count",
+            format!("Between 1 and {} tasks are accepted per request", MAX_BULK_TASKS),
         )
-            .into_response(),
-        Err(e) => {
-            tracing::error!("Failed to get task: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to get task"})),
-            )
-                .into_response()
+        .with_field("max_tasks", MAX_BULK_TASKS));
+    }
+    for (index, task) in payload.tasks.iter().enumerate() {
+        validate_file_content(&task.file_content, app_state.max_file_bytes)
+            .and_then(|()| validate_source(task.source.as_deref()))
+            .map_err(|e| e.with_field("index", index))?;
+    }
+
+    let mut inputs = Vec::with_capacity(payload.tasks.len());
+    let mut requests = Vec::with_capacity(payload.tasks.len());
+    for task in payload.tasks {
+        inputs.push(TaskInputs {
+            file_content: app_state.inline_task_content.then(|| task.file_content.clone()),
+            content_type: task.content_type.clone(),
+            attachments: task.attachments.clone().unwrap_or_default(),
+        });
+        requests.push((task, Uuid::new_v4()));
+    }
+    let tasks = FileEmbeddingTask::create_many(&app_state.db_pool, requests)
+        .await
+        .map_err(|e| ApiError::internal("task_create_failed", "Failed to create tasks", &e))?;
+
+    let events = tasks
+        .iter()
+        .zip(inputs)
+        .map(|(task, inputs)| task_created_event(&app_state, task, inputs))
+        .collect();
+    let deliveries = app_state
+        .kafka_client
+        .produce_events(TASKS_TOPIC, "task_created", events)
+        .await;
+    let mut unqueued_task_ids = Vec::new();
+    for (task, delivery) in tasks.iter().zip(deliveries) {
+        if let Err(e) = delivery {
+            tracing::error!(
+                "Failed to send Kafka message for task {} (correlation id {:?}): {}",
+                task.id,
+                task.correlation_id,
+                e
+            );
+            unqueued_task_ids.push(task.id);
         }
     }
+
+    Ok((
+        StatusCode::CREATED,
+        Json(BulkCreateTasksResponse {
+            tasks,
+            unqueued_task_ids,
+        }),
+    ))
 }
 
-pub async fn list_tasks(
+fn task_not_found() -> ApiError {
+    ApiError::not_found("task_not_found", "Task not found")
+}
+
+// Re-run a failed task: reset it to pending and publish its task_created event again
+pub async fn retry_task(
     State(app_state): State<AppState>,
-    Query(params): Query<ListTasksQuery>,
-) -> impl IntoResponse {
-    match FileEmbeddingTask::list_all(&app_state.db_pool, params.status, params.limit, params.offset).await {
-        Ok(tasks) => (StatusCode::OK, Json(tasks)).into_response(),
-        Err(e) => {
-            tracing::error!("Failed to list tasks: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to list tasks"})),
-            )
-                .into_response()
+    ApiPath(id): ApiPath<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let inputs = FileEmbeddingTask::inputs(&app_state.db_pool, id)
+        .await
+        .map_err(|e| ApiError::internal("task_retry_failed", "Failed to retry task", &e))?
+        .ok_or_else(task_not_found)?;
+    if inputs.file_content.is_none() {
+        return Err(ApiError::conflict(
+            "content_unavailable",
+            "Task was created without stored file content and cannot be retried",
+        ));
+    }
+
+    let task = requeue_task(&app_state, id, inputs)
+        .await
+        .map_err(|e| match e {
+            RequeueError::Reset(e) => retry_rejection(id, &e),
+            RequeueError::Publish(_) => ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "kafka_unavailable",
+                "Failed to queue the task for processing",
+            ),
+        })?
+        .ok_or_else(task_not_found)?;
+    Ok((StatusCode::ACCEPTED, Json(task)))
+}
+
+/// Why a failed task could not be queued again
+pub enum RequeueError {
+    /// The task could not be reset to `pending`, e.g. because it is not `failed`
+    Reset(anyhow::Error),
+    /// The task was reset but its event could not be published; it is `failed` again
+    Publish(anyhow::Error),
+}
+
+/// Reset failed task `id` to `pending` and publish its `task_created` event with `inputs`
+/// again. `None` when the task doesn't exist.
+pub async fn requeue_task(
+    app_state: &AppState,
+    id: i32,
+    inputs: TaskInputs,
+) -> Result<Option<TaskResponse>, RequeueError> {
+    // The reset only succeeds from `failed`, so a second call racing this one fails
+    // instead of publishing the event twice
+    let Some(task) = FileEmbeddingTask::retry(&app_state.db_pool, id)
+        .await
+        .map_err(RequeueError::Reset)?
+    else {
+        return Ok(None);
+    };
+
+    if let Err(e) = publish_task_created(app_state, &task, inputs).await {
+        tracing::error!("Failed to send Kafka message for retry of task {}: {}", id, e);
+        // Put the task back in `failed` so the retry can be requested again
+        let revert = UpdateTaskRequest {
+            status: Some(TaskStatus::Failed),
+            error_message: Some(format!("Retry could not be queued: {}", e)),
+            embedding_count: None,
+            timeline_events: None,
+            error_details: None,
+            full_content: None,
+        };
+        if let Err(e) = FileEmbeddingTask::update(&app_state.db_pool, id, revert).await {
+            tracing::error!("Failed to return task {} to failed: {}", id, e);
         }
+        return Err(RequeueError::Publish(e));
     }
+
+    tracing::info!("Sent Kafka message for retry of task {}", id);
+    Ok(Some(task))
+}
+
+fn retry_rejection(id: i32, error: &anyhow::Error) -> ApiError {
+    if let Some(transition) = error.downcast_ref::<InvalidStatusTransition>() {
+        return ApiError::conflict(
+            "task_not_retryable",
+            format!("Only failed tasks can be retried; task {} is {}", id, transition.from),
+        );
+    }
+    ApiError::internal("task_retry_failed", "Failed to retry task", error)
+}
+
+pub async fn get_task(
+    State(app_state): State<AppState>,
+    ApiPath(id): ApiPath<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let task = FileEmbeddingTask::find_by_id(&app_state.db_pool, id)
+        .await
+        .map_err(|e| ApiError::internal("task_read_failed", "Failed to get task", &e))?
+        .ok_or_else(task_not_found)?;
+    Ok(Json(task))
+}
+
+// Base64 content of a task's file, read by the file-processor when the Kafka message
+// does not carry it
+pub async fn get_task_content(
+    State(app_state): State<AppState>,
+    ApiPath(id): ApiPath<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let file_content = FileEmbeddingTask::file_content(&app_state.db_pool, id)
+        .await
+        .map_err(|e| ApiError::internal("task_read_failed", "Failed to get task content", &e))?
+        .ok_or_else(|| ApiError::not_found("task_content_not_found", "Task content not found"))?;
+    Ok(Json(serde_json::json!({"id": id, "file_content": file_content})))
+}
+
+pub async fn list_tasks(
+    State(app_state): State<AppState>,
+    ApiQuery(params): ApiQuery<ListTasksQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let sort = (params.sort_by, params.order);
+
+    let tasks = FileEmbeddingTask::list_all(
+        &app_state.db_pool,
+        params.filter,
+        sort,
+        params.limit,
+        params.offset,
+    )
+            .await
+            .map_err(|e| ApiError::internal("task_read_failed", "Failed to list tasks", &e))?;
+    Ok(Json(tasks))
 }
 
 pub async fn update_task(
     State(app_state): State<AppState>,
-    Path(id): Path<i32>,
-    Json(payload): Json<UpdateTaskRequest>,
-) -> impl IntoResponse {
-    match FileEmbeddingTask::update(&app_state.db_pool, id, payload).await {
-        Ok(Some(task)) => (StatusCode::OK, Json(task)).into_response(),
-        Ok(None) => (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({"error": "Task not found"})),
+    ApiPath(id): ApiPath<i32>,
+    ApiJson(payload): ApiJson<UpdateTaskRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let task = FileEmbeddingTask::update(&app_state.db_pool, id, payload)
+        .await
+        .map_err(|e| {
+            if e.downcast_ref::<InvalidStatusTransition>().is_some() {
+                ApiError::conflict("invalid_status_transition", e.to_string())
+            } else {
+                ApiError::internal("task_update_failed", "Failed to update task", &e)
+            }
+        })?
+        .ok_or_else(task_not_found)?;
+    Ok(Json(task))
+}
+
+pub async fn delete_task(
+    State(app_state): State<AppState>,
+    ApiPath(id): ApiPath<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let deleted = FileEmbeddingTask::delete(&app_state.db_pool, id)
+        .await
+        .map_err(|e| ApiError::internal("task_delete_failed", "Failed to delete task", &e))?;
+    if !deleted {
+        return Err(task_not_found());
+    }
+
+    // Purge the task's points so the document doesn't linger in search results
+    if let Err(e) = delete_task_points(&app_state, id).await {
+        tracing::error!("Failed to delete Qdrant points of deleted task {}: {}", id, e);
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "vector_delete_failed",
+            "Task deleted but failed to delete its vectors",
         )
-            .into_response(),
-        Err(e) => {
-            tracing::error!("Failed to update task: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to update task"})),
-            )
-                .into_response()
-        }
+        .with_field("task_id", id));
     }
+    Ok(StatusCode::NO_CONTENT)
 }
 
-pub async fn delete_task(
+/// Delete every point still owned by `task_id`: its chunks and attachments. Points of the
+/// same file re-processed by a later task belong to that task and are kept.
+async fn delete_task_points(app_state: &AppState, task_id: i32) -> anyhow::Result<()> {
+    app_state
+        .qdrant_client
+        .delete_points(
+            DeletePointsBuilder::new(&app_state.collection)
+                .points(Filter::must([Condition::matches(
+                    fields::TASK_ID,
+                    i64::from(task_id),
+                )]))
+                .wait(true),
+        )
+        .await?;
+    Ok(())
+}
+
+pub async fn get_task_timeline(
     State(app_state): State<AppState>,
-    Path(id): Path<i32>,
-) -> impl IntoResponse {
-    match FileEmbeddingTask::delete(&app_state.db_pool, id).await {
-        Ok(true) => StatusCode::NO_CONTENT.into_response(),
-        Ok(false) => (
-            StatusCode::NOT_FOUND,
-            Json(serde_json::json!({"error": "Task not found"})),
+    ApiPath(id): ApiPath<i32>,
+) -> Result<impl IntoResponse, ApiError> {
+    let timeline = FileEmbeddingTask::timeline(&app_state.db_pool, id)
+        .await
+        .map_err(|e| ApiError::internal("task_read_failed", "Failed to get task timeline", &e))?
+        .ok_or_else(task_not_found)?;
+    Ok(Json(timeline))
+}
+
+pub async fn update_task_metadata(
+    State(app_state): State<AppState>,
+    ApiPath(id): ApiPath<i32>,
+    ApiJson(payload): ApiJson<UpdateMetadataRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    validate_metadata(payload.metadata.as_ref())?;
+
+    let task = FileEmbeddingTask::update_metadata(&app_state.db_pool, id, payload)
+        .await
+        .map_err(|e| {
+            ApiError::internal("task_update_failed", "Failed to update task metadata", &e)
+        })?
+        .ok_or_else(task_not_found)?;
+
+    // Mirror the new metadata onto the task's points without touching their vectors
+    if let Err(e) = set_points_metadata(&app_state, &task).await {
+        tracing::error!("Failed to update Qdrant payload for task {}: {}", id, e);
+        return Err(ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "vector_payload_update_failed",
+            "Task metadata updated but failed to update vector payload",
+        ));
+    }
+
+    Ok(Json(task))
+}
+
+async fn set_points_metadata(app_state: &AppState, task: &TaskResponse) -> anyhow::Result<()> {
+    let payload = Payload::try_from(serde_json::json!({
+        fields::TAGS: task.tags,
+        fields::METADATA: task.metadata,
+    }))?;
+
+    app_state
+        .qdrant_client
+        .set_payload(
+            SetPayloadPointsBuilder::new(&app_state.collection, payload)
+                .points_selector(Filter::must([Condition::matches(
+                    fields::TASK_ID,
+                    i64::from(task.id),
+                )]))
+                .wait(true),
         )
-            .into_response(),
-        Err(e) => {
-            tracing::error!("Failed to delete task: {}", e);
+        .await?;
+
+    Ok(())
+}
+
+pub async fn export_tasks_csv(
+    State(app_state): State<AppState>,
+    ApiQuery(params): ApiQuery<ExportTasksQuery>,
+) -> impl IntoResponse {
+    let header_chunk = stream::once(async { Ok::<_, anyhow::Error>(EXPORT_CSV_HEADER.to_string()) });
+
+    // Fetch one keyset page per chunk so the full table is never held in memory
+    let rows = stream::try_unfold(Some(0), move |after_id| {
+        let pool = app_state.db_pool.clone();
+        let filter = params.filter.clone();
+        async move {
+            let Some(after_id) = after_id else {
+                return Ok(None);
+            };
+
+            let tasks = FileEmbeddingTask::list_after(&pool, &filter, after_id, EXPORT_PAGE_SIZE)
+                .await
+                .inspect_err(|e| tracing::error!("Failed to export tasks: {}", e))?;
+
+            let Some(last) = tasks.last() else {
+                return Ok(None);
+            };
+            let next = (tasks.len() == usize::try_from(EXPORT_PAGE_SIZE).unwrap_or(usize::MAX))
+                .then_some(last.id);
+
+            let chunk: String = tasks.into_iter().map(task_csv_row).collect();
+            Ok(Some((chunk, next)))
+        }
+    });
+
+    (
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
             (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(serde_json::json!({"error": "Failed to delete task"})),
-            )
-                .into_response()
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"embedding-tasks.csv\"",
+            ),
+        ],
+        Body::from_stream(futures::StreamExt::chain(header_chunk, rows)),
+    )
+}
+
+fn task_csv_row(task: TaskResponse) -> String {
+    let optional = |value: Option<String>| value.unwrap_or_default();
+
+    let fields = [
+        task.id.to_string(),
+        task.file_name,
+        task.status.to_string(),
+        task.created_at.to_string(),
+        task.updated_at.to_string(),
+        optional(task.started_at.map(|t| t.to_string())),
+        optional(task.completed_at.map(|t| t.to_string())),
+        optional(task.error_message),
+        optional(task.embedding_count.map(|c| c.to_string())),
+        task.tags.join(";"),
+        task.metadata.to_string(),
+        optional(task.source),
+        optional(
+            task.error_details
+                .and_then(|details| serde_json::to_string(&details).ok()),
+        ),
+        optional(task.correlation_id.map(|id| id.to_string())),
+    ];
+
+    let mut row = fields.iter().map(|field| csv_escape(field)).collect::<Vec<_>>().join(",");
+    row.push('\n');
+    row
+}
+
+/// Quote a CSV field when it contains a delimiter, quote, or line break
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn encoded(bytes: &[u8]) -> String {
+        general_purpose::STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn file_content_within_the_limit_is_accepted() {
+        assert!(validate_file_content(&encoded(b"hello"), 5).is_ok());
+        assert!(validate_file_content("", 5).is_ok());
+    }
+
+    #[test]
+    fn file_content_is_measured_after_decoding() {
+        // 6 bytes encode to 8 base64 characters; only the decoded size counts
+        let error = validate_file_content(&encoded(b"hello!"), 5).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "file_too_large");
+        assert!(validate_file_content(&encoded(b"hello!"), 6).is_ok());
+    }
+
+    #[test]
+    fn file_content_must_be_base64() {
+        let error = validate_file_content("not base64!", 1024).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "invalid_file_content");
+    }
+
+    fn headers_with_key(key: &[u8]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IDEMPOTENCY_KEY, HeaderValue::from_bytes(key).unwrap());
+        headers
+    }
+
+    #[test]
+    fn idempotency_key_is_optional() {
+        assert_eq!(idempotency_key(&HeaderMap::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn idempotency_key_is_read_from_the_header() {
+        let headers = headers_with_key(b"upload-2024-06-01-42");
+        assert_eq!(idempotency_key(&headers).unwrap(), Some("upload-2024-06-01-42"));
+
+        let longest = "k".repeat(MAX_IDEMPOTENCY_KEY_LENGTH);
+        let headers = headers_with_key(longest.as_bytes());
+        assert_eq!(idempotency_key(&headers).unwrap(), Some(longest.as_str()));
+    }
+
+    #[test]
+    fn idempotency_key_rejects_empty_long_and_non_ascii_values() {
+        let too_long = "k".repeat(MAX_IDEMPOTENCY_KEY_LENGTH + 1);
+        for key in [&b""[..], too_long.as_bytes(), "clé".as_bytes()] {
+            let error = idempotency_key(&headers_with_key(key)).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(error.code(), "invalid_idempotency_key");
+        }
+    }
+
+    #[test]
+    fn export_parses_the_same_filters_as_the_list() {
+        let uri: axum::http::Uri = "/api/v1/embedding-tasks/export.csv?status=failed&source=crawler\
+            &created_after=2025-06-22T13:00:00Z&updated_before=2025-06-23T00:00:00Z"
+            .parse()
+            .unwrap();
+
+        let axum::extract::Query(export) =
+            axum::extract::Query::<ExportTasksQuery>::try_from_uri(&uri).unwrap();
+        let axum::extract::Query(list) =
+            axum::extract::Query::<ListTasksQuery>::try_from_uri(&uri).unwrap();
+
+        for filter in [export.filter, list.filter] {
+            assert_eq!(filter.status, Some(TaskStatus::Failed));
+            assert_eq!(filter.source.as_deref(), Some("crawler"));
+            assert_eq!(
+                filter.created_after.unwrap().to_rfc3339(),
+                "2025-06-22T13:00:00+00:00"
+            );
+            assert!(filter.created_before.is_none());
+            assert!(filter.updated_after.is_none());
+            assert_eq!(
+                filter.updated_before.unwrap().to_rfc3339(),
+                "2025-06-23T00:00:00+00:00"
+            );
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn list_paging_parses_next_to_the_filters() {
+        let uri: axum::http::Uri = "/api/v1/embedding-tasks?status=failed&limit=10&offset=20"
+            .parse()
+            .unwrap();
+
+        let axum::extract::Query(list) =
+            axum::extract::Query::<ListTasksQuery>::try_from_uri(&uri).unwrap();
+
+        assert_eq!(list.filter.status, Some(TaskStatus::Failed));
+        assert_eq!((list.limit, list.offset), (Some(10), Some(20)));
+    }
+
+    #[test]
+    fn export_rejects_an_unknown_status() {
+        let uri: axum::http::Uri = "/api/v1/embedding-tasks/export.csv?status=done"
+            .parse()
+            .unwrap();
+        assert!(axum::extract::Query::<ExportTasksQuery>::try_from_uri(&uri).is_err());
+    }
+
+    #[test]
+    fn metadata_must_be_an_object() {
+        assert!(validate_metadata(Some(&serde_json::json!({"owner": "data-team"}))).is_ok());
+        assert!(validate_metadata(None).is_ok());
+        for metadata in [serde_json::json!(["a"]), serde_json::json!("a"), serde_json::json!(1)] {
+            let error = validate_metadata(Some(&metadata)).unwrap_err();
+            assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(error.code(), "invalid_metadata");
+        }
+    }
+
+    #[test]
+    fn null_metadata_is_left_unset() {
+        let request: UpdateMetadataRequest =
+            serde_json::from_str(r#"{"tags": ["faq"], "metadata": null}"#).unwrap();
+        assert!(request.metadata.is_none());
+    }
+
+    #[test]
+    fn source_is_limited_to_its_column_width() {
+        assert!(validate_source(None).is_ok());
+        assert!(validate_source(Some(&"a".repeat(100))).is_ok());
+        // The column counts characters, not bytes
+        assert!(validate_source(Some(&"é".repeat(100))).is_ok());
+
+        let error = validate_source(Some(&"a".repeat(101))).unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "source_too_long");
+    }
+}
@@ -1,2 +1,5 @@
+pub mod admin;
+pub mod eval;
 pub mod file_embedding_task;
+pub mod query;
 pub mod search;
\ No newline at end of file
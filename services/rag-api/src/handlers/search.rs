@@ -1,170 +1,1369 @@
 use anyhow::Result;
+use std::{collections::HashMap, time::Instant};
 use axum::{
     extract::State,
     http::StatusCode,
     response::IntoResponse,
     Json,
 };
-use qdrant_client::qdrant::{SearchParamsBuilder, SearchPointsBuilder};
-use reqwest;
+use qdrant_client::qdrant::{
+    point_id::PointIdOptions, vector_output, vectors_config, with_vectors_selector, Condition,
+    CountPointsBuilder, Filter, PointId,
+    ScoredPoint, SearchParamsBuilder, SearchPointsBuilder, Value, VectorsOutput, VectorsSelector,
+};
 use serde::{Deserialize, Serialize};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+use qdrant_client::Qdrant;
+use xlib::{
+    client::{openai::effective_dimensions, EmbeddingProvider, OpenAIError},
+    collection::{check_configured_vector_size, fields, PRIMARY_VECTOR, RERANK_VECTOR},
+    tokens::{max_embed_tokens, trim_to_token_limit},
+    vector::{cosine_similarity, l2_normalize},
+};
 
-use crate::AppState;
+use crate::{
+    error::{ApiError, ApiJson},
+    highlight,
+    models::file_embedding_task::{FileEmbeddingTask, KeywordFilter},
+    AppState,
+};
 
 #[derive(Deserialize)]
 pub struct SearchRequest {
     pub query: String,
+    /// Additional paraphrases searched alongside `query` and fused with reciprocal rank fusion
+    pub queries: Option<Vec<String>>,
+    /// Page size, at most [`TopK::max`]
     pub limit: Option<u64>,
+    /// Results to skip before the page starts
+    pub offset: Option<u64>,
+    /// Only return documents ingested from this source
+    pub source: Option<String>,
+    /// Payload fields (e.g. `file_name`, `source`, `tags`) to count values of across the candidates
+    pub facets: Option<Vec<String>>,
+    /// Return each result's raw embedding vector (adds ~1536 floats per result)
+    pub include_vectors: Option<bool>,
+    /// Drop results whose cosine similarity score is below this, applied after the search
+    pub score_threshold: Option<f32>,
+    /// Restrict the search to points matching every given field
+    pub filter: Option<SearchFilter>,
+    /// `vector` (default), `keyword` (Postgres full-text search) or `hybrid` (both, fused)
+    #[serde(default)]
+    pub mode: SearchMode,
+    /// Share of a hybrid result's fused score taken from the keyword ranking (default 0.5)
+    pub keyword_weight: Option<f32>,
+    /// Pick each highlight by embedding similarity rather than shared terms (default false)
+    pub semantic_highlight: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    #[default]
+    Vector,
+    Keyword,
+    Hybrid,
+}
+
+/// Which retrieval path returned a result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetrievalPath {
+    Vector,
+    Keyword,
+    Both,
+}
+
+#[derive(Deserialize)]
+pub struct SearchFilter {
+    pub file_name: Option<String>,
+    pub task_id: Option<i64>,
 }
 
 
 #[derive(Serialize)]
 pub struct SearchResult {
+    /// Absent for keyword hits, which match a whole document rather than a stored point
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub point_id: Option<String>,
     pub score: f32,
     pub task_id: u64,
     pub file_name: String,
+    /// Position of the matching chunk within its file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u64>,
     pub content_snippet: String,
+    /// Excerpt centered on the sentence that best matches the query
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub highlight: Option<String>,
+    /// Text the highlight is cut from
+    #[serde(skip)]
+    text: String,
+    /// Original file whose vector this result shares, when ingested as a duplicate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicate_of: Option<String>,
+    /// File that referenced this result, when it was ingested as an attachment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Reciprocal rank fusion score, present when several queries were searched or
+    /// rankings were fused in hybrid mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fused_score: Option<f32>,
+    pub matched_queries: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector: Option<Vec<f32>>,
+    /// Model that embedded the point, absent for points stored before it was recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub embedding_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_dim: Option<u64>,
+    pub retrieved_by: RetrievalPath,
+}
+
+#[derive(Serialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: usize,
 }
 
 #[derive(Serialize)]
 pub struct SearchResponse {
     pub query: String,
     pub results: Vec<SearchResult>,
+    /// Results on this page
     pub total_found: usize,
+    pub offset: u64,
+    /// Page size applied after capping
+    pub limit: u64,
+    /// Value counts per requested payload field, most frequent first
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub facets: Option<HashMap<String, Vec<FacetCount>>>,
 }
 
-// OpenAI API structures (same as in file-processor)
-#[derive(Serialize)]
-struct EmbeddingRequest {
-    input: String,
-    model: String,
+/// Results returned when a search or query sets no `limit`
+const DEFAULT_TOP_K: u64 = 5;
+
+/// Largest `limit` accepted when `MAX_TOP_K` is unset
+const DEFAULT_MAX_TOP_K: u64 = 50;
+
+/// Deepest offset served. Qdrant still scores every skipped point, and fused rankings are
+/// built in memory, so deep pages cost as much as one huge page.
+pub const MAX_SEARCH_OFFSET: u64 = 1_000;
+
+/// Most distinct queries fused for one request, the primary one included. Each costs an
+/// embedding call and a search.
+pub const MAX_FUSION_QUERIES: usize = 10;
+
+/// Minimum number of candidates retrieved when facets are requested
+const FACET_CANDIDATES: u64 = 100;
+
+/// Rank offset used by reciprocal rank fusion; 60 is the value from the original paper
+const RRF_K: f32 = 60.0;
+
+/// Share of a hybrid score taken from the keyword ranking when the request sets none
+const DEFAULT_KEYWORD_WEIGHT: f32 = 0.5;
+
+/// Candidates taken from the primary vector for reranking when `RERANK_CANDIDATES` is unset
+const DEFAULT_RERANK_CANDIDATES: u64 = 50;
+
+/// Two-stage retrieval: a first pass over the primary model's vectors, rescored
+/// against the rerank model's vectors of the same points
+#[derive(Clone)]
+pub struct RerankConfig {
+    pub model: String,
+    /// Share of the final score taken from the rerank similarity; 1.0 ranks by it alone
+    pub weight: f32,
+    /// Candidates retrieved in the first pass, at least the requested limit
+    pub candidates: u64,
 }
 
-#[derive(Deserialize)]
-struct EmbeddingResponse {
-    data: Vec<EmbeddingData>,
+impl RerankConfig {
+    /// Read `RERANK_EMBEDDING_MODEL`, `RERANK_WEIGHT` and `RERANK_CANDIDATES`;
+    /// `None` when no rerank model is set
+    pub fn from_env() -> Result<Option<Self>> {
+        let Some(model) = std::env::var("RERANK_EMBEDDING_MODEL")
+            .ok()
+            .filter(|model| !model.is_empty())
+        else {
+            return Ok(None);
+        };
+
+        let weight = match std::env::var("RERANK_WEIGHT") {
+            Ok(value) => value
+                .parse::<f32>()
+                .ok()
+                .filter(|weight| (0.0..=1.0).contains(weight))
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Invalid RERANK_WEIGHT '{}': expected a number between 0 and 1", value)
+                })?,
+            Err(_) => 1.0,
+        };
+
+        Ok(Some(Self {
+            model,
+            weight,
+            candidates: std::env::var("RERANK_CANDIDATES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_RERANK_CANDIDATES),
+        }))
+    }
 }
 
-#[derive(Deserialize)]
-struct EmbeddingData {
-    embedding: Vec<f32>,
+/// Number of results served by searches and queries
+#[derive(Clone, Copy)]
+pub struct TopK {
+    /// Results returned when the request sets no `limit`
+    pub default: u64,
+    /// Largest `limit` accepted; larger ones are rejected rather than reduced
+    pub max: u64,
 }
 
-const COLLECTION_NAME: &str = "rag-collection";
+impl TopK {
+    /// Read `DEFAULT_TOP_K` (default 5) and `MAX_TOP_K` (default 50)
+    pub fn from_env() -> Result<Self> {
+        let read = |name: &str, default: u64| match std::env::var(name) {
+            Ok(value) => value.parse::<u64>().ok().filter(|k| *k > 0).ok_or_else(|| {
+                anyhow::anyhow!("Invalid {} '{}': expected a positive integer", name, value)
+            }),
+            Err(_) => Ok(default),
+        };
+        let top_k = Self {
+            default: read("DEFAULT_TOP_K", DEFAULT_TOP_K)?,
+            max: read("MAX_TOP_K", DEFAULT_MAX_TOP_K)?,
+        };
+        if top_k.default > top_k.max {
+            return Err(anyhow::anyhow!(
+                "DEFAULT_TOP_K ({}) must not exceed MAX_TOP_K ({})",
+                top_k.default,
+                top_k.max
+            ));
+        }
+        Ok(top_k)
+    }
 
-async fn generate_query_embedding(query: &str) -> Result<Vec<f32>> {
-    let api_key = std::env::var("OPENAI_API_KEY")
-        .map_err(|_| anyhow::anyhow!("OPENAI_API_KEY environment variable not set"))?;
-        
-    let client = reqwest::Client::new();
-    
-    let request_body = EmbeddingRequest {
-        input: query.to_string(),
-        model: "text-embedding-3-small".to_string(),
+    /// The requested limit, or the default when none is set; `None` when it exceeds the max
+    pub fn resolve(self, requested: Option<u64>) -> Option<u64> {
+        let limit = requested.unwrap_or(self.default);
+        (limit <= self.max).then_some(limit)
+    }
+}
+
+/// Error for a `limit` above [`TopK::max`]
+pub fn limit_too_large(max: u64) -> ApiError {
+    ApiError::bad_request("limit_too_large", format!("limit must be at most {}", max))
+        .with_field("max_limit", max)
+}
+
+/// Error for a failed retrieval, by what failed.
+///
+/// The embedding provider rejecting the request (a 4xx other than a timeout or rate limit)
+/// is a `502`, a failed keyword query or a local embedding check is a `500`, and anything
+/// else, such as Qdrant, Postgres or the provider being unreachable, is a `503`.
+pub fn retrieval_error(error: &anyhow::Error) -> ApiError {
+    let openai = error.chain().find_map(|cause| cause.downcast_ref::<OpenAIError>());
+    if let Some(openai) = openai {
+        match openai.status() {
+            Some(status) if (400..500).contains(&status) && status != 408 && status != 429 => {
+                error!("Embedding provider rejected the search request: {:#}", error);
+                return ApiError::new(
+                    StatusCode::BAD_GATEWAY,
+                    "embedding_rejected",
+                    "The embedding provider rejected the request",
+                );
+            }
+            None => return ApiError::internal("search_failed", "Search failed", error),
+            Some(_) => {}
+        }
+    }
+
+    // A query Postgres ran and failed is a bug, not an outage
+    let database = error.chain().find_map(|cause| cause.downcast_ref::<sqlx::Error>());
+    let query_failed = database.is_some_and(|e| {
+        !matches!(
+            e,
+            sqlx::Error::PoolTimedOut
+                | sqlx::Error::PoolClosed
+                | sqlx::Error::Io(_)
+                | sqlx::Error::Tls(_)
+                | sqlx::Error::WorkerCrashed
+        )
+    });
+    if query_failed {
+        return ApiError::internal("search_failed", "Search failed", error);
+    }
+
+    error!("Retrieval failed: {:#}", error);
+    ApiError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "retrieval_unavailable",
+        "Document retrieval is unavailable",
+    )
+}
+
+pub fn too_many_queries() -> ApiError {
+    ApiError::bad_request(
+        "too_many_queries",
+        format!("At most {} distinct queries are accepted, query included", MAX_FUSION_QUERIES),
+    )
+    .with_field("max_queries", MAX_FUSION_QUERIES)
+}
+
+/// Name of the primary model's vector; `None` for a collection with a single unnamed vector
+const fn primary_vector_name(rerank: Option<&RerankConfig>) -> Option<&'static str> {
+    if rerank.is_some() {
+        Some(PRIMARY_VECTOR)
+    } else {
+        None
+    }
+}
+
+/// A retrieved point along with the queries that matched it
+pub struct RankedPoint {
+    pub point: ScoredPoint,
+    pub fused_score: Option<f32>,
+    pub matched_queries: Vec<String>,
+}
+
+/// Render a Qdrant point id (UUID or numeric) as a string
+pub fn point_id_to_string(point_id: Option<PointId>) -> String {
+    match point_id.and_then(|id| id.point_id_options) {
+        Some(PointIdOptions::Uuid(uuid)) => uuid,
+        Some(PointIdOptions::Num(num)) => num.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Refuse to start when query embeddings would not fit the collection's vectors or
+/// `QDRANT_VECTOR_SIZE`, unless `SKIP_DIMENSION_CHECK=true`.
+///
+/// A missing collection is skipped; the file-processor creates it.
+pub async fn verify_collection_dimensions(
+    qdrant_client: &Qdrant,
+    collection: &str,
+    embeddings: &dyn EmbeddingProvider,
+    rerank: Option<&RerankConfig>,
+) -> Result<()> {
+    if std::env::var("SKIP_DIMENSION_CHECK").is_ok_and(|v| v == "true") {
+        warn!("⚠️ Skipping the embedding dimension check");
+        return Ok(());
+    }
+
+    if std::env::var_os("QDRANT_VECTOR_SIZE").is_some() {
+        let model = embeddings.embedding_model();
+        let model_size = model_dimensions(embeddings, model, "EMBEDDING_DIMENSIONS").await?;
+        check_configured_vector_size(model, model_size)?;
+    }
+
+    if !qdrant_client
+        .collection_exists(collection)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to check collection existence: {}", e))?
+    {
+        warn!("Collection '{}' does not exist yet, skipping the dimension check", collection);
+        return Ok(());
+    }
+
+    let vectors_config = qdrant_client
+        .collection_info(collection)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read collection info: {}", e))?
+        .result
+        .and_then(|info| info.config)
+        .and_then(|config| config.params)
+        .and_then(|params| params.vectors_config)
+        .and_then(|vectors_config| vectors_config.config);
+    let Some(vectors_config) = vectors_config else {
+        warn!("Could not determine the collection's vector size to check it against the model");
+        return Ok(());
     };
-    
-    info!("🔍 Generating embedding for search query: '{}'", query);
-    
-    let response = client
-        .post("https://api.openai.com/v1/embeddings")
-        .header("Content-Type", "application/json")
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&request_body)
-        .send()
+
+    let mut expected = vec![(
+        primary_vector_name(rerank),
+        embeddings.embedding_model().to_string(),
+        "EMBEDDING_DIMENSIONS",
+    )];
+    if let Some(rerank) = rerank {
+        expected.push((Some(RERANK_VECTOR), rerank.model.clone(), "RERANK_EMBEDDING_DIMENSIONS"));
+    }
+
+    for (vector_name, model, dimensions_var) in expected {
+        let collection_size = match (&vectors_config, vector_name) {
+            (vectors_config::Config::Params(params), None) => Some(params.size),
+            (vectors_config::Config::ParamsMap(params_map), Some(name)) => {
+                params_map.map.get(name).map(|params| params.size)
+            }
+            _ => None,
+        };
+        let Some(collection_size) = collection_size else {
+            return Err(anyhow::anyhow!(
+                "Collection '{}' has no {} vector; RERANK_EMBEDDING_MODEL must match how \
+                 the collection was created (SKIP_DIMENSION_CHECK=true bypasses this check)",
+                collection,
+                vector_name.unwrap_or("unnamed")
+            ));
+        };
+
+        let model_size = model_dimensions(embeddings, &model, dimensions_var).await?;
+        if model_size != collection_size {
+            return Err(anyhow::anyhow!(
+                "Collection '{}' stores {}-dimension vectors but embedding model '{}' produces {}; \
+                 set EMBEDDING_MODEL to the model the collection was built with \
+                 (SKIP_DIMENSION_CHECK=true bypasses this check)",
+                collection,
+                collection_size,
+                model,
+                model_size
+            ));
+        }
+
+        info!(
+            "✅ Embedding model '{}' matches the collection's {} dimensions",
+            model, model_size
+        );
+    }
+    Ok(())
+}
+
+/// Dimension of `model`: `dimensions_var` when the model accepts it, then the known table,
+/// then a probe embedding
+async fn model_dimensions(
+    embeddings: &dyn EmbeddingProvider,
+    model: &str,
+    dimensions_var: &str,
+) -> Result<u64> {
+    let configured = std::env::var(dimensions_var)
+        .ok()
+        .and_then(|v| v.parse().ok());
+    if let Some(size) = effective_dimensions(model, configured) {
+        return Ok(size);
+    }
+
+    let probe = embeddings
+        .create_embedding_with_model("dimension check", model)
         .await?;
-    
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_else(|_| "Unknown error".to_string());
-        return Err(anyhow::anyhow!("OpenAI API request failed with status {}: {}", status, error_text));
+    Ok(u64::try_from(probe.len())?)
+}
+
+/// Points embedded by `model`, or stored before the model was recorded. Vectors of another
+/// model live in a different space, so comparing them with the query is meaningless.
+fn embedding_model_condition(model: &str) -> Condition {
+    Filter::should([
+        Condition::matches(fields::EMBEDDING_MODEL, model.to_string()),
+        Condition::is_empty(fields::EMBEDDING_MODEL),
+    ])
+    .into()
+}
+
+/// Warn at startup about points search will skip because another model embedded them, and
+/// about points whose model was never recorded. Failing to count them only logs.
+pub async fn warn_about_model_mismatches(
+    qdrant_client: &Qdrant,
+    collection: &str,
+    model: &str,
+) {
+    if !qdrant_client.collection_exists(collection).await.unwrap_or(false) {
+        return;
     }
-    
-    let embedding_response: EmbeddingResponse = response.json().await?;
-    
-    if let Some(embedding_data) = embedding_response.data.first() {
-        let embedding = embedding_data.embedding.clone();
-        info!("✅ Query embedding generated successfully ({} dimensions)", embedding.len());
-        Ok(embedding)
-    } else {
-        Err(anyhow::anyhow!("No embedding data received from OpenAI API"))
+    let count = |filter: Filter| async {
+        qdrant_client
+            .count(CountPointsBuilder::new(collection).filter(filter).exact(true))
+            .await
+            .map(|response| response.result.map_or(0, |result| result.count))
+    };
+    let mismatched = Filter::must_not([
+        Condition::matches(fields::EMBEDDING_MODEL, model.to_string()),
+        Condition::is_empty(fields::EMBEDDING_MODEL),
+    ]);
+    let unrecorded = Filter::must([Condition::is_empty(fields::EMBEDDING_MODEL)]);
+
+    match tokio::try_join!(count(mismatched), count(unrecorded)) {
+        Ok((mismatched, unrecorded)) => {
+            if mismatched > 0 {
+                warn!(
+                    "⚠️ {} points were embedded by a model other than '{}' and are excluded \
+                     from search; reprocess their tasks to include them",
+                    mismatched, model
+                );
+            }
+            if unrecorded > 0 {
+                warn!(
+                    "⚠️ {} points have no recorded embedding model and are searched as if \
+                     embedded by '{}'",
+                    unrecorded, model
+                );
+            }
+        }
+        Err(e) => warn!("Could not count points by embedding model: {}", e),
     }
 }
 
 // Search endpoint with JSON body
 pub async fn search_embeddings(
     State(app_state): State<AppState>,
-    Json(search_request): Json<SearchRequest>,
-) -> impl IntoResponse {
+    ApiJson(search_request): ApiJson<SearchRequest>,
+) -> Result<impl IntoResponse, ApiError> {
     info!("🔍 Search request received: '{}'", search_request.query);
+
+    if let Some(weight) = search_request.keyword_weight {
+        if !(0.0..=1.0).contains(&weight) {
+            return Err(ApiError::bad_request(
+                "invalid_search_request",
+                "keyword_weight must be between 0 and 1",
+            )
+            .with_field("keyword_weight", weight));
+        }
+    }
+    if search_request.offset.is_some_and(|offset| offset > MAX_SEARCH_OFFSET) {
+        return Err(ApiError::bad_request(
+            "invalid_search_request",
+            format!("offset must be at most {}", MAX_SEARCH_OFFSET),
+        )
+        .with_field("max_offset", MAX_SEARCH_OFFSET));
+    }
+    let limit = app_state
+        .top_k
+        .resolve(search_request.limit)
+        .ok_or_else(|| limit_too_large(app_state.top_k.max))?;
     
-    let limit = search_request.limit.unwrap_or(5);
+    let queries = collect_queries(&search_request.query, search_request.queries.as_deref())
+        .ok_or_else(too_many_queries)?;
     
-    match perform_search(&app_state, &search_request.query, limit).await {
-        Ok(response) => (StatusCode::OK, Json(response)),
-        Err(e) => {
-            error!("Search failed: {}", e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(SearchResponse {
-                    query: search_request.query,
-                    results: vec![],
-                    total_found: 0,
-                }),
-            )
+    let filter = search_filter(&search_request);
+    
+    let started = Instant::now();
+    let result = perform_search(&app_state, &search_request, &queries, filter, limit).await;
+    metrics::histogram!(
+        "search_duration_seconds",
+        "outcome" => if result.is_ok() { "success" } else { "error" },
+    )
+    .record(started.elapsed());
+
+    let response = result.map_err(|e| retrieval_error(&e))?;
+    Ok(Json(response))
+}
+
+
+/// Qdrant filter requiring every condition in the request; `None` when it sets none
+fn search_filter(request: &SearchRequest) -> Option<Filter> {
+    let mut conditions = Vec::new();
+    if let Some(source) = &request.source {
+        conditions.push(Condition::matches(fields::SOURCE, source.clone()));
+    }
+    if let Some(filter) = &request.filter {
+        if let Some(file_name) = &filter.file_name {
+            conditions.push(Condition::matches(fields::FILE_NAME, file_name.clone()));
+        }
+        if let Some(task_id) = filter.task_id {
+            conditions.push(Condition::matches(fields::TASK_ID, task_id));
         }
     }
+
+    (!conditions.is_empty()).then(|| Filter::must(conditions))
 }
 
+/// The primary query followed by any distinct additional queries, or `None` when there are
+/// more than `MAX_FUSION_QUERIES` of them
+pub fn collect_queries(query: &str, extra: Option<&[String]>) -> Option<Vec<String>> {
+    let mut queries = vec![query.to_string()];
+    for extra_query in extra.unwrap_or_default() {
+        if !queries.contains(extra_query) {
+            queries.push(extra_query.clone());
+        }
+    }
+    (queries.len() <= MAX_FUSION_QUERIES).then_some(queries)
+}
 
-async fn perform_search(app_state: &AppState, query: &str, limit: u64) -> Result<SearchResponse> {
+async fn search_points_for_query(
+    app_state: &AppState,
+    query: &str,
+    filter: Option<Filter>,
+    offset: u64,
+    limit: u64,
+    with_vectors: bool,
+) -> Result<Vec<ScoredPoint>> {
     // Generate embedding for the search query
-    let query_embedding = generate_query_embedding(query).await?;
-    
+    info!("🔍 Generating embedding for search query: '{}'", query);
+    let model = app_state.embeddings.embedding_model();
+    let mut query_embedding = app_state
+        .embeddings
+        .create_embedding(&embedding_input(query, model))
+        .await?;
+    if app_state.normalize_embeddings {
+        l2_normalize(&mut query_embedding);
+    }
+
+    let Some(rerank) = &app_state.rerank else {
+        let page = SearchPage { offset, limit };
+        return search_vector(app_state, query_embedding, None, filter, page, with_vectors.into())
+            .await;
+    };
+
+    // Cheap first pass over the primary vectors, fetching the rerank vectors to rescore with.
+    // Reranking reorders the candidates, so the offset is applied after it.
+    let end = offset.saturating_add(limit);
+    let candidates = search_vector(
+        app_state,
+        query_embedding,
+        Some(PRIMARY_VECTOR),
+        filter,
+        SearchPage { offset: 0, limit: end.max(rerank.candidates) },
+        VectorsSelector {
+            names: vec![PRIMARY_VECTOR.to_string(), RERANK_VECTOR.to_string()],
+        }
+        .into(),
+    )
+    .await?;
+
+    let mut rerank_embedding = app_state
+        .embeddings
+        .create_embedding_with_model(&embedding_input(query, &rerank.model), &rerank.model)
+        .await?;
+    if app_state.normalize_embeddings {
+        l2_normalize(&mut rerank_embedding);
+    }
+
+    let mut reranked =
+        rerank_points(candidates, &rerank_embedding, rerank.weight, end, with_vectors);
+    skip_offset(&mut reranked, offset);
+    Ok(reranked)
+}
+
+/// Window of a ranking passed to Qdrant as `offset` and `limit`
+#[derive(Clone, Copy)]
+struct SearchPage {
+    offset: u64,
+    limit: u64,
+}
+
+/// Drop the first `offset` items; an offset past the end leaves nothing
+fn skip_offset<T>(items: &mut Vec<T>, offset: u64) {
+    let skipped = usize::try_from(offset).unwrap_or(usize::MAX).min(items.len());
+    items.drain(..skipped);
+}
+
+async fn search_vector(
+    app_state: &AppState,
+    query_embedding: Vec<f32>,
+    vector_name: Option<&str>,
+    filter: Option<Filter>,
+    page: SearchPage,
+    with_vectors: with_vectors_selector::SelectorOptions,
+) -> Result<Vec<ScoredPoint>> {
     // Perform similarity search in Qdrant
     info!("🎯 Searching for similar embeddings in Qdrant...");
     
-    let search_result = app_state
-        .qdrant_client
-        .search_points(
-            SearchPointsBuilder::new(COLLECTION_NAME, query_embedding, limit)
-                .with_payload(true)
-                .params(SearchParamsBuilder::default()),
-        )
+    let mut search_builder =
+        SearchPointsBuilder::new(app_state.vector_store.collection(), query_embedding, page.limit)
+            .offset(page.offset)
+            .with_payload(true)
+            .with_vectors(with_vectors)
+            .params(SearchParamsBuilder::default());
+    if let Some(vector_name) = vector_name {
+        search_builder = search_builder.vector_name(vector_name);
+    }
+    if let Some(read_consistency) = app_state.read_consistency {
+        search_builder = search_builder.read_consistency(read_consistency);
+    }
+    let mut filter = filter.unwrap_or_default();
+    filter.must.push(embedding_model_condition(app_state.embeddings.embedding_model()));
+    search_builder = search_builder.filter(filter);
+
+    app_state
+        .vector_store
+        .search(search_builder.build())
         .await
-        .map_err(|e| anyhow::anyhow!("Qdrant search failed: {}", e))?;
+        .map_err(|e| anyhow::anyhow!("Qdrant search failed: {}", e))
+}
+
+/// Blend each candidate's first-pass score with its rerank similarity, keeping the best `limit`.
+/// Candidates without a rerank vector keep only the first-pass share of their score.
+fn rerank_points(
+    candidates: Vec<ScoredPoint>,
+    rerank_embedding: &[f32],
+    weight: f32,
+    limit: u64,
+    with_vectors: bool,
+) -> Vec<ScoredPoint> {
+    let mut reranked: Vec<ScoredPoint> = candidates
+        .into_iter()
+        .map(|mut point| {
+            let rerank_score = point
+                .vectors
+                .as_ref()
+                .and_then(|vectors| dense_vector(vectors, Some(RERANK_VECTOR)))
+                .map_or(0.0, |vector| cosine_similarity(rerank_embedding, &vector));
+            point.score = (1.0 - weight).mul_add(point.score, weight * rerank_score);
+            if !with_vectors {
+                point.vectors = None;
+            }
+            point
+        })
+        .collect();
+
+    reranked.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(std::cmp::Ordering::Equal));
+    reranked.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    info!("🔁 Reranked candidates with weight {}", weight);
+    reranked
+}
+
+/// The dense vector named `name` (`None` for the unnamed vector) from a point's vectors
+fn dense_vector(vectors: &VectorsOutput, name: Option<&str>) -> Option<Vec<f32>> {
+    match vectors.get_vector_by_name(name.unwrap_or_default()) {
+        Some(vector_output::Vector::Dense(dense)) => Some(dense.data),
+        _ => None,
+    }
+}
+
+/// Search every query and, when there is more than one, fuse the rankings. Returns `limit`
+/// points after skipping the first `offset` of the (fused) ranking.
+pub async fn retrieve_points(
+    app_state: &AppState,
+    queries: &[String],
+    filter: Option<Filter>,
+    offset: u64,
+    limit: u64,
+    with_vectors: bool,
+) -> Result<Vec<RankedPoint>> {
+    if let [query] = queries {
+        let points =
+            search_points_for_query(app_state, query, filter, offset, limit, with_vectors).await?;
+        return Ok(points
+            .into_iter()
+            .map(|point| RankedPoint {
+                point,
+                fused_score: None,
+                matched_queries: vec![query.clone()],
+            })
+            .collect());
+    }
+
+    // Fusion reorders the rankings, so every query is searched from the top
+    info!("🔀 Searching {} queries for fusion", queries.len());
+    let end = offset.saturating_add(limit);
+    let rankings = futures::future::try_join_all(queries.iter().map(|query| {
+        search_points_for_query(app_state, query, filter.clone(), 0, end, with_vectors)
+    }))
+    .await?;
+
+    let mut fused = reciprocal_rank_fusion(queries, rankings, end);
+    skip_offset(&mut fused, offset);
+    Ok(fused)
+}
+
+/// Merge per-query rankings: each point scores the sum of `1 / (RRF_K + rank)` over the
+/// queries that returned it, keeping the copy with the best similarity score
+fn reciprocal_rank_fusion(
+    queries: &[String],
+    rankings: Vec<Vec<ScoredPoint>>,
+    limit: u64,
+) -> Vec<RankedPoint> {
+    let mut fused: HashMap<String, RankedPoint> = HashMap::new();
+
+    for (query, points) in queries.iter().zip(rankings) {
+        for (rank, point) in points.into_iter().enumerate() {
+            #[allow(clippy::cast_precision_loss)]
+            let contribution = 1.0 / (RRF_K + (rank + 1) as f32);
+            let key = point_id_to_string(point.id.clone());
+
+            let entry = fused.entry(key).or_insert_with(|| RankedPoint {
+                point: point.clone(),
+                fused_score: Some(0.0),
+                matched_queries: Vec::new(),
+            });
+            entry.fused_score = entry.fused_score.map(|score| score + contribution);
+            entry.matched_queries.push(query.clone());
+            if point.score > entry.point.score {
+                entry.point = point;
+            }
+        }
+    }
+
+    let mut ranked: Vec<RankedPoint> = fused.into_values().collect();
+    ranked.sort_by(|a, b| b.fused_score.partial_cmp(&a.fused_score).unwrap_or(std::cmp::Ordering::Equal));
+    ranked.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    ranked
+}
+
+async fn perform_search(
+    app_state: &AppState,
+    request: &SearchRequest,
+    queries: &[String],
+    filter: Option<Filter>,
+    limit: u64,
+) -> Result<SearchResponse> {
+    let offset = request.offset.unwrap_or(0);
+
+    // Hybrid fusion reorders both rankings, so each is fetched from the top
+    let page = if request.mode == SearchMode::Hybrid {
+        SearchPage { offset: 0, limit: offset.saturating_add(limit) }
+    } else {
+        SearchPage { offset, limit }
+    };
+
+    let (vector_results, facets) = if request.mode == SearchMode::Keyword {
+        (Vec::new(), None)
+    } else {
+        vector_search(app_state, request, queries, filter, page).await?
+    };
+    let keyword_results = if request.mode == SearchMode::Vector {
+        Vec::new()
+    } else {
+        keyword_search(app_state, request, page).await?
+    };
+
+    let mut results = match request.mode {
+        SearchMode::Vector => vector_results,
+        SearchMode::Keyword => keyword_results,
+        SearchMode::Hybrid => {
+            let weight = request.keyword_weight.unwrap_or(DEFAULT_KEYWORD_WEIGHT);
+            let mut fused = fuse_hybrid(vector_results, keyword_results, weight, page.limit);
+            skip_offset(&mut fused, offset);
+            fused
+        }
+    };
+    add_highlights(app_state, request, &mut results).await;
+
+    Ok(SearchResponse {
+        query: request.query.clone(),
+        total_found: results.len(),
+        results,
+        offset,
+        limit,
+        facets,
+    })
+}
+
+/// Similarity search over the stored points, with facets counted over its candidates
+async fn vector_search(
+    app_state: &AppState,
+    request: &SearchRequest,
+    queries: &[String],
+    filter: Option<Filter>,
+    page: SearchPage,
+) -> Result<(Vec<SearchResult>, Option<HashMap<String, Vec<FacetCount>>>)> {
+    let facet_fields = request.facets.as_deref();
+    let with_vectors = request.include_vectors.unwrap_or(false);
+
+    // Facets are counted over a wider candidate set than the results returned, taken from
+    // the top of the ranking so they don't change from page to page
+    let (offset, candidate_limit) = if facet_fields.is_some() {
+        (0, page.offset.saturating_add(page.limit).max(FACET_CANDIDATES))
+    } else {
+        (page.offset, page.limit)
+    };
+    let mut ranked_points =
+        retrieve_points(app_state, queries, filter, offset, candidate_limit, with_vectors)
+            .await?;
     
-    info!("📊 Found {} similar results", search_result.result.len());
+    info!("📊 Found {} similar results", ranked_points.len());
+
+    // Qdrant returns the top candidates whatever their score, so the cutoff is applied here
+    if let Some(threshold) = request.score_threshold {
+        ranked_points.retain(|ranked| ranked.point.score >= threshold);
+        info!("🎚️ {} results at or above score {}", ranked_points.len(), threshold);
+    }
+
+    let facets = facet_fields.map(|fields| aggregate_facets(&ranked_points, fields));
+    if facets.is_some() {
+        skip_offset(&mut ranked_points, page.offset);
+    }
+    ranked_points.truncate(usize::try_from(page.limit).unwrap_or(usize::MAX));
     
     // Convert Qdrant results to our response format
-    let mut results = Vec::new();
-    let total_found = search_result.result.len();
-    
-    for point in search_result.result {
-        let payload = point.payload;
-        let task_id = payload.get("task_id")
-            .and_then(|v| v.as_integer())
-            .unwrap_or(0) as u64;
-            
-        let file_name = payload.get("file_name")
-            .and_then(|v| v.as_str())
-            .map_or_else(|| "unknown".to_string(), |s| s.to_string());
-            
-        let content_snippet = payload.get("content_snippet")
-            .and_then(|v| v.as_str())
-            .map_or_else(String::new, |s| s.to_string());
+    let results = ranked_points
+        .into_iter()
+        .map(|ranked| vector_result(app_state, ranked))
+        .collect();
+    Ok((results, facets))
+}
+
+/// Fill in the highlight of every result that has none yet, once the page is final so
+/// only returned results are highlighted
+async fn add_highlights(
+    app_state: &AppState,
+    request: &SearchRequest,
+    results: &mut [SearchResult],
+) {
+    let pending: Vec<&mut SearchResult> =
+        results.iter_mut().filter(|result| result.highlight.is_none()).collect();
+    let texts: Vec<&str> = pending.iter().map(|result| result.text.as_str()).collect();
+    let semantic = request.semantic_highlight.unwrap_or(false);
+    let highlights = highlights(app_state, &request.query, &texts, semantic).await;
+    for (result, highlight) in pending.into_iter().zip(highlights) {
+        result.highlight = highlight;
+    }
+}
+
+/// Text a highlight is cut from: the chunk's stored `full_content`, or its snippet for
+/// points ingested with `STORE_FULL_CONTENT=false`
+fn highlight_source(payload: &HashMap<String, Value>) -> String {
+    payload
+        .get(fields::FULL_CONTENT)
+        .or_else(|| payload.get(fields::CONTENT_SNIPPET))
+        .and_then(|v| v.as_str())
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Each text's highlight around its sentence closest to `query`: by embedding similarity
+/// when `semantic` is set, otherwise (or when embedding fails) by shared terms
+async fn highlights(
+    app_state: &AppState,
+    query: &str,
+    texts: &[&str],
+    semantic: bool,
+) -> Vec<Option<String>> {
+    let sentences: Vec<Vec<&str>> = texts
+        .iter()
+        .map(|text| {
+            let mut sentences = highlight::sentences(text);
+            sentences.truncate(highlight::MAX_HIGHLIGHT_SENTENCES);
+            sentences
+        })
+        .collect();
+
+    let best = if semantic {
+        best_sentences_by_embedding(app_state, query, &sentences)
+            .await
+            .unwrap_or_else(|e| {
+                warn!("⚠️ Semantic highlighting failed, matching terms instead: {:#}", e);
+                best_sentences_by_overlap(query, &sentences)
+            })
+    } else {
+        best_sentences_by_overlap(query, &sentences)
+    };
+
+    sentences
+        .iter()
+        .zip(best)
+        .map(|(sentences, best)| {
+            best.map(|best| highlight::window(sentences, best, highlight::HIGHLIGHT_LENGTH))
+        })
+        .collect()
+}
+
+fn best_sentences_by_overlap(query: &str, sentences: &[Vec<&str>]) -> Vec<Option<usize>> {
+    sentences
+        .iter()
+        .map(|sentences| highlight::best_by_overlap(query, sentences))
+        .collect()
+}
+
+/// `text` trimmed to `model`'s embedding input limit, so an oversized query is embedded
+/// from its start instead of failing the request
+fn embedding_input(text: &str, model: &str) -> String {
+    trim_to_token_limit(text, max_embed_tokens(model))
+}
+
+/// Embed the query and every sentence in one batch request and pick each text's most
+/// similar sentence
+async fn best_sentences_by_embedding(
+    app_state: &AppState,
+    query: &str,
+    sentences: &[Vec<&str>],
+) -> Result<Vec<Option<usize>>> {
+    let model = app_state.embeddings.embedding_model();
+    let inputs: Vec<String> = std::iter::once(query)
+        .chain(sentences.iter().flatten().copied())
+        .map(|text| embedding_input(text, model))
+        .collect();
+    let mut embeddings = app_state.embeddings.create_embeddings(&inputs).await?.into_iter();
+    let query_embedding = embeddings.next().unwrap_or_default();
+
+    Ok(sentences
+        .iter()
+        .map(|sentences| {
+            let sentence_embeddings: Vec<Vec<f32>> =
+                embeddings.by_ref().take(sentences.len()).collect();
+            highlight::best_by_similarity(&query_embedding, &sentence_embeddings)
+        })
+        .collect())
+}
+
+fn vector_result(app_state: &AppState, ranked: RankedPoint) -> SearchResult {
+    let RankedPoint { point, fused_score, matched_queries } = ranked;
+    let payload = point.payload;
+    let task_id = payload.get(fields::TASK_ID)
+        .and_then(|v| v.as_integer())
+        .and_then(|v| u64::try_from(v).ok())
+        .unwrap_or(0);
+        
+    let file_name = payload.get(fields::FILE_NAME)
+        .and_then(|v| v.as_str())
+        .map_or_else(|| "unknown".to_string(), Clone::clone);
         
-        results.push(SearchResult {
-            score: point.score,
+    let chunk_index = payload.get(fields::CHUNK_INDEX)
+        .and_then(|v| v.as_integer())
+        .and_then(|v| u64::try_from(v).ok());
+
+    let content_snippet = payload.get(fields::CONTENT_SNIPPET)
+        .and_then(|v| v.as_str())
+        .cloned()
+        .unwrap_or_default();
+
+    let duplicate_of = payload.get(fields::DUPLICATE_OF)
+        .and_then(|v| v.as_str())
+        .cloned();
+
+    let parent_file = payload.get(fields::PARENT_FILE)
+        .and_then(|v| v.as_str())
+        .cloned();
+
+    let source = payload.get(fields::SOURCE)
+        .and_then(|v| v.as_str())
+        .cloned();
+
+    let embedding_model = payload.get(fields::EMBEDDING_MODEL)
+        .and_then(|v| v.as_str())
+        .cloned();
+
+    let vector_dim = payload.get(fields::VECTOR_DIM)
+        .and_then(|v| v.as_integer())
+        .and_then(|v| u64::try_from(v).ok());
+
+    let text = highlight_source(&payload);
+
+    let vector = point.vectors.and_then(|vectors| {
+        dense_vector(&vectors, primary_vector_name(app_state.rerank.as_ref()))
+    });
+    
+    SearchResult {
+        point_id: Some(point_id_to_string(point.id)),
+        score: point.score,
+        task_id,
+        file_name,
+        chunk_index,
+        content_snippet,
+        highlight: None,
+        text,
+        duplicate_of,
+        parent_file,
+        source,
+        fused_score,
+        matched_queries,
+        vector,
+        embedding_model,
+        vector_dim,
+        retrieved_by: RetrievalPath::Vector,
+    }
+}
+
+/// Full-text search of the documents' extracted text for `query`. Paraphrases in
+/// `queries` only feed the vector search.
+async fn keyword_search(
+    app_state: &AppState,
+    request: &SearchRequest,
+    page: SearchPage,
+) -> Result<Vec<SearchResult>> {
+    let filter = KeywordFilter {
+        source: request.source.as_deref(),
+        file_name: request.filter.as_ref().and_then(|f| f.file_name.as_deref()),
+        task_id: request
+            .filter
+            .as_ref()
+            .and_then(|f| f.task_id)
+            .and_then(|id| i32::try_from(id).ok()),
+    };
+    let hits = FileEmbeddingTask::keyword_search(
+        &app_state.db_pool,
+        &request.query,
+        filter,
+        i64::try_from(page.offset).unwrap_or(i64::MAX),
+        i64::try_from(page.limit).unwrap_or(i64::MAX),
+    )
+    .await?;
+    info!("🔤 Found {} keyword matches", hits.len());
+
+    Ok(hits
+        .into_iter()
+        .map(|hit| SearchResult {
+            point_id: None,
+            score: hit.rank,
+            task_id: u64::try_from(hit.id).unwrap_or(0),
+            file_name: hit.file_name,
+            chunk_index: None,
+            highlight: Some(hit.snippet.clone()),
+            text: String::new(),
+            content_snippet: hit.snippet,
+            duplicate_of: None,
+            parent_file: None,
+            source: hit.source,
+            fused_score: None,
+            matched_queries: vec![request.query.clone()],
+            vector: None,
+            embedding_model: None,
+            vector_dim: None,
+            retrieved_by: RetrievalPath::Keyword,
+        })
+        .collect())
+}
+
+/// Weighted reciprocal rank fusion of the vector and keyword rankings per document. Each
+/// document keeps its best vector hit (or its keyword hit when the vector search missed
+/// it) and scores `(1 - keyword_weight) / (RRF_K + vector rank)` plus
+/// `keyword_weight / (RRF_K + keyword rank)`.
+fn fuse_hybrid(
+    vector_results: Vec<SearchResult>,
+    keyword_results: Vec<SearchResult>,
+    keyword_weight: f32,
+    limit: u64,
+) -> Vec<SearchResult> {
+    #[allow(clippy::cast_precision_loss)]
+    let contribution = |weight: f32, rank: usize| weight / (RRF_K + (rank + 1) as f32);
+
+    let mut fused: Vec<SearchResult> = Vec::new();
+    let mut by_task: HashMap<u64, usize> = HashMap::new();
+    for mut result in vector_results {
+        if by_task.contains_key(&result.task_id) {
+            continue;
+        }
+        result.fused_score = Some(contribution(1.0 - keyword_weight, by_task.len()));
+        by_task.insert(result.task_id, fused.len());
+        fused.push(result);
+    }
+
+    for (rank, result) in keyword_results.into_iter().enumerate() {
+        let score = contribution(keyword_weight, rank);
+        match by_task.get(&result.task_id) {
+            Some(&index) => {
+                let existing = &mut fused[index];
+                existing.fused_score = existing.fused_score.map(|fused| fused + score);
+                existing.retrieved_by = RetrievalPath::Both;
+            }
+            None => fused.push(SearchResult {
+                fused_score: Some(score),
+                ..result
+            }),
+        }
+    }
+
+    fused.sort_by(|a, b| {
+        b.fused_score
+            .partial_cmp(&a.fused_score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    fused.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    fused
+}
+
+/// Count payload values per field; list values (like `tags`) count each element
+fn aggregate_facets(points: &[RankedPoint], fields: &[String]) -> HashMap<String, Vec<FacetCount>> {
+    fields
+        .iter()
+        .map(|field| {
+            let mut counts: HashMap<String, usize> = HashMap::new();
+            for value in points.iter().filter_map(|ranked| ranked.point.payload.get(field)) {
+                let values: Vec<&Value> = value
+                    .try_list_iter()
+                    .map_or_else(|| vec![value], Iterator::collect);
+                for value in values {
+                    let key = value.as_str().cloned().unwrap_or_else(|| value.to_string());
+                    *counts.entry(key).or_default() += 1;
+                }
+            }
+
+            let mut facet_counts: Vec<FacetCount> = counts
+                .into_iter()
+                .map(|(value, count)| FacetCount { value, count })
+                .collect();
+            facet_counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+            (field.clone(), facet_counts)
+        })
+        .collect()
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: Option<f32>, expected: f32) {
+        let actual = actual.expect("a fused score");
+        assert!((actual - expected).abs() < 1e-6, "expected {expected}, got {actual}");
+    }
+
+    fn scored(id: u64, score: f32) -> ScoredPoint {
+        ScoredPoint {
+            id: Some(PointId::from(id)),
+            score,
+            ..Default::default()
+        }
+    }
+
+    fn ids(ranked: &[RankedPoint]) -> Vec<String> {
+        ranked.iter().map(|r| point_id_to_string(r.point.id.clone())).collect()
+    }
+
+    fn result(task_id: u64, retrieved_by: RetrievalPath) -> SearchResult {
+        SearchResult {
+            point_id: None,
+            score: 0.0,
             task_id,
-            file_name,
-            content_snippet,
-        });
+            file_name: format!("{}.md", task_id),
+            chunk_index: None,
+            content_snippet: String::new(),
+            highlight: None,
+            text: String::new(),
+            duplicate_of: None,
+            parent_file: None,
+            source: None,
+            fused_score: None,
+            matched_queries: Vec::new(),
+            vector: None,
+            embedding_model: None,
+            vector_dim: None,
+            retrieved_by,
+        }
     }
-    
-    Ok(SearchResponse {
-        query: query.to_string(),
-        results,
-        total_found,
-    })
-}
\ No newline at end of file
+
+    #[test]
+    fn fusion_scores_each_rank_as_one_over_k_plus_rank() {
+        let queries = vec!["a".to_string()];
+        let ranking = vec![scored(1, 0.9), scored(2, 0.8)];
+
+        let fused = reciprocal_rank_fusion(&queries, vec![ranking], 10);
+
+        assert_eq!(ids(&fused), ["1", "2"]);
+        assert_close(fused[0].fused_score, 1.0 / (RRF_K + 1.0));
+        assert_close(fused[1].fused_score, 1.0 / (RRF_K + 2.0));
+    }
+
+    #[test]
+    fn points_found_by_several_queries_rank_first_and_sum_their_contributions() {
+        let queries = vec!["a".to_string(), "b".to_string()];
+        let rankings = vec![
+            vec![scored(1, 0.9), scored(2, 0.5)],
+            vec![scored(2, 0.7), scored(3, 0.6)],
+        ];
+
+        let fused = reciprocal_rank_fusion(&queries, rankings, 10);
+
+        assert_eq!(ids(&fused), ["2", "1", "3"]);
+        assert_close(fused[0].fused_score, 1.0 / (RRF_K + 2.0) + 1.0 / (RRF_K + 1.0));
+        assert_eq!(fused[0].matched_queries, ["a", "b"]);
+        assert_eq!(fused[1].matched_queries, ["a"]);
+        // The copy with the best similarity score is kept
+        assert!((fused[0].point.score - 0.7).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fusion_keeps_at_most_limit_points() {
+        let queries = vec!["a".to_string()];
+        let ranking = (1..=5).map(|id| scored(id, 0.5)).collect();
+
+        let fused = reciprocal_rank_fusion(&queries, vec![ranking], 2);
+
+        assert_eq!(ids(&fused), ["1", "2"]);
+    }
+
+    #[test]
+    fn hybrid_fusion_merges_documents_found_by_both_paths() {
+        let vector = vec![
+            result(1, RetrievalPath::Vector),
+            // A second chunk of task 1 doesn't take a rank
+            result(1, RetrievalPath::Vector),
+            result(2, RetrievalPath::Vector),
+        ];
+        let keyword = vec![result(2, RetrievalPath::Keyword), result(3, RetrievalPath::Keyword)];
+
+        let fused = fuse_hybrid(vector, keyword, 0.5, 10);
+
+        let tasks: Vec<u64> = fused.iter().map(|r| r.task_id).collect();
+        assert_eq!(tasks, [2, 1, 3]);
+        assert_eq!(fused[0].retrieved_by, RetrievalPath::Both);
+        assert_close(fused[0].fused_score, 0.5 / (RRF_K + 2.0) + 0.5 / (RRF_K + 1.0));
+        assert_eq!(fused[1].retrieved_by, RetrievalPath::Vector);
+        assert_close(fused[1].fused_score, 0.5 / (RRF_K + 1.0));
+        assert_eq!(fused[2].retrieved_by, RetrievalPath::Keyword);
+        assert_close(fused[2].fused_score, 0.5 / (RRF_K + 2.0));
+    }
+
+    #[test]
+    fn keyword_weight_decides_which_ranking_leads() {
+        let vector = || vec![result(1, RetrievalPath::Vector), result(2, RetrievalPath::Vector)];
+        let keyword = || vec![result(2, RetrievalPath::Keyword), result(1, RetrievalPath::Keyword)];
+        let order = |weight| -> Vec<u64> {
+            fuse_hybrid(vector(), keyword(), weight, 10).iter().map(|r| r.task_id).collect()
+        };
+
+        assert_eq!(order(0.2), [1, 2]);
+        assert_eq!(order(0.8), [2, 1]);
+        assert_eq!(fuse_hybrid(vector(), keyword(), 0.8, 1).len(), 1);
+    }
+
+    fn point_with(payload: qdrant_client::Payload) -> RankedPoint {
+        RankedPoint {
+            point: ScoredPoint {
+                payload: payload.into(),
+                ..Default::default()
+            },
+            fused_score: None,
+            matched_queries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn facets_count_each_list_element_and_sort_by_count_then_value() {
+        let points: Vec<RankedPoint> = [
+            serde_json::json!({"source": "wiki", "tags": ["ops", "db"]}),
+            serde_json::json!({"source": "wiki", "tags": ["db"]}),
+            serde_json::json!({"source": "drive", "tags": []}),
+            serde_json::json!({"chunk_index": 2}),
+        ]
+        .into_iter()
+        .map(|payload| point_with(payload.try_into().unwrap()))
+        .collect();
+        let fields = ["source", "tags", "chunk_index", "missing"].map(String::from);
+
+        let facets = aggregate_facets(&points, &fields);
+
+        let counts = |field: &str| -> Vec<(String, usize)> {
+            facets[field].iter().map(|f| (f.value.clone(), f.count)).collect()
+        };
+        assert_eq!(counts("source"), [("wiki".to_string(), 2), ("drive".to_string(), 1)]);
+        assert_eq!(counts("tags"), [("db".to_string(), 2), ("ops".to_string(), 1)]);
+        assert_eq!(counts("chunk_index"), [("2".to_string(), 1)]);
+        assert!(facets["missing"].is_empty());
+    }
+
+    #[test]
+    fn provider_rejections_are_bad_gateway_errors() {
+        for status in [400, 401, 404] {
+            let error = OpenAIError::from_response_text(status, "rejected")
+                .context("Failed to embed search query");
+            let api_error = retrieval_error(&error);
+            assert_eq!(api_error.status(), StatusCode::BAD_GATEWAY, "{}", status);
+            assert_eq!(api_error.code(), "embedding_rejected");
+        }
+    }
+
+    #[test]
+    fn local_embedding_checks_and_failed_keyword_queries_are_internal_errors() {
+        let too_large = anyhow::Error::from(OpenAIError::InputTooLarge { limit: 1, actual: 2 });
+        let bad_query = anyhow::Error::from(sqlx::Error::Protocol("syntax error".to_string()))
+            .context("Keyword search failed");
+
+        for error in [too_large, bad_query] {
+            let api_error = retrieval_error(&error);
+            assert_eq!(api_error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+            assert_eq!(api_error.code(), "search_failed");
+        }
+    }
+
+    #[test]
+    fn unreachable_dependencies_are_unavailable() {
+        let errors = [
+            OpenAIError::from_response_text(429, "slow down"),
+            OpenAIError::from_response_text(503, "overloaded"),
+            anyhow::Error::from(sqlx::Error::PoolTimedOut),
+            anyhow::anyhow!("Qdrant search failed: transport error"),
+        ];
+
+        for error in errors {
+            let api_error = retrieval_error(&error);
+            assert_eq!(api_error.status(), StatusCode::SERVICE_UNAVAILABLE, "{:#}", error);
+            assert_eq!(api_error.code(), "retrieval_unavailable");
+        }
+    }
+}
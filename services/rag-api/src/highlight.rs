@@ -0,0 +1,157 @@
+use std::collections::HashSet;
+
+use xlib::vector::cosine_similarity;
+
+/// Characters in a highlight, matching the default ingestion snippet length
+pub const HIGHLIGHT_LENGTH: usize = 200;
+
+/// Sentences of a text considered for its highlight
+pub const MAX_HIGHLIGHT_SENTENCES: usize = 20;
+
+/// Words shorter than this don't count towards keyword overlap ("a", "of", "to")
+const MIN_TERM_LENGTH: usize = 3;
+
+/// Sentences of `text`, split after `.`, `!` or `?` followed by whitespace and at line
+/// breaks, with surrounding whitespace trimmed
+pub fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        let next_is_space = chars.peek().is_some_and(|(_, next)| next.is_whitespace());
+        if c == '\n' || (matches!(c, '.' | '!' | '?') && next_is_space) {
+            let end = index + c.len_utf8();
+            sentences.push(text[start..end].trim());
+            start = end;
+        }
+    }
+    sentences.push(text[start..].trim());
+    sentences.retain(|sentence| !sentence.is_empty());
+    sentences
+}
+
+/// Lowercased words of `text` long enough to be meaningful
+fn terms(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_TERM_LENGTH)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Index of the sentence sharing the most distinct terms with `query`, the earliest on a
+/// tie; `None` when no sentence shares any
+pub fn best_by_overlap(query: &str, sentences: &[&str]) -> Option<usize> {
+    let query_terms = terms(query);
+    sentences
+        .iter()
+        .map(|sentence| terms(sentence).intersection(&query_terms).count())
+        .enumerate()
+        .filter(|(_, overlap)| *overlap > 0)
+        .min_by_key(|(index, overlap)| (std::cmp::Reverse(*overlap), *index))
+        .map(|(index, _)| index)
+}
+
+/// Index of the sentence embedding most similar to the query embedding
+pub fn best_by_similarity(query: &[f32], sentences: &[Vec<f32>]) -> Option<usize> {
+    sentences
+        .iter()
+        .map(|sentence| cosine_similarity(query, sentence))
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(index, _)| index)
+}
+
+/// The `best` sentence plus as many neighbours, alternating before and after, as fit in
+/// `max_chars`. `...` marks text cut on either side.
+pub fn window(sentences: &[&str], best: usize, max_chars: usize) -> String {
+    let length = |sentence: &str| sentence.chars().count();
+    let best_sentence = sentences[best];
+    if length(best_sentence) > max_chars {
+        let end = best_sentence
+            .char_indices()
+            .nth(max_chars)
+            .map_or(best_sentence.len(), |(end, _)| end);
+        let prefix = if best > 0 { "..." } else { "" };
+        return format!("{}{}...", prefix, &best_sentence[..end]);
+    }
+
+    let (mut start, mut end) = (best, best + 1);
+    let mut used = length(best_sentence);
+    loop {
+        let before = start.checked_sub(1).map(|i| (i, length(sentences[i])));
+        let after = sentences.get(end).map(|sentence| (end, length(sentence)));
+        // Prefer the side with fewer sentences taken so the match stays central
+        let sides = if best - start <= end - 1 - best {
+            [before, after]
+        } else {
+            [after, before]
+        };
+        let Some((index, chars)) = sides
+            .into_iter()
+            .flatten()
+            .find(|(_, chars)| used + 1 + chars <= max_chars)
+        else {
+            break;
+        };
+        used += 1 + chars;
+        if index < start {
+            start = index;
+        } else {
+            end = index + 1;
+        }
+    }
+
+    let mut highlight = sentences[start..end].join(" ");
+    if start > 0 {
+        highlight.insert_str(0, "...");
+    }
+    if end < sentences.len() {
+        highlight.push_str("...");
+    }
+    highlight
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sentences_split_at_terminators_and_line_breaks() {
+        let text = "Install it. Then run v1.2 now!\nDone?  Yes";
+        assert_eq!(sentences(text), ["Install it.", "Then run v1.2 now!", "Done?", "Yes"]);
+        assert!(sentences("  \n ").is_empty());
+    }
+
+    #[test]
+    fn overlap_picks_the_sentence_sharing_most_query_terms() {
+        let text = ["The cat sat.", "Reset the password in settings.", "Password rules."];
+
+        assert_eq!(best_by_overlap("how to reset my password", &text), Some(1));
+        // Earliest wins a tie; short words like "to" never count
+        assert_eq!(best_by_overlap("cat rules", &text), Some(0));
+        assert_eq!(best_by_overlap("to be or", &text), None);
+    }
+
+    #[test]
+    fn similarity_picks_the_closest_sentence_embedding() {
+        let sentences = [vec![1.0, 0.0], vec![0.6, 0.8], vec![0.0, 1.0]];
+        assert_eq!(best_by_similarity(&[0.0, 1.0], &sentences), Some(2));
+        assert_eq!(best_by_similarity(&[0.0, 1.0], &[]), None);
+    }
+
+    #[test]
+    fn window_adds_neighbours_that_fit_and_marks_cuts() {
+        let text = ["One.", "Two.", "Three.", "Four.", "Five."];
+
+        assert_eq!(window(&text, 2, 100), "One. Two. Three. Four. Five.");
+        assert_eq!(window(&text, 2, 17), "...Two. Three. Four....");
+        assert_eq!(window(&text, 0, 9), "One. Two....");
+    }
+
+    #[test]
+    fn window_cuts_a_best_sentence_longer_than_the_limit() {
+        let text = ["Intro.", "Ééééééééé long sentence."];
+        assert_eq!(window(&text, 1, 5), "...Ééééé...");
+        assert_eq!(window(&text[1..], 0, 5), "Ééééé...");
+    }
+}
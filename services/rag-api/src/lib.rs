@@ -0,0 +1,199 @@
+pub mod error;
+pub mod handlers;
+mod highlight;
+pub mod models;
+pub mod rate_limit;
+
+use anyhow::Result;
+use axum::{
+    extract::{DefaultBodyLimit, Json, State},
+    middleware,
+    response::IntoResponse,
+    routing::{delete, get, patch, post, put},
+    Router,
+};
+use qdrant_client::qdrant::read_consistency;
+use qdrant_client::Qdrant;
+use serde_json::json;
+use std::sync::Arc;
+use tower_http::{
+    cors::CorsLayer,
+    trace::{DefaultMakeSpan, TraceLayer},
+};
+use tracing::{warn, Level};
+use xlib::{
+    app::metrics::PrometheusHandle,
+    client::{ChatProvider, EmbeddingProvider, KafkaClient, PostgresClient, VectorStore},
+};
+
+use handlers::admin::{check_consistency, repair_consistency};
+use handlers::eval::evaluate_retrieval;
+use handlers::file_embedding_task::{
+    create_task, create_tasks_bulk, delete_task, export_tasks_csv, get_task, get_task_content,
+    get_task_timeline, list_tasks, retry_task, update_task, update_task_metadata,
+};
+use handlers::query::{
+    query_handler, query_stream_handler, ContextSafetyMode, RetrievalFallback,
+};
+use handlers::search::{search_embeddings, RerankConfig, TopK};
+use rate_limit::{rate_limit, RateLimiter};
+
+/// Room left in a task creation body for the fields besides `file_content`
+const TASK_BODY_OVERHEAD_BYTES: usize = 64 * 1024;
+
+/// How long the health check waits for each dependency
+const DEPENDENCY_PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct AppState {
+    pub db_pool: PostgresClient,
+    pub kafka_client: std::sync::Arc<KafkaClient>,
+    /// For Qdrant features [`VectorStore`] doesn't cover: scrolls, facets, payload updates
+    pub qdrant_client: std::sync::Arc<Qdrant>,
+    /// Qdrant collection searched and updated, from `QDRANT_COLLECTION`
+    pub collection: String,
+    /// Vector searches, served by a `QdrantStore` on `collection`
+    pub vector_store: std::sync::Arc<dyn VectorStore>,
+    /// Query embeddings, served by the `OpenAIClient`
+    pub embeddings: std::sync::Arc<dyn EmbeddingProvider>,
+    /// Answer generation for `/query`
+    pub chat: std::sync::Arc<dyn ChatProvider>,
+    /// Read consistency applied to Qdrant searches; `None` keeps the server default
+    pub read_consistency: Option<read_consistency::Value>,
+    pub retrieval_fallback: RetrievalFallback,
+    pub context_safety: ContextSafetyMode,
+    /// Characters of document content allowed in a `/query` prompt context
+    pub context_max_chars: usize,
+    /// Chunks of one file kept in a `/query` context (`MAX_CHUNKS_PER_FILE`); 0 keeps all
+    pub max_chunks_per_file: usize,
+    /// Upper bound on a `/query` request's `timeout_ms`, also used when none is given
+    pub query_max_timeout: std::time::Duration,
+    /// Scale query vectors to unit length, matching `NORMALIZE_EMBEDDINGS` in the file-processor
+    pub normalize_embeddings: bool,
+    /// Default and largest number of results for `/search` and `/query`
+    pub top_k: TopK,
+    /// Two-stage retrieval over named vectors; `None` searches the single unnamed vector
+    pub rerank: Option<RerankConfig>,
+    /// Send file content in `task_created` messages; otherwise the file-processor loads it
+    /// from the task row
+    pub inline_task_content: bool,
+    /// Largest decoded `file_content` accepted when creating a task (`MAX_FILE_BYTES`)
+    pub max_file_bytes: usize,
+    /// How long a task creation `Idempotency-Key` is remembered (`IDEMPOTENCY_KEY_TTL_SECS`)
+    pub idempotency_key_ttl: std::time::Duration,
+}
+
+async fn health_check(State(app_state): State<AppState>) -> impl IntoResponse {
+    let qdrant_ping = async {
+        tokio::time::timeout(DEPENDENCY_PING_TIMEOUT, app_state.qdrant_client.health_check())
+            .await
+            .map_err(|_| anyhow::anyhow!("Qdrant health check timed out"))?
+            .map(|_| ())
+            .map_err(anyhow::Error::from)
+    };
+    let (db, qdrant, kafka) = tokio::join!(
+        app_state.db_pool.ping(DEPENDENCY_PING_TIMEOUT),
+        qdrant_ping,
+        app_state.kafka_client.ping(DEPENDENCY_PING_TIMEOUT),
+    );
+    let db = dependency_status("Postgres", db);
+    let qdrant = dependency_status("Qdrant", qdrant);
+    let kafka = dependency_status("Kafka", kafka);
+
+    // The process itself is up, so report which dependency is down rather than failing
+    // the check; the API keeps serving whatever doesn't need it
+    let all_ok = [db, qdrant, kafka].iter().all(|status| *status == "ok");
+    let status = if all_ok { "ok" } else { "degraded" };
+
+    Json(json!({
+        "status": status,
+        "service": "rag-api",
+        "db": db,
+        "qdrant": qdrant,
+        "kafka": kafka
+    }))
+}
+
+fn dependency_status(name: &str, result: Result<()>) -> &'static str {
+    match result {
+        Ok(()) => "ok",
+        Err(e) => {
+            warn!("{} health check failed: {:#}", name, e);
+            "unavailable"
+        }
+    }
+}
+
+pub fn router(
+    app_state: AppState,
+    embedding_limit: Option<Arc<RateLimiter>>,
+    general_limit: Option<Arc<RateLimiter>>,
+    worker_limit: Option<Arc<RateLimiter>>,
+    cors: CorsLayer,
+    metrics: Option<PrometheusHandle>,
+) -> Router {
+    // Base64 takes 4 bytes per 3, so the JSON body limit must fit the encoded file
+    let task_body_limit = app_state.max_file_bytes.div_ceil(3) * 4 + TASK_BODY_OVERHEAD_BYTES;
+
+    // Endpoints that embed the request with OpenAI
+    let embedding_routes = Router::new()
+        .route("/api/v1/query", post(query_handler))
+        .route("/api/v1/query/stream", post(query_stream_handler))
+        .route("/api/v1/search", post(search_embeddings))
+        .route("/api/v1/eval", post(evaluate_retrieval));
+
+    let general_routes = Router::new()
+        // Health endpoint
+        .route("/api/v1/health", get(health_check))
+        // Embedding task endpoints
+        .route(
+            "/api/v1/embedding-tasks",
+            post(create_task).layer(DefaultBodyLimit::max(task_body_limit)),
+        )
+        // The files of a bulk request share the body limit of a single task
+        .route(
+            "/api/v1/embedding-tasks/bulk",
+            post(create_tasks_bulk).layer(DefaultBodyLimit::max(task_body_limit)),
+        )
+        .route("/api/v1/embedding-tasks", get(list_tasks))
+        .route("/api/v1/embedding-tasks/export.csv", get(export_tasks_csv))
+        .route("/api/v1/embedding-tasks/{id}", get(get_task))
+        .route("/api/v1/embedding-tasks/{id}", delete(delete_task))
+        .route("/api/v1/embedding-tasks/{id}/retry", post(retry_task))
+        .route("/api/v1/embedding-tasks/{id}/timeline", get(get_task_timeline))
+        .route("/api/v1/embedding-tasks/{id}/metadata", patch(update_task_metadata))
+        // Admin endpoints
+        .route("/api/v1/admin/consistency", get(check_consistency))
+        .route("/api/v1/admin/consistency/repair", post(repair_consistency));
+
+    // Endpoints the file-processor calls for every task, all from one address; a shared
+    // limit would reject its status updates and strand tasks in `processing`
+    let worker_routes = Router::new()
+        .route("/api/v1/embedding-tasks/{id}", put(update_task))
+        .route("/api/v1/embedding-tasks/{id}/content", get(get_task_content));
+
+    let mut routes = with_rate_limit(embedding_routes, embedding_limit)
+        .merge(with_rate_limit(general_routes, general_limit))
+        .merge(with_rate_limit(worker_routes, worker_limit));
+    // Scrapes are exempt from rate limiting
+    if let Some(handle) = metrics {
+        routes = routes.route("/metrics", get(move || std::future::ready(handle.render())));
+    }
+
+    routes
+        .with_state(app_state)
+        .layer(cors)
+        // Info-level request spans so exported traces (and Kafka messages) have a parent
+        .layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new().level(Level::INFO)))
+}
+
+fn with_rate_limit(
+    routes: Router<AppState>,
+    limiter: Option<Arc<RateLimiter>>,
+) -> Router<AppState> {
+    match limiter {
+        Some(limiter) => routes.route_layer(middleware::from_fn_with_state(limiter, rate_limit)),
+        None => routes,
+    }
+}
+
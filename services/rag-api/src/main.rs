@@ -1,77 +1,93 @@
-mod handlers;
-mod models;
-
 use anyhow::Result;
-use axum::{
-    extract::Json,
-    response::IntoResponse,
-    routing::{delete, get, post, put},
-    Router,
+use axum::http::{header, HeaderValue, Method};
+use qdrant_client::qdrant::{read_consistency, ReadConsistencyType};
+use sqlx::migrate::Migrator;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
 };
-use qdrant_client::Qdrant;
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::net::SocketAddr;
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
-use tracing::info;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{info, warn};
 use xlib::{
-    app::{serve::serve_service, tracing::init_tracing},
-    client::{KafkaClient, KafkaClientConfig, PostgresClient, PostgresClientConfig},
+    app::{metrics::init_metrics, serve::serve_service, tracing::init_tracing},
+    client::{
+        openai::DEFAULT_EMBEDDING_MODEL, qdrant, ApiFlavor, EmbeddingCacheConfig,
+        EmbeddingFallbackConfig, KafkaClient, KafkaClientConfig, OpenAIClient,
+        OpenAIClientConfig, PostgresClient, PostgresClientConfig, QdrantClientConfig, QdrantStore,
+        RequestLoggingConfig,
+    },
+    collection::collection_name,
 };
 
-use handlers::file_embedding_task::{
-    create_task, delete_task, get_task, list_tasks, update_task,
+use rag_api::handlers::file_embedding_task::IDEMPOTENCY_KEY;
+use rag_api::handlers::query::{ContextSafetyMode, RetrievalFallback};
+use rag_api::handlers::search::{
+    verify_collection_dimensions, warn_about_model_mismatches, RerankConfig, TopK,
+};
+use rag_api::rate_limit::{
+    RateLimiter, DEFAULT_EMBEDDING_RPM, DEFAULT_GENERAL_RPM, DEFAULT_WORKER_RPM,
 };
-use handlers::search::search_embeddings;
+use rag_api::{router, AppState};
 
-#[derive(Clone)]
-pub struct AppState {
-    pub db_pool: sqlx::Pool<sqlx::Postgres>,
-    pub kafka_client: std::sync::Arc<KafkaClient>,
-    pub qdrant_client: std::sync::Arc<Qdrant>,
-}
+/// Roughly 12k tokens of English text
+const DEFAULT_CONTEXT_MAX_CHARS: usize = 48_000;
 
-#[derive(Deserialize)]
-#[allow(dead_code)]
-struct QueryRequest {
-    query: String,
-    system_prompt: Option<String>,
-    user_prompt: Option<String>,
-    json_mode: Option<bool>,
-}
+/// Query embeddings cached when `EMBEDDING_CACHE_CAPACITY` is unset
+const DEFAULT_EMBEDDING_CACHE_CAPACITY: usize = 1_000;
 
-#[derive(Serialize)]
-struct QueryResponse {
-    status: String,
-    message: String,
-    query: String,
-}
+/// Seconds a cached query embedding is reused when `EMBEDDING_CACHE_TTL_SECS` is unset
+const DEFAULT_EMBEDDING_CACHE_TTL_SECS: u64 = 3_600;
 
-async fn health_check() -> impl IntoResponse {
-    Json(json!({"status": "ok", "service": "rag-api"}))
-}
+/// Decoded size of a task's file content allowed when `MAX_FILE_BYTES` is unset
+const DEFAULT_MAX_FILE_BYTES: usize = 10 * 1024 * 1024;
+
+/// Seconds an `Idempotency-Key` is remembered when `IDEMPOTENCY_KEY_TTL_SECS` is unset
+const DEFAULT_IDEMPOTENCY_KEY_TTL_SECS: u64 = 24 * 3_600;
 
-async fn query_handler(Json(payload): Json<QueryRequest>) -> impl IntoResponse {
-    info!("Received query: {}", payload.query);
+/// Schema migrations, embedded at compile time and applied on every startup
+static MIGRATOR: Migrator = sqlx::migrate!("../../migrations");
 
-    let response = QueryResponse {
-        status: "ok".to_string(),
-        message: "Query processed successfully".to_string(),
-        query: payload.query,
+/// Parse `QDRANT_READ_CONSISTENCY`: `all`, `majority`, `quorum`, or a replica count
+fn parse_read_consistency(value: &str) -> Result<read_consistency::Value> {
+    let consistency = match value.trim().to_lowercase().as_str() {
+        "all" => read_consistency::Value::Type(ReadConsistencyType::All as i32),
+        "majority" => read_consistency::Value::Type(ReadConsistencyType::Majority as i32),
+        "quorum" => read_consistency::Value::Type(ReadConsistencyType::Quorum as i32),
+        other => read_consistency::Value::Factor(other.parse().map_err(|_| {
+            anyhow::anyhow!(
+                "Invalid QDRANT_READ_CONSISTENCY '{}': expected all, majority, quorum, or a number",
+                value
+            )
+        })?),
     };
 
-    Json(response)
+    Ok(consistency)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    dotenv::dotenv().ok();
-    init_tracing();
-
-    info!("Starting RAG API service...");
+/// Parse `OPENAI_API_FLAVOR`: `openai` (default) or `azure`, which reads its deployments
+/// and api-version from `AZURE_OPENAI_*`
+fn api_flavor_from_env() -> Result<ApiFlavor> {
+    let flavor = std::env::var("OPENAI_API_FLAVOR").unwrap_or_default();
+    match flavor.trim().to_lowercase().as_str() {
+        "" | "openai" => Ok(ApiFlavor::OpenAI),
+        "azure" => Ok(ApiFlavor::Azure {
+            deployment: std::env::var("AZURE_OPENAI_DEPLOYMENT").map_err(|_| {
+                anyhow::anyhow!("AZURE_OPENAI_DEPLOYMENT is required when OPENAI_API_FLAVOR=azure")
+            })?,
+            embedding_deployment: std::env::var("AZURE_OPENAI_EMBEDDING_DEPLOYMENT").ok(),
+            api_version: std::env::var("AZURE_OPENAI_API_VERSION")
+                .unwrap_or_else(|_| "2024-06-01".to_string()),
+        }),
+        other => Err(anyhow::anyhow!(
+            "Invalid OPENAI_API_FLAVOR '{}': expected openai or azure",
+            other
+        )),
+    }
+}
 
-    // Initialize database connection
-    let postgres_config = PostgresClientConfig {
+fn postgres_config_from_env() -> Result<PostgresClientConfig> {
+    Ok(PostgresClientConfig {
         hostname: std::env::var("DATABASE_HOSTNAME").unwrap_or_else(|_| "localhost".to_string()),
         port: std::env::var("DATABASE_PORT")
             .ok()
@@ -79,60 +95,300 @@ async fn main() -> Result<()> {
         user: Some(std::env::var("DATABASE_USER").unwrap_or_else(|_| "raguser".to_string())),
         password: Some(std::env::var("DATABASE_PASSWORD").unwrap_or_else(|_| "ragpassword".to_string())),
         db_name: std::env::var("DATABASE_NAME").unwrap_or_else(|_| "rag".to_string()),
-    };
-
-    let postgres_client = PostgresClient::build(&postgres_config).await?;
-    let pool = postgres_client.into_inner();
+        max_connections: std::env::var("DATABASE_MAX_CONNECTIONS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        min_connections: std::env::var("DATABASE_MIN_CONNECTIONS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        acquire_timeout: std::env::var("DATABASE_ACQUIRE_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(std::time::Duration::from_millis),
+        idle_timeout: std::env::var("DATABASE_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(std::time::Duration::from_secs),
+        ssl_mode: std::env::var("DATABASE_SSL_MODE")
+            .ok()
+            .map(|v| v.parse())
+            .transpose()?,
+        ssl_root_cert: std::env::var("DATABASE_SSL_ROOT_CERT").ok().map(Into::into),
+    })
+}
 
-    // Initialize Kafka client
-    let kafka_config = KafkaClientConfig {
+fn kafka_config_from_env() -> KafkaClientConfig {
+    KafkaClientConfig {
         bootstrap_servers: std::env::var("KAFKA_BOOTSTRAP_SERVERS")
             .unwrap_or_else(|_| "localhost:9092".to_string()),
         group_id: Some("rag-api-group".to_string()),
+        linger_ms: std::env::var("KAFKA_LINGER_MS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        batch_size: std::env::var("KAFKA_BATCH_SIZE")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        message_timeout_ms: std::env::var("KAFKA_MESSAGE_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        ..Default::default()
+    }
+}
+
+fn openai_config_from_env() -> Result<OpenAIClientConfig> {
+    Ok(OpenAIClientConfig {
+        api_key: std::env::var("OPENAI_API_KEY").unwrap_or_default(),
+        base_url: std::env::var("OPENAI_BASE_URL").ok(),
+        flavor: api_flavor_from_env()?,
+        embedding_model: std::env::var("EMBEDDING_MODEL").ok(),
+        embedding_dimensions: embedding_dimensions_from_env(),
+        max_embed_input_bytes: std::env::var("MAX_EMBED_INPUT_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        request_logging: std::env::var("OPENAI_LOG_REQUESTS")
+            .is_ok_and(|v| v == "true")
+            .then(|| RequestLoggingConfig {
+                redact_patterns: std::env::var("OPENAI_LOG_REDACT_PATTERN")
+                    .ok()
+                    .into_iter()
+                    .collect(),
+                max_content_chars: std::env::var("OPENAI_LOG_MAX_CHARS")
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(200),
+            }),
+        max_retries: std::env::var("OPENAI_MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.parse().ok()),
+        base_delay: std::env::var("OPENAI_RETRY_BASE_DELAY_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(std::time::Duration::from_millis),
+        embedding_cache: embedding_cache_config_from_env(),
+        // The same provider file-processor falls over to, so query vectors match ingested ones
+        embedding_fallback: EmbeddingFallbackConfig::from_env(),
+    })
+}
+
+/// `EMBEDDING_DIMENSIONS` and `RERANK_EMBEDDING_DIMENSIONS` keyed by the model each sizes
+fn embedding_dimensions_from_env() -> HashMap<String, u64> {
+    let model = std::env::var("EMBEDDING_MODEL")
+        .unwrap_or_else(|_| DEFAULT_EMBEDDING_MODEL.to_string());
+    let rerank_model = std::env::var("RERANK_EMBEDDING_MODEL")
+        .ok()
+        .filter(|model| !model.is_empty());
+    [
+        (Some(model), "EMBEDDING_DIMENSIONS"),
+        (rerank_model, "RERANK_EMBEDDING_DIMENSIONS"),
+    ]
+    .into_iter()
+    .filter_map(|(model, dimensions_var)| {
+        let dimensions = std::env::var(dimensions_var).ok()?.parse().ok()?;
+        Some((model?, dimensions))
+    })
+    .collect()
+}
+
+/// `EMBEDDING_CACHE_CAPACITY` entries (0 disables the cache) kept for
+/// `EMBEDDING_CACHE_TTL_SECS` (0 keeps them until evicted)
+fn embedding_cache_config_from_env() -> Option<EmbeddingCacheConfig> {
+    let capacity = std::env::var("EMBEDDING_CACHE_CAPACITY")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EMBEDDING_CACHE_CAPACITY);
+    let ttl_secs = std::env::var("EMBEDDING_CACHE_TTL_SECS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_EMBEDDING_CACHE_TTL_SECS);
+
+    let capacity = std::num::NonZeroUsize::new(capacity)?;
+    Some(EmbeddingCacheConfig {
+        capacity,
+        ttl: (ttl_secs > 0).then(|| std::time::Duration::from_secs(ttl_secs)),
+    })
+}
+
+/// CORS restricted to `CORS_ALLOWED_ORIGINS`, or any origin when `CORS_PERMISSIVE=true`.
+/// With neither set, no cross-origin request is allowed.
+fn cors_layer_from_env() -> Result<CorsLayer> {
+    if std::env::var("CORS_PERMISSIVE").is_ok_and(|v| v == "true") {
+        warn!("CORS_PERMISSIVE=true: accepting cross-origin requests from any origin");
+        return Ok(CorsLayer::permissive());
+    }
+
+    let origins = std::env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            if origin == "*" {
+                return Err(anyhow::anyhow!(
+                    "CORS_ALLOWED_ORIGINS cannot contain '*'; set CORS_PERMISSIVE=true instead"
+                ));
+            }
+            HeaderValue::from_str(origin)
+                .map_err(|e| anyhow::anyhow!("Invalid CORS origin {:?}: {}", origin, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    info!("CORS allowed origins: {:?}", origins);
+
+    Ok(CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::PATCH, Method::DELETE])
+        .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION, IDEMPOTENCY_KEY])
+        .expose_headers([header::RETRY_AFTER, header::CONTENT_DISPOSITION])
+        .allow_credentials(std::env::var("CORS_ALLOW_CREDENTIALS").is_ok_and(|v| v == "true")))
+}
+
+/// `BIND_ADDR` (e.g. `127.0.0.1:8080`), or `HOST` and `PORT`, defaulting to `0.0.0.0:3000`
+fn bind_addr_from_env() -> Result<SocketAddr> {
+    if let Ok(value) = std::env::var("BIND_ADDR") {
+        return value.parse().map_err(|e| {
+            anyhow::anyhow!("Invalid BIND_ADDR {:?}, expected IP:PORT: {}", value, e)
+        });
+    }
+    let host = match std::env::var("HOST") {
+        Ok(value) => value
+            .parse::<IpAddr>()
+            .map_err(|e| anyhow::anyhow!("Invalid HOST {:?}, expected an IP: {}", value, e))?,
+        Err(_) => IpAddr::from([0, 0, 0, 0]),
+    };
+    let port = match std::env::var("PORT") {
+        Ok(value) => value
+            .parse::<u16>()
+            .map_err(|e| anyhow::anyhow!("Invalid PORT {:?}: {}", value, e))?,
+        Err(_) => 3000,
     };
+    Ok(SocketAddr::new(host, port))
+}
+
+/// Per-client limit from `name` in requests per minute, `default_rpm` when unset
+fn rate_limiter_from_env(name: &str, default_rpm: u32) -> Result<Option<Arc<RateLimiter>>> {
+    let requests_per_minute = match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid {} {:?}: {}", name, value, e))?,
+        Err(_) => default_rpm,
+    };
+    Ok(RateLimiter::per_minute(requests_per_minute))
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    dotenv::dotenv().ok();
+    let _tracing = init_tracing("rag-api");
 
+    info!("Starting RAG API service...");
+
+    // Initialize database connection
+    let postgres_client = PostgresClient::build_with_retry(
+        &postgres_config_from_env()?,
+        5,                                 // max retries
+        std::time::Duration::from_secs(2), // retry delay
+    )
+    .await?;
+    postgres_client.migrate(&MIGRATOR).await?;
+
+    // Initialize Kafka client
     let kafka_client = KafkaClient::new_with_retry(
-        kafka_config, 
+        kafka_config_from_env(),
         5, // max retries
         std::time::Duration::from_secs(2) // retry delay
     ).await?;
     let kafka_client = std::sync::Arc::new(kafka_client);
 
     // Initialize Qdrant client
-    let qdrant_url = std::env::var("QDRANT_URL")
-        .unwrap_or_else(|_| "http://localhost:6333".to_string());
-    
-    info!("Connecting to Qdrant at: {}", qdrant_url);
-    let qdrant_client = std::sync::Arc::new(
-        Qdrant::from_url(&qdrant_url)
-            .build()
-            .map_err(|e| anyhow::anyhow!("Failed to connect to Qdrant: {}", e))?
+    let qdrant_config = QdrantClientConfig::from_env();
+    info!("Connecting to Qdrant at: {}", qdrant_config.url);
+    let qdrant_client = std::sync::Arc::new(qdrant::connect_with_retry(&qdrant_config).await?);
+
+    // Initialize OpenAI client
+    let openai_client = std::sync::Arc::new(OpenAIClient::new(openai_config_from_env()?)?);
+    openai_client.verify_embedding_fallback().await?;
+
+    let rerank = RerankConfig::from_env()?;
+    let collection = collection_name();
+    info!("Using Qdrant collection '{}'", collection);
+    verify_collection_dimensions(&qdrant_client, &collection, &*openai_client, rerank.as_ref())
+        .await?;
+    warn_about_model_mismatches(&qdrant_client, &collection, openai_client.embedding_model())
+        .await;
+
+    let read_consistency = std::env::var("QDRANT_READ_CONSISTENCY")
+        .ok()
+        .map(|value| parse_read_consistency(&value))
+        .transpose()?;
+
+    let retrieval_fallback = std::env::var("RETRIEVAL_FALLBACK")
+        .ok()
+        .map(|value| value.parse::<RetrievalFallback>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let context_safety = std::env::var("CONTEXT_SAFETY_MODE")
+        .ok()
+        .map(|value| value.parse::<ContextSafetyMode>())
+        .transpose()?
+        .unwrap_or_default();
+
+    let query_max_timeout = std::time::Duration::from_millis(
+        std::env::var("QUERY_MAX_TIMEOUT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(120_000),
     );
 
     // Create application state
     let app_state = AppState {
-        db_pool: pool,
+        db_pool: postgres_client,
         kafka_client,
+        vector_store: std::sync::Arc::new(QdrantStore::new(qdrant_client.clone(), &collection)),
         qdrant_client,
+        collection,
+        embeddings: openai_client.clone(),
+        chat: openai_client,
+        read_consistency,
+        retrieval_fallback,
+        context_safety,
+        context_max_chars: std::env::var("CONTEXT_MAX_CHARS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CONTEXT_MAX_CHARS),
+        max_chunks_per_file: std::env::var("MAX_CHUNKS_PER_FILE")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0),
+        query_max_timeout,
+        normalize_embeddings: std::env::var("NORMALIZE_EMBEDDINGS").is_ok_and(|v| v == "true"),
+        top_k: TopK::from_env()?,
+        rerank,
+        inline_task_content: !std::env::var("INLINE_TASK_CONTENT").is_ok_and(|v| v == "false"),
+        max_file_bytes: std::env::var("MAX_FILE_BYTES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_FILE_BYTES),
+        idempotency_key_ttl: std::time::Duration::from_secs(
+            std::env::var("IDEMPOTENCY_KEY_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_IDEMPOTENCY_KEY_TTL_SECS),
+        ),
     };
 
-    let app = Router::new()
-        // Health and query endpoints
-        .route("/api/v1/health", get(health_check))
-        .route("/api/v1/query", post(query_handler))
-        // Embedding task endpoints
-        .route("/api/v1/embedding-tasks", post(create_task))
-        .route("/api/v1/embedding-tasks", get(list_tasks))
-        .route("/api/v1/embedding-tasks/{id}", get(get_task))
-        .route("/api/v1/embedding-tasks/{id}", put(update_task))
-        .route("/api/v1/embedding-tasks/{id}", delete(delete_task))
-        // Search endpoint
-        .route("/api/v1/search", post(search_embeddings))
-        .with_state(app_state)
-        .layer(CorsLayer::permissive())
-        .layer(TraceLayer::new_for_http());
-
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let embedding_limit = rate_limiter_from_env("RATE_LIMIT_EMBEDDING_RPM", DEFAULT_EMBEDDING_RPM)?;
+    let general_limit = rate_limiter_from_env("RATE_LIMIT_GENERAL_RPM", DEFAULT_GENERAL_RPM)?;
+    let worker_limit = rate_limiter_from_env("RATE_LIMIT_WORKER_RPM", DEFAULT_WORKER_RPM)?;
+    let app = router(
+        app_state,
+        embedding_limit,
+        general_limit,
+        worker_limit,
+        cors_layer_from_env()?,
+        init_metrics()?,
+    );
+
+    let addr = bind_addr_from_env()?;
 
     serve_service(app, addr, "RAG API").await?;
 
@@ -1,59 +1,164 @@
 use anyhow::Result;
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, Pool, Postgres};
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgHasArrayType, PgTypeInfo, PgValueRef},
+    types::Json,
+    FromRow, PgExecutor, Pool, Postgres,
+};
+use std::{collections::{HashMap, HashSet}, fmt, str::FromStr, time::Duration};
+use thiserror::Error;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum TaskStatus {
     Pending,
     Processing,
     Completed,
     Failed,
-    Unknown,
 }
 
-impl From<String> for TaskStatus {
-    fn from(s: String) -> Self {
-        match s.as_str() {
-            "pending" => Self::Pending,
-            "processing" => Self::Processing,
-            "completed" => Self::Completed,
-            "failed" => Self::Failed,
-            _ => Self::Unknown,
+impl TaskStatus {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Processing => "processing",
+            Self::Completed => "completed",
+            Self::Failed => "failed",
         }
     }
+
+    /// Statuses a task may move to `self` from: pending → processing → completed/failed,
+    /// failed → pending to retry, and pending → failed when a task can't be started.
+    /// Repeating the current status is allowed so redelivered updates are harmless.
+    const fn allowed_from(self) -> &'static [Self] {
+        match self {
+            Self::Pending => &[Self::Pending, Self::Failed],
+            Self::Processing => &[Self::Pending, Self::Processing],
+            Self::Completed => &[Self::Processing, Self::Completed],
+            Self::Failed => &[Self::Pending, Self::Processing, Self::Failed],
+        }
+    }
+}
+
+impl fmt::Display for TaskStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
-impl From<TaskStatus> for String {
-    fn from(status: TaskStatus) -> Self {
-        match status {
-            TaskStatus::Pending => "pending".to_string(),
-            TaskStatus::Processing => "processing".to_string(),
-            TaskStatus::Completed => "completed".to_string(),
-            TaskStatus::Failed => "failed".to_string(),
-            TaskStatus::Unknown => "unknown".to_string(),
+impl FromStr for TaskStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(Self::Pending),
+            "processing" => Ok(Self::Processing),
+            "completed" => Ok(Self::Completed),
+            "failed" => Ok(Self::Failed),
+            _ => Err(format!("Unrecognized task status '{}'", s)),
         }
     }
 }
 
+// Stored in the VARCHAR `status` column (constrained by chk_file_to_embedding_task_status).
+// Decoding fails on any other value instead of hiding a corrupt row.
+impl sqlx::Type<Postgres> for TaskStatus {
+    fn type_info() -> PgTypeInfo {
+        <&str as sqlx::Type<Postgres>>::type_info()
+    }
+
+    fn compatible(ty: &PgTypeInfo) -> bool {
+        <&str as sqlx::Type<Postgres>>::compatible(ty)
+    }
+}
+
+impl PgHasArrayType for TaskStatus {
+    fn array_type_info() -> PgTypeInfo {
+        <&str as PgHasArrayType>::array_type_info()
+    }
+}
+
+impl sqlx::Encode<'_, Postgres> for TaskStatus {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&str as sqlx::Encode<Postgres>>::encode(self.as_str(), buf)
+    }
+}
+
+impl sqlx::Decode<'_, Postgres> for TaskStatus {
+    fn decode(value: PgValueRef<'_>) -> Result<Self, BoxDynError> {
+        Ok(<&str as sqlx::Decode<Postgres>>::decode(value)?.parse()?)
+    }
+}
+
+/// Rejected status change, recoverable from an `anyhow::Error` via `downcast_ref`
+#[derive(Debug, Error)]
+#[error("Cannot change task status from {from} to {to}")]
+pub struct InvalidStatusTransition {
+    pub from: TaskStatus,
+    pub to: TaskStatus,
+}
+
 #[derive(Debug, FromRow, Serialize)]
 pub struct FileEmbeddingTask {
     pub id: i32,
     pub file_name: String,
-    pub status: String,
+    pub status: TaskStatus,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub started_at: Option<NaiveDateTime>,
     pub completed_at: Option<NaiveDateTime>,
     pub error_message: Option<String>,
     pub embedding_count: Option<i32>,
+    pub tags: Vec<String>,
+    pub metadata: serde_json::Value,
+    pub source: Option<String>,
+    pub error_details: Option<Json<TaskErrorDetails>>,
+    pub correlation_id: Option<Uuid>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct CreateTaskRequest {
     pub file_name: String,
     pub file_content: String,
+    /// Paths (relative to the documents directory) of attachments to embed with the file
+    pub attachments: Option<Vec<String>>,
+    /// System the document came from, e.g. `upload`, `crawler`, or `sync`
+    pub source: Option<String>,
+    /// MIME type of `file_content`, e.g. `application/pdf`; guessed from the file name when unset
+    pub content_type: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct BulkCreateTasksRequest {
+    pub tasks: Vec<CreateTaskRequest>,
+}
+
+/// Tasks created by a bulk request, in request order
+#[derive(Debug, Serialize)]
+pub struct BulkCreateTasksResponse {
+    pub tasks: Vec<TaskResponse>,
+    /// Ids of the created tasks whose `task_created` event could not be published
+    pub unqueued_task_ids: Vec<i32>,
+}
+
+/// Outcome of creating a task under an idempotency key
+#[derive(Debug)]
+pub enum TaskCreation {
+    Created(TaskResponse),
+    /// The key was already used; no task was created and this is the one it created
+    Replayed(TaskResponse),
+}
+
+/// Stored inputs of a task, republished in its `task_created` event
+#[derive(Debug, FromRow)]
+pub struct TaskInputs {
+    pub file_content: Option<String>,
+    pub content_type: Option<String>,
+    pub attachments: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -61,6 +166,154 @@ pub struct UpdateTaskRequest {
     pub status: Option<TaskStatus>,
     pub error_message: Option<String>,
     pub embedding_count: Option<i32>,
+    /// Stage timings to append to the task's timeline
+    pub timeline_events: Option<Vec<TimelineEvent>>,
+    pub error_details: Option<TaskErrorDetails>,
+    /// Extracted text of the document, indexed for keyword search
+    pub full_content: Option<String>,
+}
+
+/// Where and how processing failed; `error_message` stays the human-readable summary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaskErrorDetails {
+    /// Pipeline stage that failed, e.g. `decode`, `embed`, or `upsert`
+    pub stage: String,
+    /// Error code reported by the embedding provider, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u32>,
+    pub message: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMetadataRequest {
+    pub tags: Option<Vec<String>>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    pub stage: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// Chunk the event times, for the per-chunk `embed` and `upsert` events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunk_index: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Filters of the task list; each one left `None` matches every task. Time bounds are
+/// inclusive after and exclusive before.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TaskFilter {
+    pub status: Option<TaskStatus>,
+    pub source: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub updated_after: Option<DateTime<Utc>>,
+    pub updated_before: Option<DateTime<Utc>>,
+}
+
+/// WHERE clause over `TaskFilter`, bound as $1..$6 in field order. Timestamps are stored
+/// as UTC without a time zone, so bounds are bound as naive UTC.
+const TASK_FILTER_WHERE: &str = "
+    WHERE ($1::VARCHAR IS NULL OR status = $1)
+      AND ($2::VARCHAR IS NULL OR source = $2)
+      AND ($3::TIMESTAMP IS NULL OR created_at >= $3)
+      AND ($4::TIMESTAMP IS NULL OR created_at < $4)
+      AND ($5::TIMESTAMP IS NULL OR updated_at >= $5)
+      AND ($6::TIMESTAMP IS NULL OR updated_at < $6)
+";
+
+/// Column the task list is sorted by; only these can reach the ORDER BY clause
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskSortColumn {
+    #[default]
+    CreatedAt,
+    UpdatedAt,
+    Status,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// ORDER BY clause for a sort, with `id` as a tie-breaker so pages don't overlap
+const fn order_by_clause(column: TaskSortColumn, order: SortOrder) -> &'static str {
+    match (column, order) {
+        (TaskSortColumn::CreatedAt, SortOrder::Asc) => "ORDER BY created_at ASC, id ASC",
+        (TaskSortColumn::CreatedAt, SortOrder::Desc) => "ORDER BY created_at DESC, id DESC",
+        (TaskSortColumn::UpdatedAt, SortOrder::Asc) => "ORDER BY updated_at ASC, id ASC",
+        (TaskSortColumn::UpdatedAt, SortOrder::Desc) => "ORDER BY updated_at DESC, id DESC",
+        (TaskSortColumn::Status, SortOrder::Asc) => "ORDER BY status ASC, id ASC",
+        (TaskSortColumn::Status, SortOrder::Desc) => "ORDER BY status DESC, id DESC",
+    }
+}
+
+/// A task row along with the number of rows matching the list filters
+#[derive(Debug, FromRow)]
+struct TaskPageRow {
+    #[sqlx(flatten)]
+    task: FileEmbeddingTask,
+    total_count: i64,
+}
+
+/// One page of tasks, with the total across all pages for rendering pagination
+#[derive(Debug, Serialize)]
+pub struct TaskPage {
+    pub items: Vec<TaskResponse>,
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+#[derive(Debug, FromRow)]
+struct TaskTimelineRow {
+    id: i32,
+    file_name: String,
+    status: TaskStatus,
+    created_at: NaiveDateTime,
+    started_at: Option<NaiveDateTime>,
+    completed_at: Option<NaiveDateTime>,
+    timeline: Json<Vec<TimelineEvent>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskTimelineResponse {
+    pub id: i32,
+    pub file_name: String,
+    pub status: TaskStatus,
+    pub created_at: NaiveDateTime,
+    pub started_at: Option<NaiveDateTime>,
+    pub completed_at: Option<NaiveDateTime>,
+    pub total_duration_ms: Option<i64>,
+    pub events: Vec<TimelineEvent>,
+}
+
+impl From<TaskTimelineRow> for TaskTimelineResponse {
+    fn from(row: TaskTimelineRow) -> Self {
+        let total_duration_ms = row
+            .completed_at
+            .map(|completed_at| (completed_at - row.created_at).num_milliseconds());
+
+        Self {
+            id: row.id,
+            file_name: row.file_name,
+            status: row.status,
+            created_at: row.created_at,
+            started_at: row.started_at,
+            completed_at: row.completed_at,
+            total_duration_ms,
+            events: row.timeline.0,
+        }
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -74,6 +327,33 @@ pub struct TaskResponse {
     pub completed_at: Option<NaiveDateTime>,
     pub error_message: Option<String>,
     pub embedding_count: Option<i32>,
+    pub tags: Vec<String>,
+    pub metadata: serde_json::Value,
+    pub source: Option<String>,
+    pub error_details: Option<TaskErrorDetails>,
+    /// Id attached to the task's Kafka messages and worker logs; null for tasks created
+    /// before it was introduced
+    pub correlation_id: Option<Uuid>,
+}
+
+/// Restrictions applied to a keyword search; `None` fields match every task
+#[derive(Debug, Default, Clone, Copy)]
+pub struct KeywordFilter<'a> {
+    pub source: Option<&'a str>,
+    pub file_name: Option<&'a str>,
+    pub task_id: Option<i32>,
+}
+
+/// A task whose extracted text matched a keyword search
+#[derive(Debug, FromRow)]
+pub struct KeywordHit {
+    pub id: i32,
+    pub file_name: String,
+    pub source: Option<String>,
+    /// `ts_rank_cd` relevance; only comparable between hits of the same query
+    pub rank: f32,
+    /// Excerpt around the matched terms, which are wrapped in `<b>`
+    pub snippet: String,
 }
 
 impl From<FileEmbeddingTask> for TaskResponse {
@@ -81,28 +361,124 @@ impl From<FileEmbeddingTask> for TaskResponse {
         Self {
             id: task.id,
             file_name: task.file_name,
-            status: TaskStatus::from(task.status),
+            status: task.status,
             created_at: task.created_at,
             updated_at: task.updated_at,
             started_at: task.started_at,
             completed_at: task.completed_at,
             error_message: task.error_message,
             embedding_count: task.embedding_count,
+            tags: task.tags,
+            metadata: task.metadata,
+            source: task.source,
+            error_details: task.error_details.map(|details| details.0),
+            correlation_id: task.correlation_id,
         }
     }
 }
 
 impl FileEmbeddingTask {
-    pub async fn create(pool: &Pool<Postgres>, request: CreateTaskRequest) -> Result<TaskResponse> {
+    pub async fn create(
+        pool: &Pool<Postgres>,
+        request: CreateTaskRequest,
+        correlation_id: Uuid,
+    ) -> Result<TaskResponse> {
+        Self::insert(pool, request, correlation_id).await
+    }
+
+    /// Create one task per `(request, correlation_id)` in a single transaction, so either
+    /// all of them are created or none
+    pub async fn create_many(
+        pool: &Pool<Postgres>,
+        requests: Vec<(CreateTaskRequest, Uuid)>,
+    ) -> Result<Vec<TaskResponse>> {
+        let mut tx = pool.begin().await?;
+        let mut tasks = Vec::with_capacity(requests.len());
+        for (request, correlation_id) in requests {
+            tasks.push(Self::insert(&mut *tx, request, correlation_id).await?);
+        }
+        tx.commit().await?;
+        Ok(tasks)
+    }
+
+    /// Create a task unless `idempotency_key` was used within `ttl`, in which case the
+    /// task created under it is returned instead. Keys older than `ttl` are deleted first,
+    /// so an expired key creates a new task.
+    pub async fn create_idempotent(
+        pool: &Pool<Postgres>,
+        request: CreateTaskRequest,
+        correlation_id: Uuid,
+        idempotency_key: &str,
+        ttl: Duration,
+    ) -> Result<TaskCreation> {
+        let mut tx = pool.begin().await?;
+        sqlx::query(
+            "
+            DELETE FROM task_idempotency_key
+            WHERE created_at < NOW() - $1 * INTERVAL '1 second'
+            ",
+        )
+        .bind(ttl.as_secs_f64())
+        .execute(&mut *tx)
+        .await?;
+
+        let task = Self::insert(&mut *tx, request, correlation_id).await?;
+        // A concurrent request with the same key blocks here until the first commits
+        let stored = sqlx::query(
+            "
+            INSERT INTO task_idempotency_key (key, task_id)
+            VALUES ($1, $2)
+            ON CONFLICT (key) DO NOTHING
+            ",
+        )
+        .bind(idempotency_key)
+        .bind(task.id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if stored {
+            tx.commit().await?;
+            return Ok(TaskCreation::Created(task));
+        }
+
+        // Drop the task just inserted and return the one the key already names
+        tx.rollback().await?;
+        let existing = sqlx::query_as::<_, Self>(
+            "
+            SELECT t.id, t.file_name, t.status, t.created_at, t.updated_at, t.started_at, t.completed_at, t.error_message, t.embedding_count, t.tags, t.metadata, t.source, t.error_details, t.correlation_id
+            FROM task_idempotency_key k
+            JOIN file_to_embedding_task t ON t.id = k.task_id
+            WHERE k.key = $1
+            ",
+        )
+        .bind(idempotency_key)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Task of idempotency key was deleted concurrently"))?;
+
+        Ok(TaskCreation::Replayed(TaskResponse::from(existing)))
+    }
+
+    async fn insert(
+        executor: impl PgExecutor<'_>,
+        request: CreateTaskRequest,
+        correlation_id: Uuid,
+    ) -> Result<TaskResponse> {
         let task = sqlx::query_as::<_, Self>(
             "
-            INSERT INTO file_to_embedding_task (file_name)
-            VALUES ($1)
-            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count
+            INSERT INTO file_to_embedding_task (file_name, source, file_content, content_type, attachments, correlation_id)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
             ",
         )
         .bind(request.file_name)
-        .fetch_one(pool)
+        .bind(request.source)
+        .bind(request.file_content)
+        .bind(request.content_type)
+        .bind(request.attachments.unwrap_or_default())
+        .bind(correlation_id)
+        .fetch_one(executor)
         .await?;
 
         Ok(TaskResponse::from(task))
@@ -111,7 +487,7 @@ impl FileEmbeddingTask {
     pub async fn find_by_id(pool: &Pool<Postgres>, id: i32) -> Result<Option<TaskResponse>> {
         let task = sqlx::query_as::<_, Self>(
             "
-            SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count
+            SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
             FROM file_to_embedding_task
             WHERE id = $1
             ",
@@ -125,47 +501,129 @@ impl FileEmbeddingTask {
 
     pub async fn list_all(
         pool: &Pool<Postgres>,
-        status_filter: Option<TaskStatus>,
+        filter: TaskFilter,
+        sort: (TaskSortColumn, SortOrder),
         limit: Option<i64>,
         offset: Option<i64>,
-    ) -> Result<Vec<TaskResponse>> {
+    ) -> Result<TaskPage> {
         let limit = limit.unwrap_or(50);
         let offset = offset.unwrap_or(0);
 
-        let tasks = match status_filter {
-            Some(status) => {
-                let status_str: String = status.into();
-                sqlx::query_as::<_, Self>(
-                    "
-                    SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count
-                    FROM file_to_embedding_task
-                    WHERE status = $1
-                    ORDER BY created_at DESC
-                    LIMIT $2 OFFSET $3
-                    ",
-                )
-                .bind(status_str)
-                .bind(limit)
-                .bind(offset)
-                .fetch_all(pool)
-                .await?
-            }
+        // The window count is taken in the same snapshot as the page, so the two agree.
+        // Every filter is always bound; an absent one is NULL and matches all rows.
+        let rows = sqlx::query_as::<_, TaskPageRow>(&format!(
+            "
+            SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id,
+                   COUNT(*) OVER () AS total_count
+            FROM file_to_embedding_task
+            {TASK_FILTER_WHERE}
+            {}
+            LIMIT $7 OFFSET $8
+            ",
+            order_by_clause(sort.0, sort.1)
+        ))
+        .bind(filter.status)
+        .bind(&filter.source)
+        .bind(filter.created_after.map(|t| t.naive_utc()))
+        .bind(filter.created_before.map(|t| t.naive_utc()))
+        .bind(filter.updated_after.map(|t| t.naive_utc()))
+        .bind(filter.updated_before.map(|t| t.naive_utc()))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        // A page past the end has no rows to carry the count
+        let total = match rows.first() {
+            Some(row) => row.total_count,
+            None if offset == 0 => 0,
             None => {
-                sqlx::query_as::<_, Self>(
-                    "
-                    SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count
-                    FROM file_to_embedding_task
-                    ORDER BY created_at DESC
-                    LIMIT $1 OFFSET $2
-                    ",
-                )
-                .bind(limit)
-                .bind(offset)
-                .fetch_all(pool)
+                sqlx::query_scalar::<_, i64>(&format!(
+                    "SELECT COUNT(*) FROM file_to_embedding_task {TASK_FILTER_WHERE}"
+                ))
+                .bind(filter.status)
+                .bind(filter.source)
+                .bind(filter.created_after.map(|t| t.naive_utc()))
+                .bind(filter.created_before.map(|t| t.naive_utc()))
+                .bind(filter.updated_after.map(|t| t.naive_utc()))
+                .bind(filter.updated_before.map(|t| t.naive_utc()))
+                .fetch_one(pool)
                 .await?
             }
         };
 
+        Ok(TaskPage {
+            items: rows.into_iter().map(|row| TaskResponse::from(row.task)).collect(),
+            total,
+            limit,
+            offset,
+        })
+    }
+
+    /// Completed tasks whose extracted text matches `query` (web search syntax: quoted
+    /// phrases, `or`, `-term`), best match first, with a highlighted excerpt of each
+    pub async fn keyword_search(
+        pool: &Pool<Postgres>,
+        query: &str,
+        filter: KeywordFilter<'_>,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<KeywordHit>> {
+        let hits = sqlx::query_as::<_, KeywordHit>(
+            "
+            SELECT id, file_name, source,
+                   ts_rank_cd(full_content_tsv, query) AS rank,
+                   ts_headline('english', full_content, query, 'MaxWords=35, MinWords=15') AS snippet
+            FROM file_to_embedding_task, websearch_to_tsquery('english', $1) AS query
+            WHERE full_content_tsv @@ query
+              AND status = 'completed'
+              AND ($2::VARCHAR IS NULL OR source = $2)
+              AND ($3::VARCHAR IS NULL OR file_name = $3)
+              AND ($4::INTEGER IS NULL OR id = $4)
+            ORDER BY rank DESC, id ASC
+            LIMIT $5 OFFSET $6
+            ",
+        )
+        .bind(query)
+        .bind(filter.source)
+        .bind(filter.file_name)
+        .bind(filter.task_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(hits)
+    }
+
+    /// Page through tasks in id order, starting after `after_id` (keyset pagination)
+    pub async fn list_after(
+        pool: &Pool<Postgres>,
+        filter: &TaskFilter,
+        after_id: i32,
+        page_size: i64,
+    ) -> Result<Vec<TaskResponse>> {
+        let tasks = sqlx::query_as::<_, Self>(&format!(
+            "
+            SELECT id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
+            FROM file_to_embedding_task
+            {TASK_FILTER_WHERE}
+              AND id > $7
+            ORDER BY id ASC
+            LIMIT $8
+            "
+        ))
+        .bind(filter.status)
+        .bind(&filter.source)
+        .bind(filter.created_after.map(|t| t.naive_utc()))
+        .bind(filter.created_before.map(|t| t.naive_utc()))
+        .bind(filter.updated_after.map(|t| t.naive_utc()))
+        .bind(filter.updated_before.map(|t| t.naive_utc()))
+        .bind(after_id)
+        .bind(page_size)
+        .fetch_all(pool)
+        .await?;
+
         Ok(tasks.into_iter().map(TaskResponse::from).collect())
     }
 
@@ -178,42 +636,214 @@ impl FileEmbeddingTask {
         if request.status.is_none()
             && request.error_message.is_none()
             && request.embedding_count.is_none()
+            && request.timeline_events.is_none()
+            && request.error_details.is_none()
+            && request.full_content.is_none()
         {
             return Self::find_by_id(pool, id).await;
         }
 
-        // For now, we'll do a basic update that handles status changes
-        let status_str: Option<String> = request.status.map(|s| s.into());
+        let allowed_from = request.status.map(TaskStatus::allowed_from);
 
+        // The transition is checked in the UPDATE itself, so two writers racing on the
+        // same row can't both move it. Going back to pending clears the previous attempt.
         let task = sqlx::query_as::<_, Self>(
             "
             UPDATE file_to_embedding_task
             SET status = COALESCE($1, status),
-                error_message = COALESCE($2, error_message),
+                error_message = CASE
+                    WHEN $1 = 'pending' THEN $2
+                    ELSE COALESCE($2, error_message)
+                END,
                 embedding_count = COALESCE($3, embedding_count),
+                timeline = timeline || COALESCE($5, '[]'::jsonb),
+                error_details = CASE
+                    WHEN $1 = 'pending' THEN $6
+                    ELSE COALESCE($6, error_details)
+                END,
                 updated_at = NOW(),
-                started_at = CASE 
-                    WHEN $1 = 'processing' AND started_at IS NULL THEN NOW() 
-                    ELSE started_at 
+                started_at = CASE
+                    WHEN $1 = 'pending' THEN NULL
+                    WHEN $1 = 'processing' AND started_at IS NULL THEN NOW()
+                    ELSE started_at
                 END,
-                completed_at = CASE 
+                completed_at = CASE
+                    WHEN $1 = 'pending' THEN NULL
                     WHEN $1 IN ('completed', 'failed') AND completed_at IS NULL THEN NOW()
-                    ELSE completed_at 
-                END
+                    ELSE completed_at
+                END,
+                full_content = COALESCE($8, full_content)
             WHERE id = $4
-            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count
+              AND ($7::VARCHAR[] IS NULL OR status = ANY($7))
+            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
             ",
         )
-        .bind(status_str)
+        .bind(request.status)
         .bind(request.error_message)
         .bind(request.embedding_count)
         .bind(id)
+        .bind(request.timeline_events.map(Json))
+        .bind(request.error_details.map(Json))
+        .bind(allowed_from)
+        .bind(request.full_content)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(task) = task {
+            return Ok(Some(TaskResponse::from(task)));
+        }
+
+        // No row was updated: either the task is gone or its status blocked the change
+        match (Self::find_by_id(pool, id).await?, request.status) {
+            (Some(current), Some(to)) => Err(InvalidStatusTransition {
+                from: current.status,
+                to,
+            }
+            .into()),
+            _ => Ok(None),
+        }
+    }
+
+    pub async fn update_metadata(
+        pool: &Pool<Postgres>,
+        id: i32,
+        request: UpdateMetadataRequest,
+    ) -> Result<Option<TaskResponse>> {
+        let task = sqlx::query_as::<_, Self>(
+            "
+            UPDATE file_to_embedding_task
+            SET tags = COALESCE($1, tags),
+                metadata = COALESCE($2, metadata),
+                updated_at = NOW()
+            WHERE id = $3
+            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
+            ",
+        )
+        .bind(request.tags)
+        .bind(request.metadata)
+        .bind(id)
         .fetch_optional(pool)
         .await?;
 
         Ok(task.map(TaskResponse::from))
     }
 
+    pub async fn timeline(pool: &Pool<Postgres>, id: i32) -> Result<Option<TaskTimelineResponse>> {
+        let row = sqlx::query_as::<_, TaskTimelineRow>(
+            "
+            SELECT id, file_name, status, created_at, started_at, completed_at, timeline
+            FROM file_to_embedding_task
+            WHERE id = $1
+            ",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(row.map(TaskTimelineResponse::from))
+    }
+
+    /// Base64 file content stored with the task; `None` when the task does not exist or
+    /// predates stored content
+    pub async fn file_content(pool: &Pool<Postgres>, id: i32) -> Result<Option<String>> {
+        let content = sqlx::query_scalar::<_, Option<String>>(
+            "
+            SELECT file_content
+            FROM file_to_embedding_task
+            WHERE id = $1
+            ",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(content.flatten())
+    }
+
+    /// What the file-processor needs to process the task again; `None` when the task
+    /// does not exist
+    pub async fn inputs(pool: &Pool<Postgres>, id: i32) -> Result<Option<TaskInputs>> {
+        let inputs = sqlx::query_as::<_, TaskInputs>(
+            "
+            SELECT file_content, content_type, attachments
+            FROM file_to_embedding_task
+            WHERE id = $1
+            ",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        Ok(inputs)
+    }
+
+    /// Move a failed task back to pending, clearing the previous attempt. Only one of
+    /// several concurrent calls succeeds; the others see `pending` and get
+    /// `InvalidStatusTransition`.
+    pub async fn retry(pool: &Pool<Postgres>, id: i32) -> Result<Option<TaskResponse>> {
+        let task = sqlx::query_as::<_, Self>(
+            "
+            UPDATE file_to_embedding_task
+            SET status = 'pending',
+                error_message = NULL,
+                error_details = NULL,
+                started_at = NULL,
+                completed_at = NULL,
+                updated_at = NOW()
+            WHERE id = $1 AND status = 'failed'
+            RETURNING id, file_name, status, created_at, updated_at, started_at, completed_at, error_message, embedding_count, tags, metadata, source, error_details, correlation_id
+            ",
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await?;
+
+        if let Some(task) = task {
+            return Ok(Some(TaskResponse::from(task)));
+        }
+
+        match Self::find_by_id(pool, id).await? {
+            Some(current) => Err(InvalidStatusTransition {
+                from: current.status,
+                to: TaskStatus::Pending,
+            }
+            .into()),
+            None => Ok(None),
+        }
+    }
+
+    /// The subset of `ids` that still have a task row
+    pub async fn existing_ids(pool: &Pool<Postgres>, ids: &[i32]) -> Result<HashSet<i32>> {
+        let existing = sqlx::query_scalar::<_, i32>(
+            "
+            SELECT id
+            FROM file_to_embedding_task
+            WHERE id = ANY($1)
+            ",
+        )
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(existing.into_iter().collect())
+    }
+
+    /// Extracted text of the tasks in `ids` that have one, keyed by task id
+    pub async fn full_contents(pool: &Pool<Postgres>, ids: &[i32]) -> Result<HashMap<i32, String>> {
+        let rows = sqlx::query_as::<_, (i32, String)>(
+            "
+            SELECT id, full_content
+            FROM file_to_embedding_task
+            WHERE id = ANY($1) AND full_content IS NOT NULL
+            ",
+        )
+        .bind(ids)
+        .fetch_all(pool)
+        .await?;
+
+        Ok(rows.into_iter().collect())
+    }
+
     pub async fn delete(pool: &Pool<Postgres>, id: i32) -> Result<bool> {
         let result = sqlx::query(
             "
@@ -228,3 +858,53 @@ impl FileEmbeddingTask {
         Ok(result.rows_affected() > 0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL: [TaskStatus; 4] = [
+        TaskStatus::Pending,
+        TaskStatus::Processing,
+        TaskStatus::Completed,
+        TaskStatus::Failed,
+    ];
+
+    fn can_move(from: TaskStatus, to: TaskStatus) -> bool {
+        to.allowed_from().contains(&from)
+    }
+
+    #[test]
+    fn tasks_move_forward_through_processing() {
+        use TaskStatus::{Completed, Failed, Pending, Processing};
+
+        let allowed = [
+            (Pending, Processing),
+            (Processing, Completed),
+            (Processing, Failed),
+            (Pending, Failed),
+            (Failed, Pending),
+        ];
+        for from in ALL {
+            for to in ALL {
+                let expected = from == to || allowed.contains(&(from, to));
+                assert_eq!(can_move(from, to), expected, "{} -> {}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn completed_tasks_are_final() {
+        for to in ALL.into_iter().filter(|&to| to != TaskStatus::Completed) {
+            assert!(!can_move(TaskStatus::Completed, to), "completed -> {}", to);
+        }
+    }
+
+    #[test]
+    fn status_round_trips_through_its_name() {
+        for status in ALL {
+            assert_eq!(status.to_string().parse::<TaskStatus>(), Ok(status));
+        }
+        assert!("done".parse::<TaskStatus>().is_err());
+    }
+}
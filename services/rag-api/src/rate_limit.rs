@@ -0,0 +1,165 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::error::ApiError;
+
+/// Requests per minute per client on routes that call `OpenAI`
+pub const DEFAULT_EMBEDDING_RPM: u32 = 60;
+/// Requests per minute per client on health and task CRUD routes
+pub const DEFAULT_GENERAL_RPM: u32 = 600;
+/// Requests per minute per client on the task update and content routes the
+/// file-processor calls; 0 leaves them unlimited
+pub const DEFAULT_WORKER_RPM: u32 = 0;
+
+/// Buckets tracked before idle (fully refilled) ones are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Per-IP token bucket holding up to one minute's worth of requests, refilled continuously
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    /// `None` when `requests_per_minute` is 0, which disables limiting
+    pub fn per_minute(requests_per_minute: u32) -> Option<Arc<Self>> {
+        (requests_per_minute > 0).then(|| {
+            Arc::new(Self {
+                capacity: f64::from(requests_per_minute),
+                refill_per_sec: f64::from(requests_per_minute) / 60.0,
+                buckets: Mutex::new(HashMap::new()),
+            })
+        })
+    }
+
+    /// Take a token for `ip`, or return how long until one is available
+    fn acquire(&self, ip: IpAddr) -> Result<(), Duration> {
+        self.acquire_at(ip, Instant::now())
+    }
+
+    fn acquire_at(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+                elapsed.mul_add(self.refill_per_sec, bucket.tokens) < self.capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: self.capacity,
+            refilled_at: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = elapsed.mul_add(self.refill_per_sec, bucket.tokens).min(self.capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        let missing = 1.0 - bucket.tokens;
+        drop(buckets);
+        Err(Duration::from_secs_f64(missing / self.refill_per_sec))
+    }
+}
+
+/// Middleware rejecting a client with 429 and `Retry-After` once its bucket is empty
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.acquire(addr.ip()) {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            warn!(
+                "Rate limit exceeded for {} on {}, retry after {}s",
+                addr.ip(),
+                request.uri().path(),
+                retry_after
+            );
+            let error = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "Too many requests, retry later",
+            );
+            ([(header::RETRY_AFTER, retry_after.to_string())], error).into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn zero_requests_per_minute_disables_limiting() {
+        assert!(RateLimiter::per_minute(0).is_none());
+    }
+
+    #[test]
+    fn rejects_once_the_burst_is_spent_until_a_token_refills() {
+        let limiter = RateLimiter::per_minute(60).unwrap();
+        let start = Instant::now();
+
+        for _ in 0..60 {
+            assert!(limiter.acquire_at(CLIENT, start).is_ok());
+        }
+        let wait = limiter.acquire_at(CLIENT, start).unwrap_err();
+        assert_eq!(wait, Duration::from_secs(1));
+
+        let half_refilled = start + Duration::from_millis(500);
+        let wait = limiter.acquire_at(CLIENT, half_refilled).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(500));
+
+        assert!(limiter.acquire_at(CLIENT, start + Duration::from_secs(1)).is_ok());
+        assert!(limiter.acquire_at(CLIENT, start + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn refill_never_exceeds_capacity() {
+        let limiter = RateLimiter::per_minute(2).unwrap();
+        let later = Instant::now() + Duration::from_hours(1);
+
+        assert!(limiter.acquire_at(CLIENT, later).is_ok());
+        assert!(limiter.acquire_at(CLIENT, later).is_ok());
+        assert!(limiter.acquire_at(CLIENT, later).is_err());
+    }
+
+    #[test]
+    fn clients_have_separate_buckets() {
+        let limiter = RateLimiter::per_minute(1).unwrap();
+        let now = Instant::now();
+        let other = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(limiter.acquire_at(CLIENT, now).is_ok());
+        assert!(limiter.acquire_at(CLIENT, now).is_err());
+        assert!(limiter.acquire_at(other, now).is_ok());
+    }
+}
@@ -0,0 +1,111 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::ConnectInfo,
+    http::{header, Request, StatusCode},
+};
+use rag_api::{rate_limit::RateLimiter, router};
+use serde_json::{json, Value};
+use std::{net::SocketAddr, sync::Arc};
+use tower::ServiceExt;
+use tower_http::cors::CorsLayer;
+
+use common::{app_state, MockChat, MockEmbeddings, MockVectorStore};
+
+/// POST `body` to `uri` on an app whose database and Kafka are unreachable, so only
+/// requests rejected before creating anything succeed
+async fn post(uri: &str, body: Value) -> (StatusCode, Value) {
+    let app = router(
+        app_state(
+            Arc::new(MockEmbeddings::default()),
+            Arc::new(MockChat::answering("unused")),
+            Arc::new(MockVectorStore::with_documents(&[])),
+        ),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+    let request = Request::post(uri)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+fn task(file_name: &str, file_content: &str) -> Value {
+    json!({"file_name": file_name, "file_content": file_content})
+}
+
+#[tokio::test]
+async fn bulk_create_rejects_an_empty_or_oversized_batch() {
+    let (status, body) = post("/api/v1/embedding-tasks/bulk", json!({"tasks": []})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_task_count");
+
+    let tasks: Vec<Value> = (0..101).map(|i| task(&format!("{}.md", i), "aGk=")).collect();
+    let (status, body) = post("/api/v1/embedding-tasks/bulk", json!({"tasks": tasks})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_task_count");
+    assert_eq!(body["max_tasks"], 100);
+}
+
+#[tokio::test]
+async fn create_rejects_a_source_longer_than_its_column() {
+    let mut body = task("a.md", "aGk=");
+    body["source"] = json!("s".repeat(101));
+
+    let (status, body) = post("/api/v1/embedding-tasks", body).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "source_too_long");
+    assert_eq!(body["max_length"], 100);
+}
+
+#[tokio::test]
+async fn bulk_create_names_the_task_with_invalid_content() {
+    let tasks = json!([task("a.md", "aGk="), task("b.md", "not base64!")]);
+
+    let (status, body) = post("/api/v1/embedding-tasks/bulk", json!({"tasks": tasks})).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "invalid_file_content");
+    assert_eq!(body["index"], 1);
+}
+
+#[tokio::test]
+async fn worker_routes_are_not_held_to_the_general_limit() {
+    let app = router(
+        app_state(
+            Arc::new(MockEmbeddings::default()),
+            Arc::new(MockChat::answering("unused")),
+            Arc::new(MockVectorStore::with_documents(&[])),
+        ),
+        None,
+        RateLimiter::per_minute(1),
+        None,
+        CorsLayer::new(),
+        None,
+    );
+    let status = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let mut request = Request::get(uri).body(Body::empty()).unwrap();
+            let peer: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            app.oneshot(request).await.unwrap().status()
+        }
+    };
+
+    assert_ne!(status("/api/v1/embedding-tasks/1").await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(status("/api/v1/embedding-tasks/1").await, StatusCode::TOO_MANY_REQUESTS);
+    for _ in 0..3 {
+        let content = status("/api/v1/embedding-tasks/1/content").await;
+        assert_ne!(content, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
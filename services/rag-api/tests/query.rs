@@ -0,0 +1,213 @@
+mod common;
+
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, Request, StatusCode},
+};
+use qdrant_client::{qdrant::PointStruct, Payload};
+use rag_api::router;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tower::ServiceExt;
+use tower_http::cors::CorsLayer;
+use xlib::{client::VectorStore, collection::fields};
+
+use common::{app_state, MockChat, MockEmbeddings, MockVectorStore};
+
+async fn post_query(app: axum::Router, body: Value) -> (StatusCode, Value) {
+    let request = Request::post("/api/v1/query")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    let response = app.oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn query_answers_from_retrieved_context() {
+    let embeddings = Arc::new(MockEmbeddings::default());
+    let chat = Arc::new(MockChat::answering("Add an index on the filtered column."));
+    let vector_store = Arc::new(MockVectorStore::with_documents(&[
+        ("indexes.md", "Indexes speed up filtered queries.", 0.9),
+        ("vacuum.md", "VACUUM reclaims dead tuples.", 0.7),
+    ]));
+    let app = router(
+        app_state(embeddings.clone(), chat.clone(), vector_store.clone()),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+
+    let (status, body) = post_query(
+        app,
+        json!({"query": "How do I speed up queries?", "limit": 2}),
+    )
+    .await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["response"], "Add an index on the filtered column.");
+    let files: Vec<&str> = body["retrieved_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["file_name"].as_str().unwrap())
+        .collect();
+    assert_eq!(files, ["indexes.md", "vacuum.md"]);
+
+    // The query was embedded once and searched with the requested limit
+    assert_eq!(*embeddings.inputs.lock().unwrap(), ["How do I speed up queries?"]);
+    assert_eq!(vector_store.searches.lock().unwrap()[0].limit, 2);
+
+    // Both documents reached the prompt, in ranking order, along with the question
+    let prompt = chat.prompts.lock().unwrap().remove(0);
+    let (role, user_prompt) = &prompt[1];
+    assert_eq!(role, "user");
+    let first = user_prompt.find("Indexes speed up filtered queries.").unwrap();
+    let second = user_prompt.find("VACUUM reclaims dead tuples.").unwrap();
+    assert!(first < second);
+    assert!(user_prompt.ends_with("Question: How do I speed up queries?"));
+}
+
+#[tokio::test]
+async fn query_without_generation_skips_the_chat() {
+    let chat = Arc::new(MockChat::answering("unused"));
+    let app = router(
+        app_state(
+            Arc::new(MockEmbeddings::default()),
+            chat.clone(),
+            Arc::new(MockVectorStore::with_documents(&[("a.txt", "alpha", 0.5)])),
+        ),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+
+    let (status, body) = post_query(app, json!({"query": "alpha?", "generate": false})).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.get("response").is_none());
+    assert!(body["context"].as_str().unwrap().contains("alpha"));
+    assert!(chat.prompts.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn query_rejects_more_queries_than_are_fused() {
+    let embeddings = Arc::new(MockEmbeddings::default());
+    let app = router(
+        app_state(
+            embeddings.clone(),
+            Arc::new(MockChat::answering("unused")),
+            Arc::new(MockVectorStore::with_documents(&[("a.txt", "alpha", 0.5)])),
+        ),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+    // With the primary query, one more than the cap; a duplicate doesn't count
+    let mut queries: Vec<String> = (0..10).map(|i| format!("paraphrase {}", i)).collect();
+    queries.push("paraphrase 0".to_string());
+
+    let (status, body) = post_query(app, json!({"query": "alpha?", "queries": queries})).await;
+
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "too_many_queries");
+    assert_eq!(body["max_queries"], 10);
+    assert!(embeddings.inputs.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn query_reads_points_stored_with_the_shared_payload_keys() {
+    // Stored the way file-processor stores a chunk, through the same payload keys
+    let vector_store = Arc::new(MockVectorStore::with_documents(&[]));
+    let mut payload = Payload::new();
+    payload.insert(fields::FILE_NAME, "guide.md");
+    payload.insert(fields::TASK_ID, 42_i64);
+    payload.insert(fields::CHUNK_INDEX, 1_i64);
+    payload.insert(fields::FULL_CONTENT, "Run migrations before deploying.");
+    vector_store
+        .upsert(vec![PointStruct::new(7, vec![1.0, 0.0, 0.0], payload)])
+        .await
+        .unwrap();
+    let app = router(
+        app_state(
+            Arc::new(MockEmbeddings::default()),
+            Arc::new(MockChat::answering("unused")),
+            vector_store,
+        ),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+
+    let (status, body) = post_query(app, json!({"query": "deploying?", "generate": false})).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let file = &body["retrieved_files"][0];
+    assert_eq!(file["file_name"], "guide.md");
+    assert_eq!(file["task_id"], 42);
+    assert_eq!(file["chunk_index"], 1);
+    assert_eq!(file["content"], "Run migrations before deploying.");
+}
+
+#[tokio::test]
+async fn chunks_stored_without_content_each_contribute_their_own_text() {
+    // Three chunks of one file ingested with STORE_FULL_CONTENT=false. The task row can't
+    // be read here, so each chunk falls back to its own snippet.
+    let vector_store = Arc::new(MockVectorStore::with_documents(&[]));
+    let points = (0..3_i64)
+        .map(|chunk_index| {
+            let mut payload = Payload::new();
+            payload.insert(fields::FILE_NAME, "guide.md");
+            payload.insert(fields::TASK_ID, 42_i64);
+            payload.insert(fields::CHUNK_INDEX, chunk_index);
+            payload.insert(fields::CHUNK_COUNT, 3_i64);
+            payload.insert(fields::CONTENT_SNIPPET, format!("Part {} of the guide.", chunk_index));
+            PointStruct::new(
+                u64::try_from(chunk_index).unwrap() + 1,
+                vec![1.0, 0.0, 0.0],
+                payload,
+            )
+        })
+        .collect();
+    vector_store.upsert(points).await.unwrap();
+    let app = router(
+        app_state(
+            Arc::new(MockEmbeddings::default()),
+            Arc::new(MockChat::answering("unused")),
+            vector_store,
+        ),
+        None,
+        None,
+        None,
+        CorsLayer::new(),
+        None,
+    );
+
+    let (status, body) = post_query(app, json!({"query": "guide?", "generate": false})).await;
+
+    assert_eq!(status, StatusCode::OK);
+    let contents: Vec<&str> = body["retrieved_files"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|file| file["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        contents,
+        ["Part 0 of the guide.", "Part 1 of the guide.", "Part 2 of the guide."]
+    );
+    let context = body["context"].as_str().unwrap();
+    for content in contents {
+        assert_eq!(context.matches(content).count(), 1);
+    }
+}
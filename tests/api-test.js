@@ -63,7 +63,8 @@ export default function () {
                 taskId = body.id;
                 return body.file_name === 'test-document.txt' && 
                        body.status === 'pending' && 
-                       body.id > 0;
+                       body.id > 0 &&
+                       typeof body.correlation_id === 'string';
             } catch (e) {
                 console.log('Failed to parse create task response:', r.body);
                 return false;
@@ -86,10 +87,12 @@ export default function () {
 
     const listCheckPassed = check(listResponse, {
         'list tasks status is 200': (r) => r.status === 200,
-        'list tasks returns array': (r) => {
+        'list tasks returns a page of tasks': (r) => {
             try {
                 const body = JSON.parse(r.body);
-                return Array.isArray(body) && body.length > 0;
+                return Array.isArray(body.items) &&
+                       body.items.length > 0 &&
+                       body.total >= body.items.length;
             } catch (e) {
                 console.log('Failed to parse list tasks response:', r.body);
                 return false;
@@ -132,8 +135,18 @@ export default function () {
         console.log('Response body:', getResponse.body);
     }
 
-    // Test 5: Update task status
+    // Test 5: Update task status (a task must be processing before it can complete)
     console.log('5️⃣ Testing update task status...');
+    http.put(
+        `${BASE_URL}/api/v1/embedding-tasks/${taskId}`,
+        JSON.stringify({ status: 'processing' }),
+        {
+            headers: {
+                'Content-Type': 'application/json',
+            },
+        }
+    );
+
     const updatePayload = {
         status: 'completed',
         embedding_count: 42
@@ -182,9 +195,9 @@ export default function () {
         'filter tasks returns completed tasks': (r) => {
             try {
                 const body = JSON.parse(r.body);
-                return Array.isArray(body) && 
-                       body.length > 0 && 
-                       body.every(task => task.status === 'completed');
+                return Array.isArray(body.items) &&
+                       body.items.length > 0 &&
+                       body.items.every(task => task.status === 'completed');
             } catch (e) {
                 console.log('Failed to parse filter tasks response:', r.body);
                 return false;
@@ -225,7 +238,7 @@ export default function () {
         'verify delete returns error': (r) => {
             try {
                 const body = JSON.parse(r.body);
-                return body.error === 'Task not found';
+                return body.error === 'Task not found' && body.code === 'task_not_found';
             } catch (e) {
                 console.log('Failed to parse verify delete response:', r.body);
                 return false;
@@ -184,9 +184,14 @@ export default function () {
                 console.log('AI Search Results:', JSON.stringify(body, null, 2));
 
                 return body.query === 'artificial intelligence machine learning' &&
+                    body.offset === 0 &&
+                    body.limit === 3 &&
                     Array.isArray(body.results) &&
                     body.results.length > 0 &&
                     body.results[0].task_id === firstTaskId &&
+                    body.results[0].retrieved_by === 'vector' &&
+                    body.results[0].highlight ===
+                        'This is a document about artificial intelligence and machine learning.' &&
                     body.results[0].score > 0.5; // Should have high similarity
             } catch (e) {
                 console.log('Failed to parse AI search response:', r.body);
@@ -21,10 +21,10 @@ pub async fn shutdown_signal() {
     let terminate = std::future::pending::<()>();
 
     tokio::select! {
-        _ = ctrl_c => {
+        () = ctrl_c => {
             info!("Received Ctrl+C signal, shutting down gracefully");
         },
-        _ = terminate => {
+        () = terminate => {
             info!("Received terminate signal, shutting down gracefully");
         },
     }
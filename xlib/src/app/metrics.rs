@@ -0,0 +1,43 @@
+use anyhow::{Context, Result};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder};
+use std::time::Duration;
+use tracing::info;
+
+pub use metrics_exporter_prometheus::PrometheusHandle;
+
+/// Histogram buckets, in seconds, for request and processing latencies
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0,
+];
+
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Install the Prometheus recorder when `METRICS_ENABLED=true`
+///
+/// The returned handle renders the text served at `/metrics`. While disabled, the
+/// `metrics` macros used across the services record nothing.
+pub fn init_metrics() -> Result<Option<PrometheusHandle>> {
+    if !std::env::var("METRICS_ENABLED").is_ok_and(|v| v == "true") {
+        return Ok(None);
+    }
+
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_seconds".to_string()), LATENCY_BUCKETS)
+        .context("Invalid metrics histogram buckets")?
+        .install_recorder()
+        .context("Failed to install the Prometheus metrics recorder")?;
+
+    // Histogram samples are buffered until upkeep folds them in; without it they
+    // accumulate between scrapes
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    info!("Prometheus metrics enabled at /metrics");
+    Ok(Some(handle))
+}
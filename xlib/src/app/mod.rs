@@ -1,3 +1,4 @@
 pub mod graceful_shutdown;
+pub mod metrics;
 pub mod serve;
 pub mod tracing;
@@ -6,7 +6,8 @@ use tracing::info;
 
 use super::graceful_shutdown::shutdown_signal;
 
-/// Serve an Axum router with graceful shutdown
+/// Serve an Axum router with graceful shutdown. Handlers can extract the peer address
+/// with `ConnectInfo<SocketAddr>`.
 pub async fn serve_service(
     app: Router,
     addr: SocketAddr,
@@ -16,7 +17,7 @@ pub async fn serve_service(
     
     let listener = TcpListener::bind(addr).await?;
     
-    axum::serve(listener, app)
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal())
         .await?;
     
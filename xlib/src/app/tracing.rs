@@ -1,17 +1,92 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    trace::TracerProvider as _,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
-/// Initialize tracing with environment-based filtering
-pub fn init_tracing() {
-    tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
+/// Keeps the OpenTelemetry exporter running; dropping it flushes spans not yet exported.
+/// Hold it until the end of `main`.
+#[must_use = "dropping the guard stops trace export"]
+pub struct TracingGuard {
+    provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to flush OpenTelemetry spans: {}", e);
+            }
+        }
+    }
+}
+
+/// Initialize tracing with environment-based filtering. Spans are also exported to an
+/// OTLP collector when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+pub fn init_tracing(service_name: &str) -> TracingGuard {
+    init_with_filter(
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        service_name,
+    )
 }
 
 /// Initialize tracing with a specific log level
-pub fn init_tracing_with_level(level: &str) {
+pub fn init_tracing_with_level(level: &str, service_name: &str) -> TracingGuard {
+    init_with_filter(EnvFilter::new(level), service_name)
+}
+
+/// Human-readable output, or one JSON object per line when `LOG_FORMAT=json`
+fn init_with_filter(filter: EnvFilter, service_name: &str) -> TracingGuard {
+    let json = std::env::var("LOG_FORMAT").is_ok_and(|v| v.eq_ignore_ascii_case("json"));
+    let provider = otlp_tracer_provider(service_name);
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name.to_string()))
+    });
+
     tracing_subscriber::registry()
-        .with(EnvFilter::new(level))
-        .with(tracing_subscriber::fmt::layer())
+        .with(filter)
+        .with(json.then(|| fmt::layer().json().with_current_span(true)))
+        .with((!json).then(fmt::layer))
+        .with(otel_layer)
         .init();
+
+    TracingGuard { provider }
+}
+
+/// OTLP/HTTP exporter configured by the standard `OTEL_EXPORTER_OTLP_*` variables.
+/// Failing to build it only disables export; the service still starts.
+fn otlp_tracer_provider(service_name: &str) -> Option<SdkTracerProvider> {
+    std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok()?;
+
+    let exporter = match opentelemetry_otlp::SpanExporter::builder().with_http().build() {
+        Ok(exporter) => exporter,
+        Err(e) => {
+            eprintln!("OpenTelemetry export disabled, failed to build the OTLP exporter: {}", e);
+            return None;
+        }
+    };
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name(service_name.to_string()).build())
+        .build();
+
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    Some(provider)
+}
+
+/// Add the current span's trace context (`traceparent`) to outgoing message headers.
+/// Adds nothing unless OpenTelemetry export is enabled.
+pub fn inject_trace_context(headers: &mut dyn Injector) {
+    let context = tracing::Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, headers));
+}
+
+/// Continue the trace carried in incoming message headers in `span`
+pub fn set_parent_from_headers(span: &tracing::Span, headers: &dyn Extractor) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(headers));
+    let _ = span.set_parent(parent);
 }
@@ -0,0 +1,137 @@
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddingCacheConfig {
+    /// Embeddings kept; the least recently used one is evicted beyond it
+    pub capacity: NonZeroUsize,
+    /// Age after which an entry is embedded again; `None` keeps entries until evicted
+    pub ttl: Option<Duration>,
+}
+
+struct CachedEmbedding {
+    embedding: Vec<f32>,
+    stored_at: Instant,
+}
+
+/// In-memory LRU of embeddings keyed by model and input text, shared by every clone of the
+/// client. Hits and misses are counted in `embedding_cache_requests_total`.
+pub struct EmbeddingCache {
+    entries: Mutex<LruCache<(String, String), CachedEmbedding>>,
+    ttl: Option<Duration>,
+}
+
+impl EmbeddingCache {
+    pub fn new(config: EmbeddingCacheConfig) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(config.capacity)),
+            ttl: config.ttl,
+        }
+    }
+
+    /// The stored embedding of `text` under `model`, unless missing or expired
+    pub fn get(&self, model: &str, text: &str) -> Option<Vec<f32>> {
+        self.get_at(model, text, Instant::now())
+    }
+
+    fn get_at(&self, model: &str, text: &str, now: Instant) -> Option<Vec<f32>> {
+        let key = (model.to_string(), text.to_string());
+        let embedding = {
+            let mut entries = self
+                .entries
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            match entries.get(&key) {
+                Some(cached)
+                    if self.ttl.is_none_or(|ttl| now.duration_since(cached.stored_at) < ttl) =>
+                {
+                    Some(cached.embedding.clone())
+                }
+                Some(_) => {
+                    entries.pop(&key);
+                    None
+                }
+                None => None,
+            }
+        };
+
+        let outcome = if embedding.is_some() { "hit" } else { "miss" };
+        metrics::counter!("embedding_cache_requests_total", "outcome" => outcome).increment(1);
+        embedding
+    }
+
+    pub fn insert(&self, model: &str, text: &str, embedding: &[f32]) {
+        self.entries
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .put(
+                (model.to_string(), text.to_string()),
+                CachedEmbedding {
+                    embedding: embedding.to_vec(),
+                    stored_at: Instant::now(),
+                },
+            );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "text-embedding-3-small";
+
+    fn cache(capacity: usize, ttl: Option<Duration>) -> EmbeddingCache {
+        EmbeddingCache::new(EmbeddingCacheConfig {
+            capacity: NonZeroUsize::new(capacity).unwrap(),
+            ttl,
+        })
+    }
+
+    #[test]
+    fn entries_are_keyed_by_model_and_text() {
+        let cache = cache(4, None);
+        cache.insert(MODEL, "hello", &[1.0, 2.0]);
+
+        assert_eq!(cache.get(MODEL, "hello"), Some(vec![1.0, 2.0]));
+        assert_eq!(cache.get("text-embedding-3-large", "hello"), None);
+        assert_eq!(cache.get(MODEL, "hello!"), None);
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted_at_capacity() {
+        let cache = cache(2, None);
+        cache.insert(MODEL, "a", &[1.0]);
+        cache.insert(MODEL, "b", &[2.0]);
+        assert!(cache.get(MODEL, "a").is_some());
+
+        cache.insert(MODEL, "c", &[3.0]);
+
+        assert_eq!(cache.get(MODEL, "b"), None);
+        assert_eq!(cache.get(MODEL, "a"), Some(vec![1.0]));
+        assert_eq!(cache.get(MODEL, "c"), Some(vec![3.0]));
+    }
+
+    #[test]
+    fn entries_expire_once_older_than_the_ttl() {
+        let cache = cache(4, Some(Duration::from_mins(1)));
+        let stored_at = Instant::now();
+        cache.insert(MODEL, "hello", &[1.0]);
+
+        assert!(cache.get_at(MODEL, "hello", stored_at + Duration::from_secs(30)).is_some());
+        assert!(cache.get_at(MODEL, "hello", stored_at + Duration::from_mins(2)).is_none());
+        // The expired entry was dropped, so it misses even at an earlier time
+        assert!(cache.get_at(MODEL, "hello", stored_at).is_none());
+    }
+
+    #[test]
+    fn without_a_ttl_entries_never_expire() {
+        let cache = cache(4, None);
+        cache.insert(MODEL, "hello", &[1.0]);
+
+        assert!(cache.get_at(MODEL, "hello", Instant::now() + Duration::from_hours(24)).is_some());
+    }
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result};
+use reqwest::{header, Client};
+use serde::Serialize;
+
+use super::openai::{
+    check_response, supports_dimensions, BatchEmbeddingRequest, EmbeddingRequest,
+    EmbeddingResponse,
+};
+
+pub struct EmbeddingFallbackConfig {
+    /// Logged for the requests it serves
+    pub name: String,
+    /// Full URL of an OpenAI-compatible embeddings endpoint
+    pub url: String,
+    /// Sent as a bearer token when not empty
+    pub api_key: String,
+    /// Model requested from it; defaults to the primary embedding model, which it must serve
+    pub model: Option<String>,
+}
+
+impl EmbeddingFallbackConfig {
+    /// `EMBEDDING_FALLBACK_URL`, `EMBEDDING_FALLBACK_NAME`, `EMBEDDING_FALLBACK_API_KEY` and
+    /// `EMBEDDING_FALLBACK_MODEL`; `None` when no URL is set
+    pub fn from_env() -> Option<Self> {
+        Some(Self {
+            url: std::env::var("EMBEDDING_FALLBACK_URL").ok()?,
+            name: std::env::var("EMBEDDING_FALLBACK_NAME")
+                .unwrap_or_else(|_| "fallback".to_string()),
+            api_key: std::env::var("EMBEDDING_FALLBACK_API_KEY").unwrap_or_default(),
+            model: std::env::var("EMBEDDING_FALLBACK_MODEL").ok(),
+        })
+    }
+}
+
+/// Secondary embeddings endpoint used when the primary fails. It has its own HTTP client,
+/// so the primary's credentials are never sent to it.
+pub struct EmbeddingFallback {
+    pub name: String,
+    url: String,
+    model: String,
+    /// Shortened vector length, matching what is requested from the primary
+    dimensions: Option<u64>,
+    client: Client,
+}
+
+impl EmbeddingFallback {
+    pub fn new(
+        config: EmbeddingFallbackConfig,
+        primary_model: &str,
+        dimensions: Option<u64>,
+    ) -> Result<Self> {
+        let mut headers = header::HeaderMap::new();
+        if !config.api_key.is_empty() {
+            headers.insert(
+                header::AUTHORIZATION,
+                header::HeaderValue::from_str(&format!("Bearer {}", config.api_key))
+                    .context("Invalid fallback API key format")?,
+            );
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .context("Failed to build fallback HTTP client")?;
+
+        let model = config.model.unwrap_or_else(|| primary_model.to_string());
+        Ok(Self {
+            name: config.name,
+            url: config.url,
+            dimensions: dimensions.filter(|_| supports_dimensions(&model)),
+            model,
+            client,
+        })
+    }
+
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let request = EmbeddingRequest {
+            input: text.to_string(),
+            model: self.model.clone(),
+            dimensions: self.dimensions,
+        };
+        self.post(&request)
+            .await?
+            .data
+            .into_iter()
+            .next()
+            .map(|data| data.embedding)
+            .with_context(|| format!("No embedding data received from {}", self.name))
+    }
+
+    /// Embed all `texts` in one request, returning the vectors in input order
+    pub async fn embed_batch(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let request = BatchEmbeddingRequest {
+            input: texts.to_vec(),
+            model: self.model.clone(),
+            dimensions: self.dimensions,
+        };
+        let mut response = self.post(&request).await?;
+        response.data.sort_by_key(|data| data.index);
+        if response.data.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} embeddings from {}, received {}",
+                texts.len(),
+                self.name,
+                response.data.len()
+            ));
+        }
+        Ok(response.data.into_iter().map(|data| data.embedding).collect())
+    }
+
+    async fn post<T: Serialize + Sync>(&self, body: &T) -> Result<EmbeddingResponse> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(body)
+            .send()
+            .await
+            .with_context(|| format!("Failed to send embedding request to {}", self.name))?;
+
+        check_response(response)
+            .await?
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse embedding response from {}", self.name))
+    }
+}
@@ -1,24 +1,95 @@
 use anyhow::{Context, Result};
 use rdkafka::{
     config::ClientConfig,
-    consumer::{Consumer, StreamConsumer},
-    producer::{FutureProducer, FutureRecord},
-    Message,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    producer::{FutureProducer, FutureRecord, Producer},
+    message::{Header, Headers, OwnedHeaders},
+    Message, Offset, TopicPartitionList,
 };
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
 use tokio::time::sleep;
 use tracing::{error, info, warn};
 
+use crate::app::tracing::inject_trace_context;
+
+/// Appended to a topic's name to get its dead-letter topic
+const DLQ_SUFFIX: &str = "-dlq";
+
 pub struct KafkaClient {
     producer: FutureProducer,
     consumer: StreamConsumer,
+    message_timeout: Duration,
+    pending_commits: Mutex<PendingCommits>,
+    commit_batch_size: usize,
+    commit_interval: Option<Duration>,
+}
+
+/// Offsets of messages passed to `commit_message` that are not committed yet
+struct PendingCommits {
+    /// Next offset to consume, per topic and partition
+    offsets: HashMap<(String, i32), i64>,
+    messages: usize,
+    last_commit: Instant,
 }
 
-#[derive(Clone)]
+impl PendingCommits {
+    /// Hand over the pending offsets to be committed, starting a new batch
+    fn take(&mut self) -> HashMap<(String, i32), i64> {
+        self.messages = 0;
+        self.last_commit = Instant::now();
+        std::mem::take(&mut self.offsets)
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct KafkaClientConfig {
     pub bootstrap_servers: String,
     pub group_id: Option<String>,
+    /// Producer `linger.ms`: how long to wait for more records before sending a batch
+    pub linger_ms: Option<u32>,
+    /// Producer `batch.size` in bytes
+    pub batch_size: Option<u32>,
+    /// Producer `message.timeout.ms` (default 15000): how long a record may wait for
+    /// delivery, retries included, before it is reported as failed
+    pub message_timeout_ms: Option<u32>,
+    /// Consumer `partition.assignment.strategy`, e.g. `cooperative-sticky`
+    pub partition_assignment_strategy: Option<String>,
+    /// Consumer `group.instance.id` for static membership; must be unique per replica
+    pub group_instance_id: Option<String>,
+    /// Disable `enable.auto.commit`; offsets then advance only through `commit_message`
+    pub manual_commit: bool,
+    /// Messages passed to `commit_message` before their offsets are committed together
+    /// (default 1, committing each one). Up to this many processed messages are redelivered
+    /// after a crash.
+    pub commit_batch_size: Option<usize>,
+    /// Commit a partial batch once this long has passed since the last commit, bounding how
+    /// long processed messages stay uncommitted
+    pub commit_interval: Option<Duration>,
+    /// Consumer `auto.offset.reset` when the group has no committed offset:
+    /// `latest` (default) or `earliest`
+    pub auto_offset_reset: Option<String>,
+    /// Consumer `session.timeout.ms` (default 10000): how long the broker waits for a
+    /// heartbeat before evicting the consumer from the group
+    pub session_timeout_ms: Option<u32>,
+    /// Consumer `heartbeat.interval.ms` (default 3000); must be below the session timeout,
+    /// typically at most a third of it
+    pub heartbeat_interval_ms: Option<u32>,
+    /// Consumer `max.poll.interval.ms` (librdkafka default 300000): the longest gap between
+    /// receives before the consumer leaves the group; must be at least the session timeout
+    pub max_poll_interval_ms: Option<u32>,
+}
+
+/// Where a consumed message sits in its topic
+#[derive(Debug, Clone)]
+pub struct MessagePosition {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -26,19 +97,52 @@ pub struct KafkaMessage {
     pub event_type: String,
     pub payload: serde_json::Value,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Set on consumed messages, used to commit them
+    #[serde(skip)]
+    pub position: Option<MessagePosition>,
+    /// Record key of a consumed message
+    #[serde(skip)]
+    pub key: Option<String>,
+    /// Record headers of a consumed message, e.g. trace IDs set by the producer
+    #[serde(skip)]
+    pub headers: HashMap<String, String>,
+}
+
+impl KafkaMessage {
+    fn new(event_type: String, payload: serde_json::Value) -> Self {
+        Self {
+            event_type,
+            payload,
+            timestamp: chrono::Utc::now(),
+            position: None,
+            key: None,
+            headers: HashMap::new(),
+        }
+    }
 }
 
 impl KafkaClient {
     pub fn new(config: KafkaClientConfig) -> Result<Self> {
         // Producer configuration with better settings
-        let producer: FutureProducer = ClientConfig::new()
+        let message_timeout_ms = config.message_timeout_ms.unwrap_or(15000);
+        let mut producer_config = ClientConfig::new();
+        producer_config
             .set("bootstrap.servers", &config.bootstrap_servers)
-            .set("message.timeout.ms", "10000")
+            .set("message.timeout.ms", message_timeout_ms.to_string())
             .set("request.timeout.ms", "5000")
-            .set("delivery.timeout.ms", "15000")
             .set("retry.backoff.ms", "100")
             .set("reconnect.backoff.ms", "100")
-            .set("reconnect.backoff.max.ms", "1000")
+            .set("reconnect.backoff.max.ms", "1000");
+
+        if let Some(linger_ms) = config.linger_ms {
+            producer_config.set("linger.ms", linger_ms.to_string());
+        }
+
+        if let Some(batch_size) = config.batch_size {
+            producer_config.set("batch.size", batch_size.to_string());
+        }
+
+        let producer: FutureProducer = producer_config
             .create()
             .context("Failed to create Kafka producer")?;
 
@@ -46,10 +150,35 @@ impl KafkaClient {
         let mut consumer_config = ClientConfig::new();
         consumer_config.set("bootstrap.servers", &config.bootstrap_servers);
         consumer_config.set("enable.partition.eof", "false");
-        consumer_config.set("session.timeout.ms", "10000");
-        consumer_config.set("heartbeat.interval.ms", "3000");
-        consumer_config.set("enable.auto.commit", "true");
-        consumer_config.set("auto.offset.reset", "latest");
+        let session_timeout_ms = config.session_timeout_ms.unwrap_or(10000);
+        let heartbeat_interval_ms = config.heartbeat_interval_ms.unwrap_or(3000);
+        if heartbeat_interval_ms >= session_timeout_ms {
+            return Err(anyhow::anyhow!(
+                "Kafka heartbeat interval ({} ms) must be below the session timeout ({} ms)",
+                heartbeat_interval_ms,
+                session_timeout_ms
+            ));
+        }
+        consumer_config.set("session.timeout.ms", session_timeout_ms.to_string());
+        consumer_config.set("heartbeat.interval.ms", heartbeat_interval_ms.to_string());
+        if let Some(max_poll_interval_ms) = config.max_poll_interval_ms {
+            if max_poll_interval_ms < session_timeout_ms {
+                return Err(anyhow::anyhow!(
+                    "Kafka max poll interval ({} ms) must be at least the session timeout ({} ms)",
+                    max_poll_interval_ms,
+                    session_timeout_ms
+                ));
+            }
+            consumer_config.set("max.poll.interval.ms", max_poll_interval_ms.to_string());
+        }
+        consumer_config.set(
+            "enable.auto.commit",
+            if config.manual_commit { "false" } else { "true" },
+        );
+        consumer_config.set(
+            "auto.offset.reset",
+            config.auto_offset_reset.as_deref().unwrap_or("latest"),
+        );
         consumer_config.set("reconnect.backoff.ms", "100");
         consumer_config.set("reconnect.backoff.max.ms", "1000");
         
@@ -59,11 +188,30 @@ impl KafkaClient {
             consumer_config.set("group.id", "rag-consumer-group");
         }
 
+        if let Some(strategy) = config.partition_assignment_strategy {
+            consumer_config.set("partition.assignment.strategy", strategy);
+        }
+
+        if let Some(group_instance_id) = config.group_instance_id {
+            consumer_config.set("group.instance.id", group_instance_id);
+        }
+
         let consumer: StreamConsumer = consumer_config
             .create()
             .context("Failed to create Kafka consumer")?;
 
-        Ok(Self { producer, consumer })
+        Ok(Self {
+            producer,
+            consumer,
+            message_timeout: Duration::from_millis(u64::from(message_timeout_ms)),
+            pending_commits: Mutex::new(PendingCommits {
+                offsets: HashMap::new(),
+                messages: 0,
+                last_commit: Instant::now(),
+            }),
+            commit_batch_size: config.commit_batch_size.unwrap_or(1).max(1),
+            commit_interval: config.commit_interval,
+        })
     }
 
     pub async fn new_with_retry(config: KafkaClientConfig, max_retries: u32, retry_delay: Duration) -> Result<Self> {
@@ -89,24 +237,75 @@ impl KafkaClient {
         Err(last_error.unwrap().context(format!("Failed to connect to Kafka after {} attempts", max_retries)))
     }
 
+    /// Confirm a broker is reachable by fetching cluster metadata within `timeout`
+    pub async fn ping(&self, timeout: Duration) -> Result<()> {
+        // fetch_metadata blocks, so keep it off the async runtime
+        let producer = self.producer.clone();
+        let metadata =
+            tokio::task::spawn_blocking(move || producer.client().fetch_metadata(None, timeout))
+                .await
+                .context("Kafka ping task failed")?
+                .context("Failed to fetch Kafka metadata")?;
+
+        if metadata.brokers().is_empty() {
+            return Err(anyhow::anyhow!("Kafka metadata lists no brokers"));
+        }
+        Ok(())
+    }
+
     pub async fn produce_event(
         &self,
         topic: &str,
         event_type: &str,
         payload: serde_json::Value,
     ) -> Result<()> {
-        let message = KafkaMessage {
-            event_type: event_type.to_string(),
-            payload,
-            timestamp: chrono::Utc::now(),
-        };
+        self.produce_event_with_key(topic, event_type, event_type, payload).await
+    }
+
+    /// Like `produce_event`, using `key` (e.g. a file name) as the record key so related
+    /// events keep their order on one partition while unrelated ones spread out
+    pub async fn produce_event_with_key(
+        &self,
+        topic: &str,
+        key: &str,
+        event_type: &str,
+        payload: serde_json::Value,
+    ) -> Result<()> {
+        self.send_event(topic, key, event_type, payload, &HashMap::new()).await
+    }
+
+    /// Like `produce_event`, attaching `headers` to the record
+    pub async fn produce_event_with_headers(
+        &self,
+        topic: &str,
+        event_type: &str,
+        payload: serde_json::Value,
+        headers: &HashMap<String, String>,
+    ) -> Result<()> {
+        self.send_event(topic, event_type, event_type, payload, headers).await
+    }
+
+    async fn send_event(
+        &self,
+        topic: &str,
+        key: &str,
+        event_type: &str,
+        payload: serde_json::Value,
+        headers: &HashMap<String, String>,
+    ) -> Result<()> {
+        let message = KafkaMessage::new(event_type.to_string(), payload);
 
         let payload_str = serde_json::to_string(&message)
             .context("Failed to serialize message")?;
 
+        // Carry the trace across the topic so consumers can continue it
+        let mut headers = headers.clone();
+        inject_trace_context(&mut headers);
+        let record_headers = record_headers(&headers);
         let record = FutureRecord::to(topic)
-            .key(&message.event_type)
-            .payload(&payload_str);
+            .key(key)
+            .payload(&payload_str)
+            .headers(record_headers);
 
         match self.producer.send(record, Duration::from_secs(0)).await {
             Ok(delivery) => {
@@ -120,6 +319,78 @@ impl KafkaClient {
         }
     }
 
+    /// Enqueue `(key, payload)` events of one type, flush the producer once, then report
+    /// delivery per event in the same order as `events`. Keys work as in
+    /// `produce_event_with_key`.
+    pub async fn produce_events(
+        &self,
+        topic: &str,
+        event_type: &str,
+        events: Vec<(String, serde_json::Value)>,
+    ) -> Vec<Result<()>> {
+        let mut deliveries = Vec::with_capacity(events.len());
+        let mut trace_headers = HashMap::new();
+        inject_trace_context(&mut trace_headers);
+
+        for (key, payload) in events {
+            let message = KafkaMessage::new(event_type.to_string(), payload);
+
+            let payload_str = match serde_json::to_string(&message) {
+                Ok(payload_str) => payload_str,
+                Err(e) => {
+                    deliveries.push(Err(anyhow::anyhow!("Failed to serialize message: {}", e)));
+                    continue;
+                }
+            };
+
+            let record = FutureRecord::to(topic)
+                .key(&key)
+                .payload(&payload_str)
+                .headers(record_headers(&trace_headers));
+
+            deliveries.push(
+                self.producer
+                    .send_result(record)
+                    .map_err(|(e, _)| anyhow::anyhow!("Failed to enqueue message: {}", e)),
+            );
+        }
+
+        // flush blocks until the queue drains, so keep it off the async runtime. Records
+        // still queued after message_timeout have failed by then.
+        let producer = self.producer.clone();
+        let timeout = self.message_timeout;
+        match tokio::task::spawn_blocking(move || producer.flush(timeout)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("Kafka producer flush did not complete: {}", e),
+            Err(e) => warn!("Kafka producer flush task failed: {}", e),
+        }
+
+        let mut results = Vec::with_capacity(deliveries.len());
+        for delivery in deliveries {
+            let result = match delivery {
+                Ok(future) => match future.await {
+                    Ok(Ok(_)) => Ok(()),
+                    Ok(Err((e, _))) => Err(anyhow::anyhow!("Failed to deliver message: {}", e)),
+                    Err(_) => Err(anyhow::anyhow!("Message delivery was cancelled")),
+                },
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = &result {
+                error!("{}", e);
+            }
+            results.push(result);
+        }
+
+        info!(
+            "Delivered {} of {} batched messages to topic '{}'",
+            results.iter().filter(|r| r.is_ok()).count(),
+            results.len(),
+            topic
+        );
+        results
+    }
+
     pub async fn subscribe_to_topics(&self, topics: &[&str]) -> Result<()> {
         self.consumer
             .subscribe(topics)
@@ -131,34 +402,254 @@ impl KafkaClient {
 
     pub async fn consume_message(&self) -> Result<Option<KafkaMessage>> {
         match self.consumer.recv().await {
-            Ok(message) => {
-                if let Some(payload) = message.payload_view::<str>() {
-                    match payload {
-                        Ok(payload_str) => {
-                            match serde_json::from_str::<KafkaMessage>(payload_str) {
-                                Ok(kafka_message) => {
-                                    info!("Received message: {:?}", kafka_message);
-                                    Ok(Some(kafka_message))
-                                }
-                                Err(e) => {
-                                    error!("Failed to deserialize message: {}", e);
-                                    Ok(None)
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to parse message payload: {}", e);
-                            Ok(None)
-                        }
-                    }
-                } else {
-                    Ok(None)
-                }
-            }
+            Ok(message) => Ok(decode_message(&message)),
             Err(e) => {
                 error!("Failed to receive message: {}", e);
                 Err(anyhow::anyhow!("Failed to receive message: {}", e))
             }
         }
     }
-} 
\ No newline at end of file
+
+    /// Receive up to `max` messages in partition order, returning early once `timeout`
+    /// elapses. Messages that fail to deserialize are logged and skipped, and a receive
+    /// error after some messages arrived ends the batch rather than discarding it.
+    pub async fn consume_batch(&self, max: usize, timeout: Duration) -> Result<Vec<KafkaMessage>> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut messages = Vec::with_capacity(max);
+
+        while messages.len() < max {
+            match tokio::time::timeout_at(deadline, self.consumer.recv()).await {
+                Ok(Ok(message)) => messages.extend(decode_message(&message)),
+                Ok(Err(e)) if messages.is_empty() => {
+                    error!("Failed to receive message: {}", e);
+                    return Err(anyhow::anyhow!("Failed to receive message: {}", e));
+                }
+                Ok(Err(e)) => {
+                    warn!("Ending batch of {} messages early: {}", messages.len(), e);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Publish a message that could not be processed to its topic's dead-letter topic
+    /// (`<topic>-dlq`), along with the error and where it was consumed from.
+    /// `retry_count` counts dead-letterings: a reprocessor that republishes the original
+    /// should copy it into the payload's `retry_count` so repeat failures can be detected.
+    pub async fn produce_to_dlq(&self, original: &KafkaMessage, error: &str) -> Result<()> {
+        let position = original
+            .position
+            .as_ref()
+            .context("Only consumed messages can be dead-lettered")?;
+        let retry_count = original
+            .payload
+            .get("retry_count")
+            .and_then(serde_json::Value::as_u64)
+            .map_or(1, |count| count + 1);
+
+        let dlq_topic = format!("{}{}", position.topic, DLQ_SUFFIX);
+        let payload = serde_json::json!({
+            "original": original,
+            "error": error,
+            "retry_count": retry_count,
+            "source_topic": position.topic,
+            "source_partition": position.partition,
+            "source_offset": position.offset,
+        });
+
+        warn!(
+            "Dead-lettering message from {}[{}]@{} to '{}' (retry count {})",
+            position.topic, position.partition, position.offset, dlq_topic, retry_count
+        );
+        self.produce_event(&dlq_topic, "dead_letter", payload).await
+    }
+
+    /// Wait up to `timeout` for queued producer messages (e.g. dead letters) to be delivered
+    pub async fn flush(&self, timeout: Duration) -> Result<()> {
+        // flush blocks until the queue drains, so keep it off the async runtime
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || producer.flush(timeout))
+            .await
+            .context("Kafka producer flush task failed")?
+            .context("Kafka producer flush did not complete")
+    }
+
+    /// Synchronously commit the consumer's current position. Call this only once every
+    /// consumed message has been processed, e.g. at shutdown, since it also covers
+    /// messages not yet passed to `commit_message`.
+    pub fn commit_position(&self) -> Result<()> {
+        self.consumer
+            .commit_consumer_state(CommitMode::Sync)
+            .context("Failed to commit consumer position")
+    }
+
+    /// Move the consumer group's offset past `message`, so it is not redelivered after a
+    /// restart. With `manual_commit`, call this once the message is fully processed. The
+    /// offset is committed along with the others pending once `commit_batch_size` messages
+    /// were passed here or `commit_interval` has passed since the last commit.
+    pub fn commit_message(&self, message: &KafkaMessage) -> Result<()> {
+        let position = message
+            .position
+            .as_ref()
+            .context("Only consumed messages can be committed")?;
+
+        let due = {
+            let mut pending = self
+                .pending_commits
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            pending
+                .offsets
+                .insert((position.topic.clone(), position.partition), position.offset + 1);
+            pending.messages += 1;
+
+            let interval_elapsed = self
+                .commit_interval
+                .is_some_and(|interval| pending.last_commit.elapsed() >= interval);
+            (pending.messages >= self.commit_batch_size || interval_elapsed)
+                .then(|| pending.take())
+        };
+        due.map_or(Ok(()), |offsets| self.commit_offsets(&offsets))
+    }
+
+    /// Commit the offsets passed to `commit_message` that are still pending, e.g. when the
+    /// topic goes quiet or before shutting down
+    pub fn commit_pending(&self) -> Result<()> {
+        let offsets = self
+            .pending_commits
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if offsets.is_empty() {
+            return Ok(());
+        }
+        self.commit_offsets(&offsets)
+    }
+
+    /// How long a partial batch of offsets may stay uncommitted, when bounded
+    pub const fn commit_interval(&self) -> Option<Duration> {
+        self.commit_interval
+    }
+
+    fn commit_offsets(&self, offsets: &HashMap<(String, i32), i64>) -> Result<()> {
+        let mut partitions = TopicPartitionList::new();
+        for ((topic, partition), offset) in offsets {
+            partitions
+                .add_partition_offset(topic, *partition, Offset::Offset(*offset))
+                .context("Failed to build commit offsets")?;
+        }
+
+        self.consumer
+            .commit(&partitions, CommitMode::Async)
+            .context("Failed to commit message offsets")?;
+        Ok(())
+    }
+}
+
+fn record_headers(headers: &HashMap<String, String>) -> OwnedHeaders {
+    headers
+        .iter()
+        .fold(OwnedHeaders::new(), |record_headers, (key, value)| {
+            record_headers.insert(Header {
+                key,
+                value: Some(value),
+            })
+        })
+}
+
+/// Deserialize a received record, recording where it came from so it can be committed,
+/// along with its key and headers
+fn decode_message(message: &impl Message) -> Option<KafkaMessage> {
+    let payload_str = match message.payload_view::<str>()? {
+        Ok(payload_str) => payload_str,
+        Err(e) => {
+            error!("Failed to parse message payload: {}", e);
+            return None;
+        }
+    };
+
+    match serde_json::from_str::<KafkaMessage>(payload_str) {
+        Ok(mut kafka_message) => {
+            info!("Received message: {:?}", kafka_message);
+            kafka_message.position = Some(MessagePosition {
+                topic: message.topic().to_string(),
+                partition: message.partition(),
+                offset: message.offset(),
+            });
+            kafka_message.key = message
+                .key()
+                .map(|key| String::from_utf8_lossy(key).into_owned());
+            kafka_message.headers = message
+                .headers()
+                .map(|headers| {
+                    headers
+                        .iter()
+                        .map(|header| {
+                            let value = header.value.map(String::from_utf8_lossy);
+                            (header.key.to_string(), value.unwrap_or_default().into_owned())
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            Some(kafka_message)
+        }
+        Err(e) => {
+            error!("Failed to deserialize message: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rdkafka::{message::OwnedMessage, Timestamp};
+
+    fn record(offset: i64, payload: &str) -> OwnedMessage {
+        OwnedMessage::new(
+            Some(payload.as_bytes().to_vec()),
+            Some(b"guide.md".to_vec()),
+            "file-embedding-tasks".to_string(),
+            Timestamp::NotAvailable,
+            2,
+            offset,
+            Some(OwnedHeaders::new().insert(Header {
+                key: "traceparent",
+                value: Some("00-abc-def-01"),
+            })),
+        )
+    }
+
+    #[test]
+    fn undecodable_record_is_skipped_without_dropping_the_others() {
+        let event = |task_id| {
+            serde_json::to_string(&KafkaMessage::new(
+                "task_created".to_string(),
+                serde_json::json!({"task_id": task_id}),
+            ))
+            .unwrap()
+        };
+        let records = [
+            record(10, &event(1)),
+            record(11, "not json"),
+            record(12, &event(3)),
+        ];
+
+        // The same per-record split consume_batch applies
+        let messages: Vec<KafkaMessage> = records.iter().filter_map(decode_message).collect();
+
+        let decoded: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                let position = message.position.as_ref().unwrap();
+                (message.payload["task_id"].as_i64(), position.partition, position.offset)
+            })
+            .collect();
+        assert_eq!(decoded, [(Some(1), 2, 10), (Some(3), 2, 12)]);
+        assert_eq!(messages[0].key.as_deref(), Some("guide.md"));
+        assert_eq!(messages[0].headers["traceparent"], "00-abc-def-01");
+    }
+}
@@ -1,7 +1,16 @@
+mod embedding_cache;
+mod embedding_fallback;
 mod kafka;
 pub mod openai;
 mod postgres;
+pub mod qdrant;
 
-pub use kafka::{KafkaClient, KafkaClientConfig};
-pub use openai::{ChatMessage, OpenAIClient, OpenAIClientConfig};
-pub use postgres::{PostgresClient, PostgresClientConfig};
+pub use embedding_cache::EmbeddingCacheConfig;
+pub use embedding_fallback::EmbeddingFallbackConfig;
+pub use kafka::{KafkaClient, KafkaClientConfig, KafkaMessage};
+pub use openai::{
+    ApiFlavor, ChatMessage, ChatOptions, ChatProvider, EmbeddingProvider, OpenAIClient,
+    OpenAIClientConfig, OpenAIError, RequestLoggingConfig,
+};
+pub use postgres::{PostgresClient, PostgresClientConfig, PostgresSslMode};
+pub use qdrant::{QdrantClientConfig, QdrantStore, VectorSpec, VectorStore};
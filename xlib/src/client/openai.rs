@@ -1,23 +1,225 @@
 use anyhow::{Context, Result};
-use reqwest::{Client, header};
+use async_trait::async_trait;
+use futures::{stream::BoxStream, Stream, StreamExt};
+use regex::Regex;
+use reqwest::{header, Client, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use super::embedding_cache::{EmbeddingCache, EmbeddingCacheConfig};
+use super::embedding_fallback::{EmbeddingFallback, EmbeddingFallbackConfig};
+
+/// Always masked in logged content, whatever patterns are configured
+const API_KEY_PATTERN: &str = r"sk-[A-Za-z0-9_\-]{8,}";
+const REDACTED: &str = "[REDACTED]";
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+/// Provider name reported for embeddings served by the primary endpoint
+pub const PRIMARY_EMBEDDING_PROVIDER: &str = "openai";
+
+pub const DEFAULT_CHAT_MODEL: &str = "gpt-4o";
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+const DEFAULT_MAX_TOKENS: u32 = 2000;
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Upper bound on a single backoff, including one requested by `Retry-After`
+const MAX_RETRY_DELAY: Duration = Duration::from_mins(1);
+
+/// Most inputs the embeddings endpoint accepts in one request
+pub const MAX_EMBEDDING_BATCH_INPUTS: usize = 2048;
+
+/// Output dimension of the known embedding models
+pub fn embedding_dimensions(model: &str) -> Option<u64> {
+    match model {
+        "text-embedding-3-small" | "text-embedding-ada-002" => Some(1536),
+        "text-embedding-3-large" => Some(3072),
+        _ => None,
+    }
+}
+
+/// Whether `model` accepts a `dimensions` parameter that shortens its vectors
+pub fn supports_dimensions(model: &str) -> bool {
+    model.starts_with("text-embedding-3-")
+}
+
+/// Length of `model`'s vectors given a configured dimension such as `EMBEDDING_DIMENSIONS`.
+///
+/// The configured length wins for models that accept `dimensions` or are missing from the
+/// known table; other models keep their known dimension.
+pub fn effective_dimensions(model: &str, configured: Option<u64>) -> Option<u64> {
+    match configured {
+        Some(configured) if supports_dimensions(model) || embedding_dimensions(model).is_none() => {
+            Some(configured)
+        }
+        _ => embedding_dimensions(model),
+    }
+}
+
+/// Reject a configured dimension `model` cannot produce: zero, or above its full length
+pub fn check_configured_dimensions(model: &str, configured: u64) -> Result<()> {
+    let full = embedding_dimensions(model);
+    if configured == 0 || (supports_dimensions(model) && full.is_some_and(|full| configured > full))
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid dimension {} for embedding model '{}': expected 1 to {}",
+            configured,
+            model,
+            full.map_or_else(|| "the model's length".to_string(), |full| full.to_string())
+        ));
+    }
+    Ok(())
+}
+
+/// Which API shape requests are built for
+#[derive(Debug, Clone, Default)]
+pub enum ApiFlavor {
+    /// `{base_url}/embeddings` and `{base_url}/chat/completions` with a bearer token
+    #[default]
+    OpenAI,
+    /// `{base_url}/openai/deployments/{deployment}/...?api-version=...` with an `api-key` header.
+    /// The deployment, not the request's model, selects the model.
+    Azure {
+        /// Deployment serving chat completions
+        deployment: String,
+        /// Deployment serving embeddings; defaults to `deployment`
+        embedding_deployment: Option<String>,
+        api_version: String,
+    },
+}
+
+/// The kind of endpoint a request goes to
+#[derive(Clone, Copy)]
+enum Endpoint {
+    Embeddings,
+    ChatCompletions,
+}
 
 #[derive(Clone)]
 pub struct OpenAIClient {
     client: Client,
-    api_key: String,
     base_url: String,
+    flavor: ApiFlavor,
+    embedding_model: String,
+    /// Shortened vector length requested per model
+    embedding_dimensions: HashMap<String, u64>,
+    max_embed_input_bytes: Option<usize>,
+    request_logger: Option<Arc<RequestLogger>>,
+    embedding_cache: Option<Arc<EmbeddingCache>>,
+    embedding_fallback: Option<Arc<EmbeddingFallback>>,
+    max_retries: u32,
+    base_delay: Duration,
 }
 
+#[derive(Default)]
 pub struct OpenAIClientConfig {
     pub api_key: String,
+    /// Required for Azure, e.g. `https://my-resource.openai.azure.com`
     pub base_url: Option<String>,
+    pub flavor: ApiFlavor,
+    /// Model used by `create_embedding`; defaults to `DEFAULT_EMBEDDING_MODEL`
+    pub embedding_model: Option<String>,
+    /// Vector length per model, sent as `dimensions` to the models that accept it
+    pub embedding_dimensions: HashMap<String, u64>,
+    /// Reject embedding inputs larger than this before sending the request
+    pub max_embed_input_bytes: Option<usize>,
+    /// Log requests and responses at debug level when set
+    pub request_logging: Option<RequestLoggingConfig>,
+    /// Retries of a 429 or 5xx response (default 3)
+    pub max_retries: Option<u32>,
+    /// First backoff delay, doubled on each retry (default 500ms)
+    pub base_delay: Option<Duration>,
+    /// Reuse embeddings of texts already embedded with the same model when set
+    pub embedding_cache: Option<EmbeddingCacheConfig>,
+    /// Endpoint embedding with the primary model when the primary provider fails
+    pub embedding_fallback: Option<EmbeddingFallbackConfig>,
+}
+
+pub struct RequestLoggingConfig {
+    /// Regexes whose matches are masked in logged content, e.g. email addresses
+    pub redact_patterns: Vec<String>,
+    /// Logged content is truncated to this many characters
+    pub max_content_chars: usize,
+}
+
+/// Debug logging of API traffic; the `Authorization` header is never logged and
+/// content passes through the redaction patterns before truncation
+struct RequestLogger {
+    redactions: Vec<Regex>,
+    max_content_chars: usize,
+}
+
+impl RequestLogger {
+    fn new(config: RequestLoggingConfig) -> Result<Self> {
+        let redactions = std::iter::once(API_KEY_PATTERN.to_string())
+            .chain(config.redact_patterns)
+            .map(|pattern| {
+                Regex::new(&pattern).context(format!("Invalid redaction pattern '{}'", pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            redactions,
+            max_content_chars: config.max_content_chars,
+        })
+    }
+
+    fn scrub(&self, content: &str) -> String {
+        let redacted = self
+            .redactions
+            .iter()
+            .fold(content.to_string(), |text, pattern| {
+                pattern.replace_all(&text, REDACTED).into_owned()
+            });
+
+        match redacted.char_indices().nth(self.max_content_chars) {
+            Some((cut, _)) => format!("{}... ({} chars)", &redacted[..cut], redacted.chars().count()),
+            None => redacted,
+        }
+    }
+
+    fn log_request(&self, url: &str, model: &str, messages: &[(&str, &str)]) {
+        let messages = messages
+            .iter()
+            .map(|(role, content)| format!("{}: {}", role, self.scrub(content)))
+            .collect::<Vec<_>>();
+        debug!(
+            url,
+            model,
+            authorization = REDACTED,
+            ?messages,
+            "OpenAI request"
+        );
+    }
+
+    fn log_response(&self, url: &str, content: &str) {
+        debug!(url, content = %self.scrub(content), "OpenAI response");
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct EmbeddingRequest {
     pub input: String,
     pub model: String,
+    /// Shortened vector length, for models that accept it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BatchEmbeddingRequest {
+    pub input: Vec<String>,
+    pub model: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -28,6 +230,9 @@ pub struct EmbeddingResponse {
 #[derive(Serialize, Deserialize)]
 pub struct EmbeddingData {
     pub embedding: Vec<f32>,
+    /// Position of the input this embedding belongs to
+    #[serde(default)]
+    pub index: usize,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,6 +242,19 @@ pub struct ChatRequest {
     pub temperature: Option<f32>,
     pub max_tokens: Option<u32>,
     pub response_format: Option<ResponseFormat>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<bool>,
+}
+
+/// Per-call chat completion settings; unset fields use the client defaults
+/// (`gpt-4o`, temperature 0.7, 2000 max tokens)
+#[derive(Debug, Clone, Default)]
+pub struct ChatOptions {
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<u32>,
+    /// Ask for a JSON object response
+    pub json_mode: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -61,13 +279,284 @@ pub struct ChatChoice {
     pub message: ChatMessage,
 }
 
+/// One server-sent event of a streamed chat completion
+#[derive(Serialize, Deserialize)]
+pub struct ChatStreamChunk {
+    pub choices: Vec<ChatStreamChoice>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatStreamChoice {
+    pub delta: ChatDelta,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatDelta {
+    pub content: Option<String>,
+}
+
+/// A parsed line of a chat completion event stream
+enum StreamLine {
+    Content(String),
+    Done,
+    Skip,
+}
+
+/// Parse one SSE line: `data:` lines carry a JSON chunk or the `[DONE]` marker;
+/// blank lines, comments and other fields are skipped
+fn parse_stream_line(line: &[u8]) -> Result<StreamLine> {
+    let line = std::str::from_utf8(line).context("Chat stream is not valid UTF-8")?;
+    let Some(data) = line.trim().strip_prefix("data:") else {
+        return Ok(StreamLine::Skip);
+    };
+
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(StreamLine::Done);
+    }
+
+    let chunk: ChatStreamChunk =
+        serde_json::from_str(data).context("Failed to parse chat stream chunk")?;
+    Ok(chunk
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.delta.content)
+        .filter(|content| !content.is_empty())
+        .map_or(StreamLine::Skip, StreamLine::Content))
+}
+
+type ByteStream = Pin<Box<dyn Stream<Item = reqwest::Result<bytes::Bytes>> + Send>>;
+
+struct StreamState {
+    bytes: ByteStream,
+    /// Bytes read but not yet terminated by a newline
+    buffer: Vec<u8>,
+    finished: bool,
+}
+
+/// Turn the response body into a stream of content deltas. Network reads can end
+/// mid-line (or mid-character), so only newline-terminated lines are parsed.
+fn content_deltas(response: Response) -> impl Stream<Item = Result<String>> + Send {
+    let state = StreamState {
+        bytes: Box::pin(response.bytes_stream()),
+        buffer: Vec::new(),
+        finished: false,
+    };
+
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(newline) = state.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = state.buffer.drain(..=newline).collect();
+                match parse_stream_line(&line) {
+                    Ok(StreamLine::Content(content)) => return Some((Ok(content), state)),
+                    Ok(StreamLine::Skip) => continue,
+                    Ok(StreamLine::Done) => return None,
+                    Err(e) => {
+                        state.finished = true;
+                        state.buffer.clear();
+                        return Some((Err(e), state));
+                    }
+                }
+            }
+            if state.finished {
+                return None;
+            }
+
+            match state.bytes.next().await {
+                Some(Ok(chunk)) => state.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    state.finished = true;
+                    state.buffer.clear();
+                    let error = anyhow::Error::new(e).context("Chat stream interrupted");
+                    return Some((Err(error), state));
+                }
+                None => {
+                    // Parse a final line that arrived without a trailing newline
+                    state.finished = true;
+                    if !state.buffer.is_empty() {
+                        state.buffer.push(b'\n');
+                    }
+                }
+            }
+        }
+    })
+}
+
+/// Error body returned by the API on non-2xx responses
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIErrorBody {
+    pub error: OpenAIErrorDetail,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OpenAIErrorDetail {
+    pub message: String,
+    #[serde(rename = "type")]
+    pub error_type: Option<String>,
+    pub code: Option<String>,
+    pub param: Option<String>,
+}
+
+/// Typed API failure, recoverable from an `anyhow::Error` via `downcast_ref`
+#[derive(Debug, Error)]
+pub enum OpenAIError {
+    #[error("OpenAI API error (status {status}, code {code:?}, type {error_type:?}): {message}")]
+    Api {
+        status: u16,
+        message: String,
+        error_type: Option<String>,
+        code: Option<String>,
+        param: Option<String>,
+    },
+    #[error("Embedding input is {actual} bytes, exceeding the {limit} byte limit")]
+    InputTooLarge { limit: usize, actual: usize },
+    #[error("Embedding batch has {actual} inputs, exceeding the {limit} input limit")]
+    BatchTooLarge { limit: usize, actual: usize },
+    #[error("Embedding model '{model}' returned {actual} dimensions, expected {expected}")]
+    DimensionMismatch {
+        model: String,
+        expected: u64,
+        actual: usize,
+    },
+}
+
+impl OpenAIError {
+    pub fn from_body(status: u16, body: OpenAIErrorBody) -> Self {
+        Self::Api {
+            status,
+            message: body.error.message,
+            error_type: body.error.error_type,
+            code: body.error.code,
+            param: body.error.param,
+        }
+    }
+
+    /// Build an error from a raw non-2xx response body. A body that isn't the usual
+    /// shape (e.g. a gateway's plain-text 502) becomes the message, so the status stays
+    /// available to callers deciding whether to retry.
+    pub fn from_response_text(status: u16, text: &str) -> anyhow::Error {
+        match serde_json::from_str::<OpenAIErrorBody>(text) {
+            Ok(body) => Self::from_body(status, body).into(),
+            Err(_) => Self::Api {
+                status,
+                message: text.to_string(),
+                error_type: None,
+                code: None,
+                param: None,
+            }
+            .into(),
+        }
+    }
+
+    /// Cheap pre-check against `max_bytes`, run before tokenizing or sending an input
+    pub const fn check_input_size(text: &str, max_bytes: Option<usize>) -> Result<(), Self> {
+        match max_bytes {
+            Some(limit) if text.len() > limit => Err(Self::InputTooLarge {
+                limit,
+                actual: text.len(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub const fn status(&self) -> Option<u16> {
+        match self {
+            Self::Api { status, .. } => Some(*status),
+            Self::InputTooLarge { .. }
+            | Self::BatchTooLarge { .. }
+            | Self::DimensionMismatch { .. } => None,
+        }
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        match self {
+            Self::Api { code, .. } => code.as_deref(),
+            Self::InputTooLarge { .. }
+            | Self::BatchTooLarge { .. }
+            | Self::DimensionMismatch { .. } => None,
+        }
+    }
+
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.code() == Some("context_length_exceeded")
+    }
+
+    /// Reject a vector whose length differs from the `expected` dimension of `model`
+    fn check_dimensions(
+        model: &str,
+        expected: Option<u64>,
+        embedding: &[f32],
+    ) -> Result<(), Self> {
+        match expected {
+            Some(expected) if u64::try_from(embedding.len()).ok() != Some(expected) => {
+                Err(Self::DimensionMismatch {
+                    model: model.to_string(),
+                    expected,
+                    actual: embedding.len(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Turn a non-2xx response into a typed `OpenAIError`
+pub(super) async fn check_response(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let text = response
+        .text()
+        .await
+        .context(format!("OpenAI API request failed with status {}", status))?;
+
+    Err(OpenAIError::from_response_text(status.as_u16(), &text))
+}
+
+/// Whether a primary failure for `model` may be retried on the fallback, which only serves
+/// `embedding_model`. An input the primary rejected as invalid would be rejected again.
+fn should_fall_back(model: &str, embedding_model: &str, error: &anyhow::Error) -> bool {
+    let is_bad_input = error
+        .downcast_ref::<OpenAIError>()
+        .and_then(OpenAIError::status)
+        .is_some_and(|status| status == 400);
+    model == embedding_model && !is_bad_input
+}
+
+/// Delay requested by a `Retry-After` header given in seconds
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Wait before retry number `attempt` (from 0): the server's `Retry-After` when given,
+/// otherwise `base_delay` doubled per earlier retry, capped at `MAX_RETRY_DELAY`
+fn backoff_delay(base_delay: Duration, attempt: u32, retry_after: Option<Duration>) -> Duration {
+    retry_after
+        .unwrap_or_else(|| base_delay.saturating_mul(2_u32.saturating_pow(attempt)))
+        .min(MAX_RETRY_DELAY)
+}
+
 impl OpenAIClient {
     pub fn new(config: OpenAIClientConfig) -> Result<Self> {
+        let (auth_header, auth_value) = match config.flavor {
+            ApiFlavor::OpenAI => (header::AUTHORIZATION, format!("Bearer {}", config.api_key)),
+            ApiFlavor::Azure { .. } => (header::HeaderName::from_static("api-key"), config.api_key),
+        };
         let mut headers = header::HeaderMap::new();
         headers.insert(
-            header::AUTHORIZATION,
-            header::HeaderValue::from_str(&format!("Bearer {}", config.api_key))
-                .context("Invalid API key format")?,
+            auth_header,
+            header::HeaderValue::from_str(&auth_value).context("Invalid API key format")?,
         );
         headers.insert(
             header::CONTENT_TYPE,
@@ -79,77 +568,768 @@ impl OpenAIClient {
             .build()
             .context("Failed to build HTTP client")?;
 
+        let request_logger = config
+            .request_logging
+            .map(RequestLogger::new)
+            .transpose()?
+            .map(Arc::new);
+
+        let base_url = match (&config.flavor, config.base_url) {
+            (_, Some(base_url)) => base_url.trim_end_matches('/').to_string(),
+            (ApiFlavor::OpenAI, None) => "https://api.openai.com/v1".to_string(),
+            (ApiFlavor::Azure { .. }, None) => {
+                return Err(anyhow::anyhow!("Azure requires the resource endpoint as base_url"));
+            }
+        };
+
+        for (model, dimensions) in &config.embedding_dimensions {
+            check_configured_dimensions(model, *dimensions)?;
+        }
+        let embedding_model = config
+            .embedding_model
+            .unwrap_or_else(|| DEFAULT_EMBEDDING_MODEL.to_string());
+        let embedding_dimensions: HashMap<String, u64> = config
+            .embedding_dimensions
+            .into_iter()
+            .filter(|(model, _)| supports_dimensions(model))
+            .collect();
+        let embedding_fallback = config
+            .embedding_fallback
+            .map(|fallback| {
+                let dimensions = embedding_dimensions.get(&embedding_model).copied();
+                EmbeddingFallback::new(fallback, &embedding_model, dimensions)
+            })
+            .transpose()?
+            .map(Arc::new);
+
         Ok(Self {
             client,
-            api_key: config.api_key,
-            base_url: config.base_url.unwrap_or_else(|| "https://api.openai.com/v1".to_string()),
+            base_url,
+            flavor: config.flavor,
+            embedding_model,
+            embedding_dimensions,
+            max_embed_input_bytes: config.max_embed_input_bytes,
+            request_logger,
+            embedding_cache: config
+                .embedding_cache
+                .map(|cache| Arc::new(EmbeddingCache::new(cache))),
+            embedding_fallback,
+            max_retries: config.max_retries.unwrap_or(DEFAULT_MAX_RETRIES),
+            base_delay: config.base_delay.unwrap_or(DEFAULT_BASE_DELAY),
         })
     }
 
+    /// URL of `endpoint` for the configured API flavor
+    fn endpoint_url(&self, endpoint: Endpoint) -> String {
+        let path = match endpoint {
+            Endpoint::Embeddings => "embeddings",
+            Endpoint::ChatCompletions => "chat/completions",
+        };
+
+        match &self.flavor {
+            ApiFlavor::OpenAI => format!("{}/{}", self.base_url, path),
+            ApiFlavor::Azure {
+                deployment,
+                embedding_deployment,
+                api_version,
+            } => {
+                let deployment = match endpoint {
+                    Endpoint::Embeddings => embedding_deployment.as_ref().unwrap_or(deployment),
+                    Endpoint::ChatCompletions => deployment,
+                };
+                format!(
+                    "{}/openai/deployments/{}/{}?api-version={}",
+                    self.base_url, deployment, path, api_version
+                )
+            }
+        }
+    }
+
+    /// POST `body` to `url`, retrying 429 and 5xx responses with exponential backoff or
+    /// the server's `Retry-After`; a final non-2xx response becomes an `OpenAIError`.
+    /// The whole call, retries included, is recorded in `openai_request_duration_seconds`.
+    async fn post_json<T: Serialize + Sync>(
+        &self,
+        url: &str,
+        body: &T,
+        request_kind: &str,
+    ) -> Result<Response> {
+        let started = Instant::now();
+        let result = self.post_json_with_retries(url, body, request_kind).await;
+        metrics::histogram!(
+            "openai_request_duration_seconds",
+            "request" => request_kind.replace(' ', "_"),
+            "outcome" => if result.is_ok() { "success" } else { "error" },
+        )
+        .record(started.elapsed());
+        result
+    }
+
+    async fn post_json_with_retries<T: Serialize + Sync>(
+        &self,
+        url: &str,
+        body: &T,
+        request_kind: &str,
+    ) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let response = self
+                .client
+                .post(url)
+                .json(body)
+                .send()
+                .await
+                .with_context(|| format!("Failed to send {} request", request_kind))?;
+
+            let status = response.status();
+            let is_retryable = status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error();
+            if !is_retryable || attempt >= self.max_retries {
+                return check_response(response).await;
+            }
+
+            let delay = backoff_delay(self.base_delay, attempt, retry_after(&response));
+            attempt += 1;
+            warn!(
+                "OpenAI {} request returned {}, retrying in {:?} ({}/{})",
+                request_kind, status, delay, attempt, self.max_retries
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Model used by `create_embedding`
+    pub fn embedding_model(&self) -> &str {
+        &self.embedding_model
+    }
+
+    /// `dimensions` sent with requests for `model`
+    fn requested_dimensions(&self, model: &str) -> Option<u64> {
+        self.embedding_dimensions.get(model).copied()
+    }
+
+    /// Length of the vectors returned for `model`, when known
+    fn expected_dimensions(&self, model: &str) -> Option<u64> {
+        self.requested_dimensions(model)
+            .or_else(|| embedding_dimensions(model))
+    }
+
+    /// Embed `text` with the configured embedding model
     pub async fn create_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        self.create_embedding_with_model(text, &self.embedding_model).await
+    }
+
+    /// Embed `text` with `model`. For models with a known dimension, a vector of any
+    /// other length is rejected before it can reach the vector store. Served from the
+    /// embedding cache when enabled and it holds `text` for `model`, and by the fallback
+    /// provider when the primary fails on the embedding model.
+    pub async fn create_embedding_with_model(&self, text: &str, model: &str) -> Result<Vec<f32>> {
+        OpenAIError::check_input_size(text, self.max_embed_input_bytes)?;
+        if let Some(embedding) = self.embedding_cache.as_ref().and_then(|c| c.get(model, text)) {
+            return Ok(embedding);
+        }
+
+        let (embedding, _) = self.embed_with_fallback(text, model).await?;
+
+        if let Some(cache) = &self.embedding_cache {
+            cache.insert(model, text, &embedding);
+        }
+        Ok(embedding)
+    }
+
+    /// Embed `text` with the embedding model, along with the name of the provider that
+    /// served it: `PRIMARY_EMBEDDING_PROVIDER` or the fallback's. Bypasses the embedding
+    /// cache, which does not record where its vectors came from.
+    pub async fn create_embedding_with_provider(&self, text: &str) -> Result<(Vec<f32>, &str)> {
+        OpenAIError::check_input_size(text, self.max_embed_input_bytes)?;
+        self.embed_with_fallback(text, &self.embedding_model).await
+    }
+
+    /// Embed `text` with the primary provider, or the fallback when it may serve `model`
+    async fn embed_with_fallback(&self, text: &str, model: &str) -> Result<(Vec<f32>, &str)> {
+        let (embedding, provider) = match self.request_embedding(text, model).await {
+            Ok(embedding) => (embedding, PRIMARY_EMBEDDING_PROVIDER),
+            Err(e) => {
+                let Some(fallback) = self.fallback_after(model, &e) else {
+                    return Err(e);
+                };
+                let embedding = fallback
+                    .embed(text)
+                    .await
+                    .with_context(|| format!("Fallback provider '{}' also failed", fallback.name))?;
+                info!("Embedding served by fallback provider '{}'", fallback.name);
+                (embedding, fallback.name.as_str())
+            }
+        };
+        OpenAIError::check_dimensions(model, self.expected_dimensions(model), &embedding)?;
+        Ok((embedding, provider))
+    }
+
+    /// One embedding request to the primary provider
+    async fn request_embedding(&self, text: &str, model: &str) -> Result<Vec<f32>> {
         let request = EmbeddingRequest {
             input: text.to_string(),
-            model: "text-embedding-3-small".to_string(),
+            model: model.to_string(),
+            dimensions: self.requested_dimensions(model),
         };
 
-        let response = self
-            .client
-            .post(&format!("{}/embeddings", self.base_url))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send embedding request")?;
+        let url = self.endpoint_url(Endpoint::Embeddings);
+        if let Some(logger) = &self.request_logger {
+            logger.log_request(&url, &request.model, &[("input", &request.input)]);
+        }
+
+        let response = self.post_json(&url, &request, "embedding").await?;
 
         let embedding_response: EmbeddingResponse = response
             .json()
             .await
             .context("Failed to parse embedding response")?;
 
-        embedding_response
+        let embedding = embedding_response
             .data
             .into_iter()
             .next()
             .map(|data| data.embedding)
-            .context("No embedding data received")
+            .context("No embedding data received")?;
+
+        if let Some(logger) = &self.request_logger {
+            logger.log_response(&url, &format!("{} dimensions", embedding.len()));
+        }
+        debug!("Embedding served by the primary provider");
+        Ok(embedding)
     }
 
+    /// The fallback provider to retry a failed request for `model` with
+    fn fallback_after(&self, model: &str, error: &anyhow::Error) -> Option<&EmbeddingFallback> {
+        let fallback = self.embedding_fallback.as_deref()?;
+        if !should_fall_back(model, &self.embedding_model, error) {
+            return None;
+        }
+
+        warn!(
+            "Primary embedding provider failed, using fallback provider '{}': {:#}",
+            fallback.name, error
+        );
+        Some(fallback)
+    }
+
+    /// Embed all `texts` with the configured embedding model in a single request,
+    /// returning the vectors in input order
+    pub async fn create_embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        if texts.len() > MAX_EMBEDDING_BATCH_INPUTS {
+            return Err(OpenAIError::BatchTooLarge {
+                limit: MAX_EMBEDDING_BATCH_INPUTS,
+                actual: texts.len(),
+            }
+            .into());
+        }
+        for text in texts {
+            OpenAIError::check_input_size(text, self.max_embed_input_bytes)?;
+        }
+
+        let model = &self.embedding_model;
+        let embeddings = match self.request_embeddings(texts).await {
+            Ok(embeddings) => embeddings,
+            Err(e) => {
+                let Some(fallback) = self.fallback_after(model, &e) else {
+                    return Err(e);
+                };
+                let embeddings = fallback
+                    .embed_batch(texts)
+                    .await
+                    .with_context(|| format!("Fallback provider '{}' also failed", fallback.name))?;
+                info!("Batch embedding served by fallback provider '{}'", fallback.name);
+                embeddings
+            }
+        };
+
+        let expected = self.expected_dimensions(model);
+        for embedding in &embeddings {
+            OpenAIError::check_dimensions(model, expected, embedding)?;
+        }
+        Ok(embeddings)
+    }
+
+    /// One batch embedding request to the primary provider
+    async fn request_embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let request = BatchEmbeddingRequest {
+            input: texts.to_vec(),
+            model: self.embedding_model.clone(),
+            dimensions: self.requested_dimensions(&self.embedding_model),
+        };
+
+        let url = self.endpoint_url(Endpoint::Embeddings);
+        if let Some(logger) = &self.request_logger {
+            let inputs = request
+                .input
+                .iter()
+                .map(|input| ("input", input.as_str()))
+                .collect::<Vec<_>>();
+            logger.log_request(&url, &request.model, &inputs);
+        }
+
+        let response = self.post_json(&url, &request, "batch embedding").await?;
+
+        let mut embedding_response: EmbeddingResponse = response
+            .json()
+            .await
+            .context("Failed to parse batch embedding response")?;
+
+        // The API does not guarantee that results come back in input order
+        embedding_response.data.sort_by_key(|data| data.index);
+        if embedding_response.data.len() != texts.len() {
+            return Err(anyhow::anyhow!(
+                "Expected {} embeddings, received {}",
+                texts.len(),
+                embedding_response.data.len()
+            ));
+        }
+
+        let embeddings = embedding_response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect::<Vec<_>>();
+
+        if let Some(logger) = &self.request_logger {
+            logger.log_response(&url, &format!("{} embeddings", embeddings.len()));
+        }
+        Ok(embeddings)
+    }
+
+    /// Refuse a fallback provider whose vectors differ in length from the embedding
+    /// model's, probing whichever length is not known
+    pub async fn verify_embedding_fallback(&self) -> Result<()> {
+        const PROBE: &str = "dimension check";
+        let Some(fallback) = &self.embedding_fallback else {
+            return Ok(());
+        };
+
+        let expected = if let Some(expected) = self.expected_dimensions(&self.embedding_model) {
+            expected
+        } else {
+            let probe = self.request_embedding(PROBE, &self.embedding_model).await?;
+            u64::try_from(probe.len())?
+        };
+        let probe = fallback
+            .embed(PROBE)
+            .await
+            .with_context(|| format!("Failed to probe fallback provider '{}'", fallback.name))?;
+        let actual = u64::try_from(probe.len())?;
+        if actual != expected {
+            return Err(anyhow::anyhow!(
+                "Fallback provider '{}' returns {} dimensions, but embedding model '{}' uses {}",
+                fallback.name,
+                actual,
+                self.embedding_model,
+                expected
+            ));
+        }
+
+        info!(
+            "Fallback embedding provider '{}' verified ({} dimensions)",
+            fallback.name, actual
+        );
+        Ok(())
+    }
+
+    /// Chat completion with the default model and sampling settings
     pub async fn chat_completion(
         &self,
         messages: Vec<ChatMessage>,
         json_mode: bool,
     ) -> Result<String> {
+        let options = ChatOptions {
+            json_mode,
+            ..ChatOptions::default()
+        };
+        self.chat_completion_with_options(messages, &options).await
+    }
+
+    /// Build a chat request and its URL, logging it when request logging is enabled
+    fn chat_request(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> (String, ChatRequest) {
         let mut request = ChatRequest {
-            model: "gpt-4o".to_string(),
+            model: options
+                .model
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CHAT_MODEL.to_string()),
             messages,
-            temperature: Some(0.7),
-            max_tokens: Some(2000),
+            temperature: Some(options.temperature.unwrap_or(DEFAULT_TEMPERATURE)),
+            max_tokens: Some(options.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)),
             response_format: None,
+            stream: None,
         };
 
-        if json_mode {
+        if options.json_mode {
             request.response_format = Some(ResponseFormat {
                 format_type: "json_object".to_string(),
             });
         }
 
-        let response = self
-            .client
-            .post(&format!("{}/chat/completions", self.base_url))
-            .json(&request)
-            .send()
-            .await
-            .context("Failed to send chat completion request")?;
+        let url = self.endpoint_url(Endpoint::ChatCompletions);
+        if let Some(logger) = &self.request_logger {
+            let messages = request
+                .messages
+                .iter()
+                .map(|message| (message.role.as_str(), message.content.as_str()))
+                .collect::<Vec<_>>();
+            logger.log_request(&url, &request.model, &messages);
+        }
+
+        (url, request)
+    }
+
+    pub async fn chat_completion_with_options(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<String> {
+        let (url, request) = self.chat_request(messages, options);
+        let response = self.post_json(&url, &request, "chat completion").await?;
 
         let chat_response: ChatResponse = response
             .json()
             .await
             .context("Failed to parse chat completion response")?;
 
-        chat_response
+        let content = chat_response
             .choices
             .into_iter()
             .next()
             .map(|choice| choice.message.content)
-            .context("No chat completion received")
+            .context("No chat completion received")?;
+
+        if let Some(logger) = &self.request_logger {
+            logger.log_response(&url, &content);
+        }
+
+        Ok(content)
+    }
+
+    /// Stream a chat completion as content deltas, ending at the `[DONE]` event. Request
+    /// and status errors are returned before any delta; later failures end the stream
+    /// with an error item.
+    pub async fn chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<impl Stream<Item = Result<String>> + Send> {
+        let (url, mut request) = self.chat_request(messages, options);
+        request.stream = Some(true);
+
+        let response = self.post_json(&url, &request, "chat completion stream").await?;
+        if let Some(logger) = &self.request_logger {
+            logger.log_response(&url, "streaming response started");
+        }
+
+        Ok(content_deltas(response))
+    }
+}
+
+/// Source of query embeddings, implemented by [`OpenAIClient`]. Handlers depend on this
+/// rather than the client so retrieval can run against another provider or a stub.
+#[async_trait]
+pub trait EmbeddingProvider: Send + Sync {
+    /// Model used by `create_embedding` and `create_embeddings`
+    fn embedding_model(&self) -> &str;
+
+    async fn create_embedding(&self, text: &str) -> Result<Vec<f32>>;
+
+    async fn create_embedding_with_model(&self, text: &str, model: &str) -> Result<Vec<f32>>;
+
+    /// Embed all `texts` in one request, returning the vectors in input order
+    async fn create_embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+/// Chat completions, implemented by [`OpenAIClient`]
+#[async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn chat_completion_with_options(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<String>;
+
+    /// Content deltas of the answer as they are generated
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<BoxStream<'static, Result<String>>>;
+}
+
+#[async_trait]
+impl EmbeddingProvider for OpenAIClient {
+    fn embedding_model(&self) -> &str {
+        Self::embedding_model(self)
+    }
+
+    async fn create_embedding(&self, text: &str) -> Result<Vec<f32>> {
+        Self::create_embedding(self, text).await
+    }
+
+    async fn create_embedding_with_model(&self, text: &str, model: &str) -> Result<Vec<f32>> {
+        Self::create_embedding_with_model(self, text, model).await
+    }
+
+    async fn create_embeddings(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        Self::create_embeddings(self, texts).await
     }
-} 
\ No newline at end of file
+}
+
+#[async_trait]
+impl ChatProvider for OpenAIClient {
+    async fn chat_completion_with_options(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<String> {
+        Self::chat_completion_with_options(self, messages, options).await
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        messages: Vec<ChatMessage>,
+        options: &ChatOptions,
+    ) -> Result<BoxStream<'static, Result<String>>> {
+        Ok(Self::chat_completion_stream(self, messages, options).await?.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MODEL: &str = "text-embedding-3-small";
+
+    fn api_error(status: u16) -> anyhow::Error {
+        OpenAIError::from_response_text(status, "upstream failure")
+    }
+
+    fn parse(status: u16, text: &str) -> OpenAIError {
+        OpenAIError::from_response_text(status, text)
+            .downcast::<OpenAIError>()
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_context_length_exceeded() {
+        let error = parse(
+            400,
+            r#"{"error": {"message": "This model's maximum context length is 8192 tokens",
+                "type": "invalid_request_error", "param": "input",
+                "code": "context_length_exceeded"}}"#,
+        );
+        assert!(error.is_context_length_exceeded());
+        assert_eq!(error.status(), Some(400));
+        let OpenAIError::Api { error_type, param, .. } = &error else {
+            panic!("expected an API error, got {error:?}");
+        };
+        assert_eq!(error_type.as_deref(), Some("invalid_request_error"));
+        assert_eq!(param.as_deref(), Some("input"));
+    }
+
+    #[test]
+    fn parses_invalid_api_key() {
+        let error = parse(
+            401,
+            r#"{"error": {"message": "Incorrect API key provided",
+                "type": "invalid_request_error", "param": null, "code": "invalid_api_key"}}"#,
+        );
+        assert_eq!(error.code(), Some("invalid_api_key"));
+        assert_eq!(error.status(), Some(401));
+        assert!(!error.is_context_length_exceeded());
+    }
+
+    #[test]
+    fn parses_model_not_found() {
+        let error = parse(
+            404,
+            r#"{"error": {"message": "The model `text-embedding-4` does not exist",
+                "type": "invalid_request_error", "param": null, "code": "model_not_found"}}"#,
+        );
+        assert_eq!(error.code(), Some("model_not_found"));
+        assert_eq!(error.status(), Some(404));
+    }
+
+    #[test]
+    fn keeps_a_non_json_body_as_the_message() {
+        let error = parse(502, "<html>502 Bad Gateway</html>");
+        assert_eq!(error.status(), Some(502));
+        assert_eq!(error.code(), None);
+        let OpenAIError::Api { message, .. } = &error else {
+            panic!("expected an API error, got {error:?}");
+        };
+        assert_eq!(message, "<html>502 Bad Gateway</html>");
+    }
+
+    #[test]
+    fn server_errors_and_rate_limits_fall_back() {
+        assert!(should_fall_back(MODEL, MODEL, &api_error(500)));
+        assert!(should_fall_back(MODEL, MODEL, &api_error(503)));
+        assert!(should_fall_back(MODEL, MODEL, &api_error(429)));
+    }
+
+    #[test]
+    fn transport_errors_fall_back() {
+        let error = anyhow::anyhow!("connection refused").context("Failed to send request");
+        assert!(should_fall_back(MODEL, MODEL, &error));
+    }
+
+    #[test]
+    fn rejected_inputs_do_not_fall_back() {
+        assert!(!should_fall_back(MODEL, MODEL, &api_error(400)));
+    }
+
+    #[test]
+    fn other_models_do_not_fall_back() {
+        assert!(!should_fall_back("text-embedding-3-large", MODEL, &api_error(500)));
+    }
+
+    fn response_with_retry_after(value: &str) -> Response {
+        Response::from(
+            http::Response::builder()
+                .status(429)
+                .header(header::RETRY_AFTER, value)
+                .body("")
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn backoff_doubles_from_the_base_delay() {
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff_delay(base, 0, None), Duration::from_millis(500));
+        assert_eq!(backoff_delay(base, 1, None), Duration::from_secs(1));
+        assert_eq!(backoff_delay(base, 3, None), Duration::from_secs(4));
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_cap() {
+        let base = Duration::from_millis(500);
+        assert_eq!(backoff_delay(base, 20, None), MAX_RETRY_DELAY);
+        assert_eq!(backoff_delay(base, u32::MAX, None), MAX_RETRY_DELAY);
+        assert_eq!(backoff_delay(base, 0, Some(Duration::from_hours(1))), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn retry_after_seconds_override_the_backoff() {
+        let delay = retry_after(&response_with_retry_after(" 7 "));
+        assert_eq!(delay, Some(Duration::from_secs(7)));
+        assert_eq!(backoff_delay(Duration::from_millis(500), 2, delay), Duration::from_secs(7));
+
+        // An HTTP date isn't supported and falls back to the backoff
+        assert_eq!(retry_after(&response_with_retry_after("Wed, 21 Oct 2026 07:28:00 GMT")), None);
+    }
+
+    fn content_of(line: &str) -> Option<String> {
+        match parse_stream_line(line.as_bytes()).unwrap() {
+            StreamLine::Content(content) => Some(content),
+            StreamLine::Done | StreamLine::Skip => None,
+        }
+    }
+
+    #[test]
+    fn stream_lines_yield_content_done_or_nothing() {
+        let chunk = r#"data: {"choices": [{"delta": {"content": "Hel"}}]}"#;
+        assert_eq!(content_of(chunk), Some("Hel".to_string()));
+        assert!(matches!(parse_stream_line(b"data: [DONE]\n"), Ok(StreamLine::Done)));
+
+        for skipped in ["", ": keep-alive", "event: message", r#"data: {"choices": []}"#] {
+            assert_eq!(content_of(skipped), None, "{skipped}");
+        }
+        assert_eq!(content_of(r#"data: {"choices": [{"delta": {"content": ""}}]}"#), None);
+        assert!(parse_stream_line(b"data: {not json").is_err());
+    }
+
+    #[tokio::test]
+    async fn content_deltas_join_lines_split_across_reads() {
+        let body = "data: {\"choices\": [{\"delta\": {\"content\": \"caf\u{e9}\"}}]}\n\n\
+                    data: {\"choices\": [{\"delta\": {\"content\": \"!\"}}]}\n\
+                    data: [DONE]\n\
+                    data: {\"choices\": [{\"delta\": {\"content\": \"ignored\"}}]}\n";
+        // Cut inside a line and inside the two bytes of "é"
+        let cut = body.find('\u{e9}').unwrap() + 1;
+        let reads: Vec<reqwest::Result<bytes::Bytes>> = vec![
+            Ok(bytes::Bytes::copy_from_slice(&body.as_bytes()[..cut])),
+            Ok(bytes::Bytes::copy_from_slice(&body.as_bytes()[cut..])),
+        ];
+        let response = Response::from(
+            http::Response::builder()
+                .body(reqwest::Body::wrap_stream(futures::stream::iter(reads)))
+                .unwrap(),
+        );
+
+        let deltas: Vec<String> = content_deltas(response)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(deltas, ["caf\u{e9}", "!"]);
+    }
+
+    fn client(base_url: Option<&str>, flavor: ApiFlavor) -> Result<OpenAIClient> {
+        OpenAIClient::new(OpenAIClientConfig {
+            api_key: "test-key".to_string(),
+            base_url: base_url.map(ToString::to_string),
+            flavor,
+            ..Default::default()
+        })
+    }
+
+    fn azure(embedding_deployment: Option<&str>) -> ApiFlavor {
+        ApiFlavor::Azure {
+            deployment: "gpt-4o-prod".to_string(),
+            embedding_deployment: embedding_deployment.map(ToString::to_string),
+            api_version: "2024-06-01".to_string(),
+        }
+    }
+
+    #[test]
+    fn azure_urls_name_the_deployment_and_api_version() {
+        let base_url = Some("https://my-resource.openai.azure.com/");
+        let client = client(base_url, azure(Some("embed-prod"))).unwrap();
+
+        assert_eq!(
+            client.endpoint_url(Endpoint::ChatCompletions),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt-4o-prod/chat/completions\
+             ?api-version=2024-06-01"
+        );
+        assert_eq!(
+            client.endpoint_url(Endpoint::Embeddings),
+            "https://my-resource.openai.azure.com/openai/deployments/embed-prod/embeddings\
+             ?api-version=2024-06-01"
+        );
+    }
+
+    #[test]
+    fn azure_embeddings_default_to_the_chat_deployment() {
+        let client = client(Some("https://my-resource.openai.azure.com"), azure(None)).unwrap();
+
+        assert_eq!(
+            client.endpoint_url(Endpoint::Embeddings),
+            "https://my-resource.openai.azure.com/openai/deployments/gpt-4o-prod/embeddings\
+             ?api-version=2024-06-01"
+        );
+    }
+
+    #[test]
+    fn azure_requires_a_base_url() {
+        assert!(client(None, azure(None)).is_err());
+    }
+
+    #[test]
+    fn openai_urls_append_the_path_to_the_base_url() {
+        let client = client(None, ApiFlavor::OpenAI).unwrap();
+        assert_eq!(
+            client.endpoint_url(Endpoint::Embeddings),
+            "https://api.openai.com/v1/embeddings"
+        );
+    }
+}
@@ -1,13 +1,55 @@
 use anyhow::{Context, Result};
 use derive_more::{Deref, From, Into};
 use sqlx::{
-    postgres::{PgConnectOptions, PgPoolOptions},
+    migrate::Migrator,
+    postgres::{PgConnectOptions, PgPoolOptions, PgSslMode},
     ConnectOptions, Pool, Postgres,
 };
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
 #[derive(Deref, From, Into, Clone)]
 pub struct PostgresClient(Pool<Postgres>);
 
+/// Pool size used when `max_connections` is unset
+const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+
+/// TLS requirement for the database connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostgresSslMode {
+    /// Plain TCP only
+    Disable,
+    /// Encrypt, without verifying the server certificate
+    Require,
+    /// Encrypt and verify the certificate chain and host name, e.g. for managed Postgres
+    VerifyFull,
+}
+
+impl FromStr for PostgresSslMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "disable" => Ok(Self::Disable),
+            "require" => Ok(Self::Require),
+            "verify-full" | "verify_full" => Ok(Self::VerifyFull),
+            other => Err(anyhow::anyhow!(
+                "invalid postgres ssl mode '{}': expected disable, require or verify-full",
+                other
+            )),
+        }
+    }
+}
+
+impl From<PostgresSslMode> for PgSslMode {
+    fn from(mode: PostgresSslMode) -> Self {
+        match mode {
+            PostgresSslMode::Disable => Self::Disable,
+            PostgresSslMode::Require => Self::Require,
+            PostgresSslMode::VerifyFull => Self::VerifyFull,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct PostgresClientConfig {
     pub hostname: String,
@@ -15,14 +57,47 @@ pub struct PostgresClientConfig {
     pub user: Option<String>,
     pub password: Option<String>,
     pub db_name: String,
+    /// Pool size (default 5). Size it per service: the API serves concurrent requests,
+    /// while a worker processing one message at a time needs only a couple.
+    pub max_connections: Option<u32>,
+    /// Connections kept open even when idle (default 0)
+    pub min_connections: Option<u32>,
+    /// How long a query waits for a free connection before failing (sqlx default 30s)
+    pub acquire_timeout: Option<Duration>,
+    /// Close connections idle for this long, down to `min_connections` (sqlx default 10 minutes)
+    pub idle_timeout: Option<Duration>,
+    /// Unset keeps libpq's default (`prefer`: TLS when the server offers it), or `PGSSLMODE`
+    pub ssl_mode: Option<PostgresSslMode>,
+    /// CA certificate used to verify the server under `VerifyFull`, when it isn't signed
+    /// by a CA in the system store
+    pub ssl_root_cert: Option<PathBuf>,
 }
 
 impl PostgresClient {
     pub async fn build(config: &PostgresClientConfig) -> Result<Self> {
         let url = config.build_url();
 
-        let client = PgPoolOptions::new()
-            .max_connections(5)
+        let max_connections = config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        let min_connections = config.min_connections.unwrap_or(0);
+        if max_connections == 0 || min_connections > max_connections {
+            return Err(anyhow::anyhow!(
+                "postgres max_connections {} must be positive and >= min_connections {}",
+                max_connections,
+                min_connections
+            ));
+        }
+
+        let mut pool_options = PgPoolOptions::new()
+            .max_connections(max_connections)
+            .min_connections(min_connections);
+        if let Some(acquire_timeout) = config.acquire_timeout {
+            pool_options = pool_options.acquire_timeout(acquire_timeout);
+        }
+        if let Some(idle_timeout) = config.idle_timeout {
+            pool_options = pool_options.idle_timeout(idle_timeout);
+        }
+
+        let client = pool_options
             .connect(&url)
             .await
             .context(format!("failed to connect to database: {}", url))?;
@@ -32,6 +107,62 @@ impl PostgresClient {
         Ok(Self(client))
     }
 
+    /// `build`, retrying up to `max_retries` attempts `retry_delay` apart so a service
+    /// started before Postgres is ready converges instead of exiting
+    pub async fn build_with_retry(
+        config: &PostgresClientConfig,
+        max_retries: u32,
+        retry_delay: Duration,
+    ) -> Result<Self> {
+        let mut attempt = 1;
+        loop {
+            match Self::build(config).await {
+                Ok(client) => {
+                    tracing::info!("postgres client connected on attempt {}", attempt);
+                    return Ok(client);
+                }
+                Err(e) if attempt < max_retries => {
+                    tracing::warn!(
+                        "Postgres connection attempt {} of {} failed: {:#}. Retrying in {:?}...",
+                        attempt,
+                        max_retries,
+                        e,
+                        retry_delay
+                    );
+                    tokio::time::sleep(retry_delay).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(e.context(format!(
+                        "Failed to connect to Postgres after {} attempts",
+                        attempt
+                    )));
+                }
+            }
+        }
+    }
+
+    /// Confirm the database answers `SELECT 1` within `timeout`, which bounds both
+    /// acquiring a pooled connection and running the statement
+    pub async fn ping(&self, timeout: Duration) -> Result<()> {
+        tokio::time::timeout(timeout, sqlx::query("SELECT 1").execute(&self.0))
+            .await
+            .context("Postgres ping timed out")?
+            .context("Postgres ping failed")?;
+        Ok(())
+    }
+
+    /// Apply `migrator`'s pending migrations, e.g. `sqlx::migrate!("../../migrations")`
+    /// embedded by the calling service. Applied versions are tracked in `_sqlx_migrations`.
+    pub async fn migrate(&self, migrator: &Migrator) -> Result<()> {
+        migrator
+            .run(&self.0)
+            .await
+            .context("failed to run database migrations")?;
+        tracing::info!("database schema is up to date ({} migrations)", migrator.iter().count());
+        Ok(())
+    }
+
     pub fn into_inner(self) -> Pool<Postgres> {
         self.0
     }
@@ -55,7 +186,57 @@ impl PostgresClientConfig {
         if let Some(password) = &self.password {
             options = options.password(password);
         }
-        
-        options.to_url_lossy().to_string()
+
+        if let Some(ssl_mode) = self.ssl_mode {
+            options = options.ssl_mode(ssl_mode.into());
+        }
+
+        // Set on the URL rather than the options: sqlx writes a root cert back out as
+        // `file: <path>`, which no longer parses as the path
+        let mut url = options.to_url_lossy();
+        if let Some(ssl_root_cert) = &self.ssl_root_cert {
+            url.query_pairs_mut()
+                .append_pair("sslrootcert", &ssl_root_cert.to_string_lossy());
+        }
+
+        url.to_string()
+    }
+} 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(
+        ssl_mode: Option<PostgresSslMode>,
+        ssl_root_cert: Option<&str>,
+    ) -> PostgresClientConfig {
+        PostgresClientConfig {
+            hostname: "db.example.com".to_string(),
+            db_name: "rag".to_string(),
+            ssl_mode,
+            ssl_root_cert: ssl_root_cert.map(PathBuf::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn require_mode_sets_sslmode() {
+        let url = config(Some(PostgresSslMode::Require), None).build_url();
+        assert!(url.contains("sslmode=require"), "{}", url);
+        assert!(!url.contains("sslrootcert"), "{}", url);
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn root_cert_is_passed_as_sslrootcert() {
+        let url = config(Some(PostgresSslMode::VerifyFull), Some("/etc/ssl/rds.pem")).build_url();
+        assert!(url.contains("sslmode=verify-full"), "{}", url);
+        assert!(url.contains("sslrootcert=%2Fetc%2Fssl%2Frds.pem"), "{}", url);
+    }
+
+    #[test]
+    fn unset_options_add_no_tls_requirement() {
+        let url = config(None, None).build_url();
+        assert!(!url.contains("sslmode=require"), "{}", url);
+        assert!(!url.contains("sslrootcert"), "{}", url);
+    }
+}
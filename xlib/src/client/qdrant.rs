@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use qdrant_client::qdrant::{
+    Condition, CreateCollectionBuilder, DeletePointsBuilder, Distance, Filter, PointStruct,
+    Range, ScoredPoint, SearchPoints, UpsertPointsBuilder, VectorParamsBuilder,
+    VectorsConfigBuilder,
+};
+use qdrant_client::Qdrant;
+use std::{sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+use crate::collection::fields;
+
+const DEFAULT_URL: &str = "http://localhost:6333";
+const DEFAULT_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_CONNECT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_CONNECT_RETRIES: u32 = 10;
+const DEFAULT_RETRY_DELAY_MS: u64 = 2_000;
+
+#[derive(Debug, Clone)]
+pub struct QdrantClientConfig {
+    pub url: String,
+    /// Deadline of each gRPC request
+    pub timeout: Duration,
+    /// Deadline for establishing the connection
+    pub connect_timeout: Duration,
+    /// Startup attempts before giving up
+    pub max_retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl QdrantClientConfig {
+    /// `QDRANT_URL`, `QDRANT_TIMEOUT_MS`, `QDRANT_CONNECT_TIMEOUT_MS`,
+    /// `QDRANT_CONNECT_RETRIES` and `QDRANT_RETRY_DELAY_MS`, with defaults for unset or
+    /// unparsable values
+    pub fn from_env() -> Self {
+        let env_u64 = |name: &str, default: u64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(default)
+        };
+        Self {
+            url: std::env::var("QDRANT_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+            timeout: Duration::from_millis(env_u64("QDRANT_TIMEOUT_MS", DEFAULT_TIMEOUT_MS)),
+            connect_timeout: Duration::from_millis(env_u64(
+                "QDRANT_CONNECT_TIMEOUT_MS",
+                DEFAULT_CONNECT_TIMEOUT_MS,
+            )),
+            max_retries: std::env::var("QDRANT_CONNECT_RETRIES")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .filter(|retries| *retries > 0)
+                .unwrap_or(DEFAULT_CONNECT_RETRIES),
+            retry_delay: Duration::from_millis(env_u64(
+                "QDRANT_RETRY_DELAY_MS",
+                DEFAULT_RETRY_DELAY_MS,
+            )),
+        }
+    }
+}
+
+/// Build a client and wait until Qdrant answers a health check, retrying up to
+/// `max_retries` attempts `retry_delay` apart.
+///
+/// The client connects lazily, so without the health check a Qdrant that is still
+/// starting would only surface on the first request.
+pub async fn connect_with_retry(config: &QdrantClientConfig) -> Result<Qdrant> {
+    let client = Qdrant::from_url(&config.url)
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout)
+        .build()
+        .with_context(|| format!("Invalid Qdrant configuration for {}", config.url))?;
+
+    let mut attempt = 1;
+    loop {
+        match client.health_check().await {
+            Ok(reply) => {
+                info!(
+                    "Qdrant {} at {} is ready (attempt {})",
+                    reply.version, config.url, attempt
+                );
+                return Ok(client);
+            }
+            Err(e) if attempt < config.max_retries => {
+                warn!(
+                    "Qdrant connection attempt {} of {} failed: {}. Retrying in {:?}...",
+                    attempt, config.max_retries, e, config.retry_delay
+                );
+                sleep(config.retry_delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Failed to connect to Qdrant at {} after {} attempts",
+                    config.url, attempt
+                )));
+            }
+        }
+    }
+}
+
+/// A vector the collection holds
+#[derive(Debug, Clone, Copy)]
+pub struct VectorSpec<'a> {
+    /// `None` for a single unnamed vector
+    pub name: Option<&'a str>,
+    pub size: u64,
+}
+
+/// Storage of document vectors, so services don't depend on Qdrant for writes, searches
+/// and deletes. Requests and points use the Qdrant types.
+#[async_trait]
+pub trait VectorStore: Send + Sync {
+    /// Name of the collection every call operates on
+    fn collection(&self) -> &str;
+
+    /// Create the collection with `vectors`, all using Cosine distance, unless it exists.
+    /// Returns whether it was created; an existing collection is not checked.
+    async fn ensure_collection(&self, vectors: &[VectorSpec<'_>]) -> Result<bool>;
+
+    async fn upsert(&self, points: Vec<PointStruct>) -> Result<()>;
+
+    /// Run `request` against [`VectorStore::collection`], whatever collection it names
+    async fn search(&self, request: SearchPoints) -> Result<Vec<ScoredPoint>>;
+
+    /// Delete the points of `file_name` stored as an attachment of `parent_file`, or as a
+    /// document of its own when `None`; only those from chunk `from_chunk` on when given
+    async fn delete_by_file(
+        &self,
+        file_name: &str,
+        parent_file: Option<&str>,
+        from_chunk: Option<usize>,
+    ) -> Result<()>;
+}
+
+/// [`VectorStore`] backed by a Qdrant collection
+#[derive(Clone)]
+pub struct QdrantStore {
+    client: Arc<Qdrant>,
+    collection: String,
+}
+
+impl QdrantStore {
+    pub fn new(client: Arc<Qdrant>, collection: impl Into<String>) -> Self {
+        Self {
+            client,
+            collection: collection.into(),
+        }
+    }
+
+    /// The underlying client, for Qdrant features the trait doesn't cover
+    pub fn client(&self) -> &Qdrant {
+        &self.client
+    }
+}
+
+#[async_trait]
+impl VectorStore for QdrantStore {
+    fn collection(&self) -> &str {
+        &self.collection
+    }
+
+    async fn ensure_collection(&self, vectors: &[VectorSpec<'_>]) -> Result<bool> {
+        if self.client.collection_exists(&self.collection).await? {
+            return Ok(false);
+        }
+
+        let mut vectors_config = VectorsConfigBuilder::default();
+        for vector in vectors {
+            let params = VectorParamsBuilder::new(vector.size, Distance::Cosine);
+            match vector.name {
+                Some(name) => vectors_config.add_named_vector_params(name, params),
+                None => vectors_config.add_vector_params(params),
+            };
+        }
+        self.client
+            .create_collection(
+                CreateCollectionBuilder::new(&self.collection).vectors_config(vectors_config),
+            )
+            .await
+            .with_context(|| format!("Failed to create collection '{}'", self.collection))?;
+        Ok(true)
+    }
+
+    async fn upsert(&self, points: Vec<PointStruct>) -> Result<()> {
+        self.client
+            .upsert_points(UpsertPointsBuilder::new(&self.collection, points))
+            .await?;
+        Ok(())
+    }
+
+    async fn search(&self, mut request: SearchPoints) -> Result<Vec<ScoredPoint>> {
+        request.collection_name.clone_from(&self.collection);
+        Ok(self.client.search_points(request).await?.result)
+    }
+
+    async fn delete_by_file(
+        &self,
+        file_name: &str,
+        parent_file: Option<&str>,
+        from_chunk: Option<usize>,
+    ) -> Result<()> {
+        let mut filter =
+            Filter::must([Condition::matches(fields::FILE_NAME, file_name.to_string())]);
+        filter.must.push(match parent_file {
+            Some(parent_file) => Condition::matches(fields::PARENT_FILE, parent_file.to_string()),
+            None => Condition::is_empty(fields::PARENT_FILE),
+        });
+        if let Some(from_chunk) = from_chunk {
+            #[allow(clippy::cast_precision_loss)]
+            let gte = Some(from_chunk as f64);
+            filter.must.push(Condition::range(
+                fields::CHUNK_INDEX,
+                Range {
+                    gte,
+                    ..Default::default()
+                },
+            ));
+        }
+        self.client
+            .delete_points(DeletePointsBuilder::new(&self.collection).points(filter).wait(true))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+//! Layout of the Qdrant collection shared by the file-processor, which writes it, and
+//! rag-api, which searches it
+
+/// Collection used when `QDRANT_COLLECTION` is unset
+pub const DEFAULT_COLLECTION_NAME: &str = "rag-collection";
+
+/// Collection holding one point per embedded chunk (`QDRANT_COLLECTION`). Services sharing
+/// a collection must agree on it; separate values keep tenants apart.
+pub fn collection_name() -> String {
+    std::env::var("QDRANT_COLLECTION")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| DEFAULT_COLLECTION_NAME.to_string())
+}
+
+/// Fail when `QDRANT_VECTOR_SIZE` is set to anything but `model_size`, the dimension of
+/// `model`. Unset leaves the size to the model.
+pub fn check_configured_vector_size(model: &str, model_size: u64) -> anyhow::Result<()> {
+    let Ok(value) = std::env::var("QDRANT_VECTOR_SIZE") else {
+        return Ok(());
+    };
+    let configured: u64 = value.trim().parse().map_err(|_| {
+        anyhow::anyhow!("Invalid QDRANT_VECTOR_SIZE '{}': expected a positive integer", value)
+    })?;
+    if configured != model_size {
+        return Err(anyhow::anyhow!(
+            "QDRANT_VECTOR_SIZE is {} but embedding model '{}' produces {}-dimension vectors",
+            configured,
+            model,
+            model_size
+        ));
+    }
+    Ok(())
+}
+
+/// Vector of the primary embedding model in a collection that also stores rerank vectors
+pub const PRIMARY_VECTOR: &str = "primary";
+
+/// Vector of `RERANK_EMBEDDING_MODEL`
+pub const RERANK_VECTOR: &str = "rerank";
+
+/// Payload keys written on each point
+pub mod fields {
+    pub const FILE_NAME: &str = "file_name";
+    pub const TASK_ID: &str = "task_id";
+    /// The first `SNIPPET_LENGTH` characters of the content
+    pub const CONTENT_SNIPPET: &str = "content_snippet";
+    /// The whole content, omitted when `STORE_FULL_CONTENT=false`
+    pub const FULL_CONTENT: &str = "full_content";
+    /// SHA-256 of the content, used to reuse embeddings of identical files
+    pub const CONTENT_HASH: &str = "content_hash";
+    /// File whose embedding was reused for this identical one
+    pub const DUPLICATE_OF: &str = "duplicate_of";
+    /// Position of the point's chunk within its file, starting at 0
+    pub const CHUNK_INDEX: &str = "chunk_index";
+    /// Number of chunks the file was split into
+    pub const CHUNK_COUNT: &str = "chunk_count";
+    /// File that referenced this attachment
+    pub const PARENT_FILE: &str = "parent_file";
+    pub const EMBEDDING_PROVIDER: &str = "embedding_provider";
+    /// `EMBEDDING_MODEL` that produced the primary vector; absent on points stored before
+    /// it was recorded
+    pub const EMBEDDING_MODEL: &str = "embedding_model";
+    /// Length of the primary vector
+    pub const VECTOR_DIM: &str = "vector_dim";
+    pub const SOURCE: &str = "source";
+    pub const TAGS: &str = "tags";
+    pub const METADATA: &str = "metadata";
+}
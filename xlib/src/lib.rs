@@ -1,2 +1,5 @@
 pub mod app;
 pub mod client;
+pub mod collection;
+pub mod tokens;
+pub mod vector;
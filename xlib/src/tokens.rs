@@ -0,0 +1,178 @@
+use tiktoken_rs::cl100k_base_singleton;
+use tracing::warn;
+
+/// Input limit of the `text-embedding-3-*` and ada-002 models (all use `cl100k_base`)
+const DEFAULT_MAX_EMBED_TOKENS: usize = 8191;
+
+/// Read `<name>_<MODEL>` (e.g. `MAX_EMBED_TOKENS_TEXT_EMBEDDING_3_SMALL`), falling back to `<name>`
+fn model_setting(name: &str, model: &str) -> Option<usize> {
+    let model_key = format!("{}_{}", name, model.to_uppercase().replace(['-', '.'], "_"));
+
+    std::env::var(model_key)
+        .or_else(|_| std::env::var(name))
+        .ok()
+        .and_then(|v| v.parse().ok())
+}
+
+/// Token limit for `model`: `MAX_EMBED_TOKENS_<MODEL>`, then `MAX_EMBED_TOKENS`,
+/// then the model's known limit
+pub fn max_embed_tokens(model: &str) -> usize {
+    model_setting("MAX_EMBED_TOKENS", model).unwrap_or(DEFAULT_MAX_EMBED_TOKENS)
+}
+
+/// Byte limit checked before tokenizing: `MAX_EMBED_INPUT_BYTES_<MODEL>`, then
+/// `MAX_EMBED_INPUT_BYTES`; unlimited when neither is set
+pub fn max_embed_input_bytes(model: &str) -> Option<usize> {
+    model_setting("MAX_EMBED_INPUT_BYTES", model)
+}
+
+/// Number of `cl100k_base` tokens in `text`, the encoding every `OpenAI` embedding model uses
+pub fn count_tokens(text: &str) -> usize {
+    cl100k_base_singleton().encode_with_special_tokens(text).len()
+}
+
+/// Trim `text` to at most `max_tokens` tokens, logging how many were dropped
+pub fn trim_to_token_limit(text: &str, max_tokens: usize) -> String {
+    let bpe = cl100k_base_singleton();
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return text.to_string();
+    }
+
+    // A cut can land inside a multi-byte character; back off until it decodes
+    let mut cut = max_tokens;
+    while cut > 0 {
+        if let Ok(trimmed) = bpe.decode(tokens[..cut].to_vec()) {
+            warn!(
+                "✂️ Trimmed embedding input from {} to {} tokens ({} dropped)",
+                tokens.len(),
+                cut,
+                tokens.len() - cut
+            );
+            return trimmed;
+        }
+        cut -= 1;
+    }
+
+    String::new()
+}
+
+/// Split `text` into windows of at most `max_tokens` tokens, each starting
+/// `max_tokens - overlap` tokens after the previous one. Text that fits in one window
+/// is returned unchanged.
+pub fn chunk_text(text: &str, max_tokens: usize, overlap: usize) -> Vec<String> {
+    let bpe = cl100k_base_singleton();
+    let tokens = bpe.encode_with_special_tokens(text);
+    if tokens.len() <= max_tokens {
+        return vec![text.to_string()];
+    }
+
+    let step = max_tokens.saturating_sub(overlap).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + max_tokens).min(tokens.len());
+        // A window edge can split a multi-byte character; shave tokens off the edges
+        // until it decodes, which the overlap with the neighbouring window covers
+        let decoded = (0..4).find_map(|trim_start| {
+            (0..4).find_map(|trim_end| {
+                let window = tokens.get(start + trim_start..end.checked_sub(trim_end)?)?;
+                bpe.decode(window.to_vec()).ok()
+            })
+        });
+        if let Some(chunk) = decoded {
+            chunks.push(chunk);
+        } else {
+            warn!("⚠️ Could not decode tokens {}..{} into text, skipping them", start, end);
+        }
+
+        if end == tokens.len() {
+            break;
+        }
+        start += step;
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(text: &str) -> Vec<u32> {
+        cl100k_base_singleton().encode_with_special_tokens(text)
+    }
+
+    /// `count` words "w0 w1 ... w9 w0 ...", one token each
+    fn words(count: usize) -> String {
+        (0..count).map(|i| format!("w{}", i % 10)).collect::<Vec<_>>().join(" ")
+    }
+
+    #[test]
+    fn text_within_one_window_is_returned_unchanged() {
+        let text = words(5);
+        assert_eq!(chunk_text(&text, 50, 10), vec![text]);
+        assert_eq!(chunk_text("", 50, 10), vec![String::new()]);
+    }
+
+    #[test]
+    fn windows_hold_at_most_max_tokens_and_overlap_their_neighbours() {
+        let text = words(100);
+        let chunks = chunk_text(&text, 20, 5);
+
+        assert!(chunks.len() > 1);
+        for pair in chunks.windows(2) {
+            let (previous, next) = (tokens(&pair[0]), tokens(&pair[1]));
+            assert_eq!(previous.len(), 20);
+            assert_eq!(previous[previous.len() - 5..], next[..5]);
+        }
+    }
+
+    #[test]
+    fn final_window_holds_the_remaining_tokens() {
+        let text = words(100);
+        let total = tokens(&text).len();
+        let chunks = chunk_text(&text, 20, 5);
+
+        // Windows start every 15 tokens; the last one runs to the end of the text
+        let last_start = (total - 20).div_ceil(15) * 15;
+        assert_eq!(chunks.len(), last_start / 15 + 1);
+        assert_eq!(tokens(chunks.last().unwrap()), tokens(&text)[last_start..]);
+        assert!(text.ends_with(chunks.last().unwrap().as_str()));
+    }
+
+    #[test]
+    fn multibyte_text_is_never_split_inside_a_character() {
+        let text = "東京の空は青い。🦀 は蟹です。".repeat(20);
+        let chunks = chunk_text(&text, 16, 4);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(!chunk.is_empty());
+            assert!(!chunk.contains('\u{FFFD}'), "{}", chunk);
+            assert!(text.contains(chunk.as_str()), "{}", chunk);
+        }
+    }
+
+    #[test]
+    fn trim_keeps_text_within_the_limit() {
+        let text = words(10);
+        assert_eq!(trim_to_token_limit(&text, 50), text);
+        assert_eq!(trim_to_token_limit("", 5), "");
+
+        let trimmed = trim_to_token_limit(&text, 4);
+        assert_eq!(tokens(&trimmed).len(), 4);
+        assert!(text.starts_with(&trimmed));
+    }
+
+    #[test]
+    fn trim_backs_off_to_a_character_boundary() {
+        let text = "🦀".repeat(10);
+        for max_tokens in 1..8 {
+            let trimmed = trim_to_token_limit(&text, max_tokens);
+            assert!(tokens(&trimmed).len() <= max_tokens);
+            assert!(text.starts_with(&trimmed), "{}", trimmed);
+            assert_eq!(trimmed.len() % '🦀'.len_utf8(), 0);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+/// Euclidean (L2) length of `vector`
+pub fn l2_norm(vector: &[f32]) -> f32 {
+    vector.iter().map(|value| value * value).sum::<f32>().sqrt()
+}
+
+/// Scale `vector` in place to unit L2 length; a zero vector is left unchanged
+pub fn l2_normalize(vector: &mut [f32]) {
+    let norm = l2_norm(vector);
+    if norm > f32::EPSILON {
+        for value in vector.iter_mut() {
+            *value /= norm;
+        }
+    }
+}
+
+/// Cosine similarity of two vectors of equal length; 0.0 when either is a zero vector
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let norms = l2_norm(a) * l2_norm(b);
+    if norms <= f32::EPSILON {
+        return 0.0;
+    }
+    a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>() / norms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOLERANCE: f32 = 1e-6;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < TOLERANCE,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn l2_norm_of_a_3_4_vector_is_5() {
+        assert_close(l2_norm(&[3.0, 4.0]), 5.0);
+    }
+
+    #[test]
+    fn l2_normalize_scales_to_unit_length() {
+        let mut vector = [3.0, 4.0];
+        l2_normalize(&mut vector);
+        assert_close(l2_norm(&vector), 1.0);
+        assert_close(vector[0], 0.6);
+        assert_close(vector[1], 0.8);
+    }
+
+    #[test]
+    fn l2_normalize_leaves_a_zero_vector_unchanged() {
+        let mut vector = [0.0; 3];
+        l2_normalize(&mut vector);
+        assert!(vector.iter().all(|value| *value == 0.0));
+    }
+
+    #[test]
+    fn l2_normalize_keeps_a_normalized_vector() {
+        let mut vector = [0.6, 0.8, 0.0];
+        l2_normalize(&mut vector);
+        for (actual, expected) in vector.iter().zip([0.6, 0.8, 0.0]) {
+            assert_close(*actual, expected);
+        }
+    }
+
+    #[test]
+    fn cosine_similarity_of_identical_vectors_is_one() {
+        let vector = [0.2, -1.5, 3.0];
+        assert_close(cosine_similarity(&vector, &vector), 1.0);
+    }
+
+    #[test]
+    fn cosine_similarity_of_orthogonal_vectors_is_zero() {
+        assert_close(cosine_similarity(&[1.0, 0.0], &[0.0, 2.0]), 0.0);
+    }
+
+    #[test]
+    fn cosine_similarity_with_a_zero_vector_is_zero() {
+        assert_close(cosine_similarity(&[1.0, 2.0], &[0.0, 0.0]), 0.0);
+    }
+}
@@ -0,0 +1,36 @@
+//! `KafkaClient` against an address no broker listens on, so every delivery fails.
+
+use serde_json::json;
+use xlib::client::{KafkaClient, KafkaClientConfig};
+
+#[tokio::test]
+async fn produce_events_reports_each_delivery_failure_in_order() {
+    let client = KafkaClient::new(KafkaClientConfig {
+        bootstrap_servers: "127.0.0.1:1".to_string(),
+        message_timeout_ms: Some(500),
+        ..Default::default()
+    })
+    .unwrap();
+    // Larger than the producer's message.max.bytes, so it is refused before being queued
+    let oversized = "x".repeat(2 * 1024 * 1024);
+
+    let results = client
+        .produce_events(
+            "file-embedding-tasks",
+            "task_created",
+            vec![
+                ("a.md".to_string(), json!({"task_id": 1})),
+                ("b.md".to_string(), json!({"task_id": 2, "file_content": oversized})),
+                ("c.md".to_string(), json!({"task_id": 3})),
+            ],
+        )
+        .await;
+
+    let errors: Vec<String> = results
+        .into_iter()
+        .map(|result| result.unwrap_err().to_string())
+        .collect();
+    assert!(errors[0].starts_with("Failed to deliver message"), "{}", errors[0]);
+    assert!(errors[1].starts_with("Failed to enqueue message"), "{}", errors[1]);
+    assert!(errors[2].starts_with("Failed to deliver message"), "{}", errors[2]);
+}
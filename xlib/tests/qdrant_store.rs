@@ -0,0 +1,127 @@
+//! `QdrantStore` against a running Qdrant at `QDRANT_URL`. Ignored by default; with
+//! `docker compose` up, run `QDRANT_URL=http://localhost:6334 cargo test -p xlib --test
+//! qdrant_store -- --ignored`.
+
+use qdrant_client::qdrant::{
+    Condition, DeleteCollectionBuilder, Filter, PointStruct, ScrollPointsBuilder,
+    SearchPointsBuilder,
+};
+use qdrant_client::Payload;
+use std::sync::Arc;
+use xlib::{
+    client::{qdrant, QdrantClientConfig, QdrantStore, VectorSpec, VectorStore},
+    collection::fields,
+};
+
+/// A store on a fresh collection of 3-dimension vectors, dropped by `drop_collection`
+async fn test_store() -> QdrantStore {
+    let mut config = QdrantClientConfig::from_env();
+    config.max_retries = 1;
+    let client = qdrant::connect_with_retry(&config).await.unwrap();
+    let collection = format!("xlib-test-{}", uuid::Uuid::new_v4());
+    let store = QdrantStore::new(Arc::new(client), collection);
+    store
+        .ensure_collection(&[VectorSpec { name: None, size: 3 }])
+        .await
+        .unwrap();
+    store
+}
+
+async fn drop_collection(store: &QdrantStore) {
+    store
+        .client()
+        .delete_collection(DeleteCollectionBuilder::new(store.collection()))
+        .await
+        .unwrap();
+}
+
+/// A point with the payload keys file-processor writes
+fn chunk(file_name: &str, parent_file: Option<&str>, chunk_index: i64) -> PointStruct {
+    let mut payload = Payload::new();
+    payload.insert(fields::FILE_NAME, file_name);
+    payload.insert(fields::TASK_ID, 7_i64);
+    payload.insert(fields::CHUNK_INDEX, chunk_index);
+    payload.insert(fields::FULL_CONTENT, format!("{} chunk {}", file_name, chunk_index));
+    if let Some(parent_file) = parent_file {
+        payload.insert(fields::PARENT_FILE, parent_file);
+    }
+    PointStruct::new(uuid::Uuid::new_v4().to_string(), vec![1.0, 0.0, 0.0], payload)
+}
+
+/// `(parent_file, chunk_index)` of every point of `file_name`, sorted
+async fn chunks_of(store: &QdrantStore, file_name: &str) -> Vec<(Option<String>, i64)> {
+    let response = store
+        .client()
+        .scroll(
+            ScrollPointsBuilder::new(store.collection())
+                .filter(Filter::must([Condition::matches(
+                    fields::FILE_NAME,
+                    file_name.to_string(),
+                )]))
+                .limit(100)
+                .with_payload(true),
+        )
+        .await
+        .unwrap();
+    let mut chunks: Vec<_> = response
+        .result
+        .into_iter()
+        .map(|point| {
+            let parent_file = point
+                .payload
+                .get(fields::PARENT_FILE)
+                .and_then(|v| v.as_str())
+                .cloned();
+            let chunk_index = point.payload[fields::CHUNK_INDEX].as_integer().unwrap();
+            (parent_file, chunk_index)
+        })
+        .collect();
+    chunks.sort();
+    chunks
+}
+
+#[tokio::test]
+#[ignore = "needs a running Qdrant at QDRANT_URL"]
+async fn upserted_point_is_searched_with_its_payload() {
+    let store = test_store().await;
+    store.upsert(vec![chunk("guide.md", None, 0)]).await.unwrap();
+
+    let results = store
+        .search(
+            SearchPointsBuilder::new(store.collection(), vec![1.0, 0.0, 0.0], 1)
+                .with_payload(true)
+                .build(),
+        )
+        .await
+        .unwrap();
+
+    drop_collection(&store).await;
+    let payload = &results[0].payload;
+    assert_eq!(payload[fields::FILE_NAME].as_str().unwrap(), "guide.md");
+    assert_eq!(payload[fields::TASK_ID].as_integer(), Some(7));
+    assert_eq!(payload[fields::CHUNK_INDEX].as_integer(), Some(0));
+    assert_eq!(payload[fields::FULL_CONTENT].as_str().unwrap(), "guide.md chunk 0");
+}
+
+#[tokio::test]
+#[ignore = "needs a running Qdrant at QDRANT_URL"]
+async fn shrinking_a_file_deletes_only_its_stale_chunks() {
+    let store = test_store().await;
+    let mut points: Vec<_> = (0..5).map(|index| chunk("guide.md", None, index)).collect();
+    // An attachment sharing the file name, and another file, keep their points
+    points.push(chunk("guide.md", Some("index.md"), 3));
+    points.push(chunk("other.md", None, 4));
+    store.upsert(points).await.unwrap();
+
+    // The file went from 5 chunks to 2
+    store.delete_by_file("guide.md", None, Some(2)).await.unwrap();
+
+    let guide = chunks_of(&store, "guide.md").await;
+    let other = chunks_of(&store, "other.md").await;
+    drop_collection(&store).await;
+    assert_eq!(
+        guide,
+        [(None, 0), (None, 1), (Some("index.md".to_string()), 3)]
+    );
+    assert_eq!(other, [(None, 4)]);
+}